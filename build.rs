@@ -0,0 +1,5 @@
+fn main() {
+    // Embedded by include_dir!, which cargo doesn't track on its own
+    println!("cargo:rerun-if-changed=static");
+    println!("cargo:rerun-if-changed=templates");
+}
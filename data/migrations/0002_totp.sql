@@ -0,0 +1,31 @@
+-- 0002: optional TOTP second factor.
+
+CREATE TABLE "user_totp" (
+    "user_id" INTEGER NOT NULL PRIMARY KEY REFERENCES "users"("id") ON DELETE CASCADE,
+    -- Base32 shared secret. It has to be reversible to compute codes, so it
+    -- is stored as-is.
+    "secret" VARCHAR NOT NULL,
+    "enabled" INTEGER NOT NULL DEFAULT 0,
+    "created_at" INTEGER NOT NULL,
+    "confirmed_at" INTEGER,
+    -- Last accepted 30-second time step; codes from this step or earlier are
+    -- rejected so an observed code can't be replayed.
+    "last_used_step" INTEGER
+);
+
+CREATE TABLE "recovery_codes" (
+    "id" INTEGER NOT NULL PRIMARY KEY,
+    "user_id" INTEGER NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+    "code_hash" VARCHAR NOT NULL,
+    "used_at" INTEGER
+);
+
+CREATE INDEX "recovery_codes_user_id" ON "recovery_codes" ("user_id");
+
+-- A password check that still needs its second factor.
+CREATE TABLE "login_challenges" (
+    "token_hash" VARCHAR NOT NULL PRIMARY KEY,
+    "user_id" INTEGER NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+    "expires_at" INTEGER NOT NULL,
+    "failed_attempts" INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,5 @@
+-- 0003: self-service registration needs emails and CPFs to identify a
+-- single user. Both columns stay nullable; NULLs never conflict.
+
+CREATE UNIQUE INDEX "users_email_unique" ON "users" ("email") WHERE "email" IS NOT NULL;
+CREATE UNIQUE INDEX "users_cpf_unique" ON "users" ("cpf") WHERE "cpf" IS NOT NULL;
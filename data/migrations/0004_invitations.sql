@@ -0,0 +1,15 @@
+-- 0004: single-use invitation tokens for invite-only onboarding.
+
+CREATE TABLE "invitations" (
+    "id" INTEGER NOT NULL PRIMARY KEY,
+    -- SHA-256 of the token; the token itself only ever lives in the link
+    "token_hash" VARCHAR NOT NULL UNIQUE,
+    -- Role the new user gets on acceptance, if any
+    "role" VARCHAR,
+    "created_by" INTEGER REFERENCES "users"("id") ON DELETE SET NULL,
+    "created_at" INTEGER NOT NULL,
+    "expires_at" INTEGER NOT NULL,
+    "used_at" INTEGER,
+    "used_by" INTEGER REFERENCES "users"("id") ON DELETE SET NULL,
+    "revoked_at" INTEGER
+);
@@ -0,0 +1,13 @@
+-- 0005: time-limited password reset tokens sent by email.
+
+CREATE TABLE "password_resets" (
+    "id" INTEGER NOT NULL PRIMARY KEY,
+    "user_id" INTEGER NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+    -- SHA-256 of the token; the token itself only ever lives in the email
+    "token_hash" VARCHAR NOT NULL UNIQUE,
+    "created_at" INTEGER NOT NULL,
+    "expires_at" INTEGER NOT NULL,
+    "used_at" INTEGER
+);
+
+CREATE INDEX "password_resets_user_id" ON "password_resets" ("user_id");
@@ -0,0 +1,15 @@
+-- 0006: failed login tracking for throttling and temporary lockouts.
+
+CREATE TABLE "login_throttle" (
+    -- "account" (keyed by the submitted username) or "ip"
+    "scope" VARCHAR NOT NULL,
+    "key" VARCHAR NOT NULL,
+    -- Failures since the counter was last reset
+    "failures" INTEGER NOT NULL,
+    "last_failure_at" INTEGER NOT NULL,
+    -- No login attempts are checked before this time
+    "blocked_until" INTEGER NOT NULL DEFAULT 0,
+    -- Whether the block is a full lockout rather than backoff
+    "locked" BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY ("scope", "key")
+);
@@ -0,0 +1,10 @@
+-- 0007: store sessions by the SHA-256 of their token instead of the token.
+--
+-- Existing rows hold plaintext tokens that can't be turned into hashes
+-- without keeping them valid, so everyone is logged out once.
+
+DELETE FROM "sessions";
+
+ALTER TABLE "sessions" RENAME COLUMN "token" TO "token_hash";
+
+CREATE UNIQUE INDEX "sessions_token_hash" ON "sessions" ("token_hash");
@@ -0,0 +1,10 @@
+-- 0008: per-session details for the session management page.
+
+ALTER TABLE "sessions" ADD COLUMN "created_at" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "sessions" ADD COLUMN "last_seen_at" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "sessions" ADD COLUMN "user_agent" VARCHAR;
+ALTER TABLE "sessions" ADD COLUMN "ip" VARCHAR;
+-- Label the user gave the session, e.g. "work laptop"
+ALTER TABLE "sessions" ADD COLUMN "name" VARCHAR;
+
+CREATE INDEX "sessions_user_id" ON "sessions" ("user_id");
@@ -0,0 +1,16 @@
+-- 0009: sliding expiration and token rotation for sessions.
+--
+-- "expires_at" is now the idle timeout, pushed forward on activity but never
+-- past "absolute_expires_at". A rotated-out token keeps working until
+-- "previous_valid_until" so requests already in flight don't fail.
+
+ALTER TABLE "sessions" ADD COLUMN "absolute_expires_at" INTEGER NOT NULL DEFAULT 0;
+UPDATE "sessions" SET "absolute_expires_at" = "expires_at";
+
+ALTER TABLE "sessions" ADD COLUMN "rotated_at" INTEGER NOT NULL DEFAULT 0;
+UPDATE "sessions" SET "rotated_at" = "created_at";
+
+ALTER TABLE "sessions" ADD COLUMN "previous_token_hash" VARCHAR;
+ALTER TABLE "sessions" ADD COLUMN "previous_valid_until" INTEGER;
+
+CREATE INDEX "sessions_previous_token_hash" ON "sessions" ("previous_token_hash");
@@ -0,0 +1,13 @@
+-- 0010: last run of each background job, so schedules survive restarts.
+
+CREATE TABLE "scheduled_jobs" (
+    "name" VARCHAR NOT NULL PRIMARY KEY,
+    "last_started_at" INTEGER,
+    "last_finished_at" INTEGER,
+    -- "success" or "failure"
+    "last_outcome" VARCHAR,
+    -- What the job reported, or why it failed
+    "last_message" VARCHAR,
+    "run_count" INTEGER NOT NULL DEFAULT 0,
+    "failure_count" INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,53 @@
+-- 0011: roles and permissions.
+--
+-- Users get permissions through roles; a user can hold several roles.
+-- Everyone already registered becomes a member. Nobody is made admin here:
+-- the server warns at startup until `nexo-admin user roles` grants it.
+
+CREATE TABLE "roles" (
+    "id" INTEGER NOT NULL PRIMARY KEY,
+    "name" VARCHAR NOT NULL UNIQUE,
+    "description" VARCHAR NOT NULL
+);
+
+CREATE TABLE "permissions" (
+    "name" VARCHAR NOT NULL PRIMARY KEY,
+    "description" VARCHAR NOT NULL
+);
+
+CREATE TABLE "role_permissions" (
+    "role_id" INTEGER NOT NULL REFERENCES "roles"("id") ON DELETE CASCADE,
+    "permission" VARCHAR NOT NULL REFERENCES "permissions"("name") ON DELETE CASCADE,
+    PRIMARY KEY ("role_id", "permission")
+);
+
+CREATE TABLE "user_roles" (
+    "user_id" INTEGER NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+    "role_id" INTEGER NOT NULL REFERENCES "roles"("id") ON DELETE CASCADE,
+    PRIMARY KEY ("user_id", "role_id")
+);
+
+INSERT INTO "permissions" ("name", "description") VALUES
+    ('users.manage', 'List users and change their roles'),
+    ('invitations.manage', 'Create, list and revoke invitations'),
+    ('lockouts.manage', 'See and lift login lockouts'),
+    ('jobs.view', 'See background job status'),
+    ('database.manage', 'Run database maintenance'),
+    ('finance.view', 'Use the finance tile'),
+    ('documents.view', 'Use the documents tile');
+
+INSERT INTO "roles" ("name", "description") VALUES
+    ('admin', 'Runs the instance and can do everything'),
+    ('member', 'A regular member of the household'),
+    ('finance', 'Sees the household finances'),
+    ('documents', 'Sees the household documents');
+
+INSERT INTO "role_permissions" ("role_id", "permission")
+    SELECT "roles"."id", "permissions"."name" FROM "roles", "permissions" WHERE "roles"."name" = 'admin';
+INSERT INTO "role_permissions" ("role_id", "permission")
+    SELECT "id", 'finance.view' FROM "roles" WHERE "name" = 'finance';
+INSERT INTO "role_permissions" ("role_id", "permission")
+    SELECT "id", 'documents.view' FROM "roles" WHERE "name" = 'documents';
+
+INSERT INTO "user_roles" ("user_id", "role_id")
+    SELECT "users"."id", "roles"."id" FROM "users", "roles" WHERE "roles"."name" = 'member';
@@ -0,0 +1,6 @@
+-- 0012: disabled users.
+--
+-- A disabled user keeps their data but can't log in, and sessions they
+-- still hold stop validating.
+
+ALTER TABLE "users" ADD COLUMN "disabled_at" INTEGER;
@@ -0,0 +1,28 @@
+-- 0013: first-run setup replaces the seed user.
+--
+-- 0001 inserted a user 'thiago' with the published password "1234" into
+-- every database, stored as a salted SHA-256 digest. It is dropped here
+-- while it still has that digest; an account whose password was changed or
+-- rehashed is kept.
+--
+-- The first admin is now created by the setup page, which is only offered
+-- while "setup" has no row and there are no users. If this drops the only
+-- admin, nobody else is promoted; the server warns at startup instead.
+
+CREATE TABLE "setup" (
+    "id" INTEGER NOT NULL PRIMARY KEY CHECK ("id" = 1),
+    "completed_at" INTEGER NOT NULL
+);
+
+-- Sessions carry no foreign key, so they are removed by hand; everything
+-- else that references users cascades
+DELETE FROM "sessions" WHERE "user_id" IN (
+    SELECT "id" FROM "users" WHERE "name" = 'thiago' AND "psw_hash" =
+        'ea32961dbd579ef5697c367f9267921ee07f14d77fb2d4fb9500d4221d615695'
+);
+DELETE FROM "users" WHERE "name" = 'thiago'
+    AND "psw_hash" = 'ea32961dbd579ef5697c367f9267921ee07f14d77fb2d4fb9500d4221d615695';
+
+-- Deployments that still have users are already set up
+INSERT INTO "setup" ("id", "completed_at")
+    SELECT 1, CAST(strftime('%s', 'now') AS INTEGER) WHERE EXISTS (SELECT 1 FROM "users");
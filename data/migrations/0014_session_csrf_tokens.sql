@@ -0,0 +1,8 @@
+-- 0014: CSRF tokens for sessions.
+--
+-- Pages carry the token of the session they were served to, and every
+-- state-changing request must send it back. It stays the same when the
+-- session token rotates, so pages that are already open keep working.
+
+ALTER TABLE "sessions" ADD COLUMN "csrf_token" VARCHAR;
+UPDATE "sessions" SET "csrf_token" = lower(hex(randomblob(32)));
@@ -0,0 +1,6 @@
+-- 0015: user locale.
+--
+-- The language a user picked, e.g. "pt-BR". While it is NULL pages follow
+-- the browser's Accept-Language.
+
+ALTER TABLE "users" ADD COLUMN "locale" VARCHAR;
@@ -0,0 +1,152 @@
+## Shared form fields
+
+username = Username
+password = Password
+email = Email
+confirm-password = Confirm password
+optional = (optional)
+
+## Login
+
+login-title = Login
+login-submit = Log In
+login-forgot-password = Forgot your password?
+login-no-account = No account yet?
+login-create-account = Create one
+logout-success = You have been logged out.
+
+totp-title = Two-factor authentication
+totp-challenge-code = Authenticator or recovery code
+totp-challenge-submit = Verify
+
+## Password reset
+
+forgot-password-title = Forgot password
+forgot-password-intro = Enter your email and we'll send you a link to choose a new password.
+forgot-password-submit = Send reset link
+forgot-password-back = Back to login
+forgot-password-sent = If an account uses that email, a link to reset its password is on its way.
+
+reset-password-title = Reset password
+reset-password-heading = Choose a new password
+reset-password-new = New password
+reset-password-submit = Reset password
+
+reset-email-subject = Reset your Nexo password
+reset-email-body =
+    Hi { $name },
+
+    Someone asked to reset the password for your Nexo account. If it was you, open this link within { $minutes } minutes:
+
+    { $link }
+
+    If it wasn't you, you can ignore this email; your password stays the same.
+
+## Sign-up
+
+invite-title = Join Nexo
+invite-heading = You're invited
+invite-intro = Choose a username and password to join.
+invite-submit = Join
+
+register-title = Create account
+register-submit = Create account
+register-have-account = Already have an account?
+register-log-in = Log in
+
+setup-title = Set up Nexo
+setup-heading = Welcome to Nexo
+setup-intro = Create the admin account. It can invite everyone else and manage their roles.
+setup-password-hint = At least 12 characters, mixing three of: lowercase, uppercase, digits and symbols.
+setup-submit = Create admin account
+
+## Home
+
+home-welcome = Welcome, { $name }!
+home-logout = Logout
+home-language = Language
+
+totp-intro = Protect your account with an authenticator app.
+totp-enable = Enable
+totp-enroll-failed = Could not start enrollment
+totp-add-key = Add this key to your authenticator app, then enter the code it shows.
+totp-open-app = Open in authenticator app
+totp-confirm = Confirm
+totp-save-codes = Save these recovery codes. Each one works once and they won't be shown again.
+totp-enabled = Enabled.
+totp-codes-left = Recovery codes left:
+totp-disable-code = Code to disable
+totp-disable = Disable
+
+sessions-title = Where you're logged in
+sessions-unknown-device = Unknown device
+sessions-this-device = This device
+sessions-unknown-address = Unknown address
+sessions-last-active = last active
+sessions-signed-in = Signed in
+sessions-name-placeholder = e.g. Work laptop
+sessions-save = Save
+sessions-rename = Rename
+sessions-log-out = Log out
+sessions-revoke = Revoke
+sessions-revoke-failed = Could not revoke session
+sessions-rename-failed = Could not rename session
+sessions-revoke-others = Log out everywhere else
+sessions-revoke-others-failed = Could not log out other sessions
+
+## Errors swapped into forms
+
+error-invalid-credentials = Invalid username or password
+error-user-not-found = User not found. Please try again.
+error-create-session-failed = Failed to create session. Please try again.
+error-create-account-failed = Failed to create account. Please try again.
+error-reset-password-failed = Failed to reset password. Please try again.
+error-verify-code-failed = Failed to verify code. Please try again.
+error-invalid-code = Invalid code
+error-too-many-codes = Too many invalid codes. Please log in again.
+error-login-expired = Your login has expired. Please log in again.
+error-setup-required = Nexo hasn't been set up yet. Create the admin account first.
+error-registration-disabled = Registration is disabled. Ask an administrator for an invitation.
+error-username-taken = That username is already taken
+error-email-taken = An account with that email already exists
+error-cpf-taken = An account with that CPF already exists
+error-details-taken = An account with those details already exists
+error-invitation-invalid = This invitation is no longer valid
+error-reset-link-invalid = This reset link is no longer valid. Please request a new one.
+error-database-busy = The database is busy. Please try again in a moment.
+
+validation-username-length = Username must be between 3 and 32 characters
+validation-username-characters = Username may only contain letters, numbers, '.', '-' and '_'
+validation-password-too-short = Password must be at least 8 characters
+validation-password-too-long = Password must be at most 128 characters
+validation-password-matches-username = Password must not be the same as the username
+validation-password-too-weak = Password must be at least 12 characters and mix at least three of lowercase letters, uppercase letters, digits and symbols
+validation-password-mismatch = Passwords do not match
+validation-invalid-email = Please enter a valid email address
+validation-invalid-cpf = Please enter a valid CPF
+
+## Error pages
+
+error-page-bad-request = The request couldn't be understood.
+error-page-forbidden = You don't have permission to see this page.
+error-page-not-found = This page doesn't exist.
+error-page-unprocessable = The submitted data wasn't valid.
+error-page-too-many-requests = Too many requests. Please wait a moment and try again.
+error-page-internal = Something went wrong on our side. Please try again later.
+error-page-unavailable = Nexo is temporarily unavailable. Please try again in a moment.
+error-page-back = Back to Nexo
+
+## Login throttling; $unit is "second" or "minute"
+
+throttle-wait = { $unit ->
+    [second] { $count ->
+        [one] a second
+       *[other] { $count } seconds
+    }
+   *[minute] { $count ->
+        [one] a minute
+       *[other] { $count } minutes
+    }
+}
+throttle-delayed = Too many failed login attempts. Please wait { throttle-wait } and try again.
+throttle-locked = Too many failed login attempts. Logins are locked for { throttle-wait }.
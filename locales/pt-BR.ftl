@@ -0,0 +1,152 @@
+## Shared form fields
+
+username = Nome de usuário
+password = Senha
+email = E-mail
+confirm-password = Confirme a senha
+optional = (opcional)
+
+## Login
+
+login-title = Entrar
+login-submit = Entrar
+login-forgot-password = Esqueceu sua senha?
+login-no-account = Ainda não tem conta?
+login-create-account = Crie uma
+logout-success = Você saiu da sua conta.
+
+totp-title = Autenticação em dois fatores
+totp-challenge-code = Código do autenticador ou de recuperação
+totp-challenge-submit = Verificar
+
+## Password reset
+
+forgot-password-title = Esqueci a senha
+forgot-password-intro = Informe seu e-mail e enviaremos um link para você escolher uma nova senha.
+forgot-password-submit = Enviar link de redefinição
+forgot-password-back = Voltar para o login
+forgot-password-sent = Se houver uma conta com esse e-mail, um link para redefinir a senha está a caminho.
+
+reset-password-title = Redefinir senha
+reset-password-heading = Escolha uma nova senha
+reset-password-new = Nova senha
+reset-password-submit = Redefinir senha
+
+reset-email-subject = Redefina sua senha do Nexo
+reset-email-body =
+    Olá, { $name },
+
+    Alguém pediu para redefinir a senha da sua conta do Nexo. Se foi você, abra este link em até { $minutes } minutos:
+
+    { $link }
+
+    Se não foi você, pode ignorar este e-mail; sua senha continua a mesma.
+
+## Sign-up
+
+invite-title = Entre no Nexo
+invite-heading = Você recebeu um convite
+invite-intro = Escolha um nome de usuário e uma senha para participar.
+invite-submit = Participar
+
+register-title = Criar conta
+register-submit = Criar conta
+register-have-account = Já tem uma conta?
+register-log-in = Entrar
+
+setup-title = Configurar o Nexo
+setup-heading = Boas-vindas ao Nexo
+setup-intro = Crie a conta de administrador. Ela pode convidar as demais pessoas e gerenciar os papéis delas.
+setup-password-hint = Pelo menos 12 caracteres, combinando três entre: letras minúsculas, letras maiúsculas, dígitos e símbolos.
+setup-submit = Criar conta de administrador
+
+## Home
+
+home-welcome = Olá, { $name }!
+home-logout = Sair
+home-language = Idioma
+
+totp-intro = Proteja sua conta com um aplicativo autenticador.
+totp-enable = Ativar
+totp-enroll-failed = Não foi possível iniciar a ativação
+totp-add-key = Adicione esta chave ao seu aplicativo autenticador e digite o código que ele mostrar.
+totp-open-app = Abrir no aplicativo autenticador
+totp-confirm = Confirmar
+totp-save-codes = Guarde estes códigos de recuperação. Cada um funciona uma única vez e eles não serão mostrados de novo.
+totp-enabled = Ativada.
+totp-codes-left = Códigos de recuperação restantes:
+totp-disable-code = Código para desativar
+totp-disable = Desativar
+
+sessions-title = Onde você está conectado
+sessions-unknown-device = Dispositivo desconhecido
+sessions-this-device = Este dispositivo
+sessions-unknown-address = Endereço desconhecido
+sessions-last-active = última atividade em
+sessions-signed-in = Entrou em
+sessions-name-placeholder = ex.: Notebook do trabalho
+sessions-save = Salvar
+sessions-rename = Renomear
+sessions-log-out = Sair
+sessions-revoke = Revogar
+sessions-revoke-failed = Não foi possível revogar a sessão
+sessions-rename-failed = Não foi possível renomear a sessão
+sessions-revoke-others = Sair de todos os outros lugares
+sessions-revoke-others-failed = Não foi possível encerrar as outras sessões
+
+## Errors swapped into forms
+
+error-invalid-credentials = Usuário ou senha inválidos
+error-user-not-found = Usuário não encontrado. Tente novamente.
+error-create-session-failed = Não foi possível iniciar a sessão. Tente novamente.
+error-create-account-failed = Não foi possível criar a conta. Tente novamente.
+error-reset-password-failed = Não foi possível redefinir a senha. Tente novamente.
+error-verify-code-failed = Não foi possível verificar o código. Tente novamente.
+error-invalid-code = Código inválido
+error-too-many-codes = Muitos códigos inválidos. Entre novamente.
+error-login-expired = Seu login expirou. Entre novamente.
+error-setup-required = O Nexo ainda não foi configurado. Crie primeiro a conta de administrador.
+error-registration-disabled = O cadastro está desativado. Peça um convite a um administrador.
+error-username-taken = Esse nome de usuário já está em uso
+error-email-taken = Já existe uma conta com esse e-mail
+error-cpf-taken = Já existe uma conta com esse CPF
+error-details-taken = Já existe uma conta com esses dados
+error-invitation-invalid = Este convite não é mais válido
+error-reset-link-invalid = Este link de redefinição não é mais válido. Solicite um novo.
+error-database-busy = O banco de dados está ocupado. Tente novamente em instantes.
+
+validation-username-length = O nome de usuário deve ter entre 3 e 32 caracteres
+validation-username-characters = O nome de usuário só pode conter letras, números, '.', '-' e '_'
+validation-password-too-short = A senha deve ter pelo menos 8 caracteres
+validation-password-too-long = A senha deve ter no máximo 128 caracteres
+validation-password-matches-username = A senha não pode ser igual ao nome de usuário
+validation-password-too-weak = A senha deve ter pelo menos 12 caracteres e combinar pelo menos três entre letras minúsculas, letras maiúsculas, dígitos e símbolos
+validation-password-mismatch = As senhas não coincidem
+validation-invalid-email = Informe um endereço de e-mail válido
+validation-invalid-cpf = Informe um CPF válido
+
+## Error pages
+
+error-page-bad-request = Não foi possível entender a requisição.
+error-page-forbidden = Você não tem permissão para ver esta página.
+error-page-not-found = Esta página não existe.
+error-page-unprocessable = Os dados enviados não são válidos.
+error-page-too-many-requests = Muitas requisições. Aguarde um momento e tente novamente.
+error-page-internal = Algo deu errado do nosso lado. Tente novamente mais tarde.
+error-page-unavailable = O Nexo está temporariamente indisponível. Tente novamente em instantes.
+error-page-back = Voltar para o Nexo
+
+## Login throttling; $unit is "second" or "minute"
+
+throttle-wait = { $unit ->
+    [second] { $count ->
+        [one] um segundo
+       *[other] { $count } segundos
+    }
+   *[minute] { $count ->
+        [one] um minuto
+       *[other] { $count } minutos
+    }
+}
+throttle-delayed = Muitas tentativas de login sem sucesso. Aguarde { throttle-wait } e tente novamente.
+throttle-locked = Muitas tentativas de login sem sucesso. O login está bloqueado por { throttle-wait }.
@@ -0,0 +1,191 @@
+//! Frontend assets, compiled into the binary
+//!
+//! Everything under `static/` is embedded at build time, so the server runs
+//! from any directory without internet access. HTMX, Alpine and Tailwind are
+//! vendored into `static/assets/vendor` by `vendor-assets.sh`. Templates link
+//! assets with `{{ asset("path") }}`, which becomes a URL containing the
+//! file's content hash, so browsers can cache them for good.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use include_dir::{include_dir, Dir};
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{Responder, Response};
+use rocket::{Request, State};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+static STATIC: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/static");
+
+/// Directory under `static/` that is served at `/assets`
+const ASSETS_DIR: &str = "assets";
+const CACHE_FOREVER: &str = "public, max-age=31536000, immutable";
+/// For URLs with an outdated hash: the contents no longer match it
+const CACHE_REVALIDATE: &str = "no-cache";
+
+/// Settings from the `assets` table in Rocket.toml
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AssetConfig {
+    /// Directory laid out like `static/` whose files are served instead of
+    /// the embedded ones, so they can be edited without rebuilding. Files
+    /// missing there fall back to the embedded copies.
+    pub override_dir: Option<PathBuf>,
+}
+
+/// First 16 hex digits of the SHA-256 of an asset
+fn content_hash(contents: &[u8]) -> String {
+    let digest = format!("{:x}", Sha256::digest(contents));
+    digest[..16].to_string()
+}
+
+fn collect_hashes(dir: &'static Dir<'static>, hashes: &mut HashMap<&'static Path, String>) {
+    for file in dir.files() {
+        hashes.insert(file.path(), content_hash(file.contents()));
+    }
+    for dir in dir.dirs() {
+        collect_hashes(dir, hashes);
+    }
+}
+
+/// Hashes of the embedded assets, computed once
+static EMBEDDED_HASHES: LazyLock<HashMap<&'static Path, String>> = LazyLock::new(|| {
+    let mut hashes = HashMap::new();
+    if let Some(assets) = STATIC.get_dir(ASSETS_DIR) {
+        collect_hashes(assets, &mut hashes);
+    }
+    hashes
+});
+
+impl AssetConfig {
+    /// A file under `static/`, from the override directory if it has one
+    ///
+    /// Reading the override directory blocks, which is fine for the
+    /// development setups that use it.
+    fn read(&self, path: &Path) -> Option<Cow<'static, [u8]>> {
+        if let Some(dir) = &self.override_dir {
+            match std::fs::read(dir.join(path)) {
+                Ok(contents) => return Some(Cow::Owned(contents)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Failed to read {}: {}", dir.join(path).display(), e),
+            }
+        }
+        STATIC.get_file(path).map(|file| Cow::Borrowed(file.contents()))
+    }
+
+    /// Content hash of an asset, or `None` if there is no such asset
+    fn hash(&self, path: &Path) -> Option<String> {
+        match self.read(path)? {
+            Cow::Borrowed(_) => EMBEDDED_HASHES.get(path).cloned(),
+            Cow::Owned(contents) => Some(content_hash(&contents)),
+        }
+    }
+
+    /// The URL an asset under `static/assets` is served at; it changes
+    /// whenever the asset does
+    pub fn url(&self, path: &str) -> Option<String> {
+        let hash = self.hash(&Path::new(ASSETS_DIR).join(path))?;
+        Some(format!("/assets/{}/{}", hash, path))
+    }
+}
+
+/// An asset and how long browsers may keep it
+pub struct Asset {
+    contents: Cow<'static, [u8]>,
+    content_type: ContentType,
+    hash: String,
+    cache_control: &'static str,
+}
+
+impl<'r> Responder<'r, 'static> for Asset {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let etag = format!("\"{}\"", self.hash);
+        let mut response = Response::build();
+        response
+            .header(Header::new("ETag", etag.clone()))
+            .raw_header("Cache-Control", self.cache_control);
+
+        let cached = request.headers().get("If-None-Match").flat_map(|tags| tags.split(',')).any(|tag| tag.trim() == etag);
+        if cached {
+            return response.status(Status::NotModified).ok();
+        }
+        let contents = match self.contents {
+            Cow::Borrowed(contents) => io::Cursor::new(contents.to_vec()),
+            Cow::Owned(contents) => io::Cursor::new(contents),
+        };
+        response.header(self.content_type).sized_body(None, contents).ok()
+    }
+}
+
+/// A file from `static/assets`
+///
+/// URLs carry the content hash from [`AssetConfig::url`]; one that no longer
+/// matches still gets the current file, but not cached for good.
+#[get("/assets/<hash>/<path..>")]
+pub async fn asset(hash: &str, path: PathBuf, config: &State<AssetConfig>) -> Option<Asset> {
+    let path = Path::new(ASSETS_DIR).join(path);
+    let contents = config.read(&path)?;
+    let current = match &contents {
+        Cow::Borrowed(_) => EMBEDDED_HASHES.get(path.as_path())?.clone(),
+        Cow::Owned(contents) => content_hash(contents),
+    };
+    let content_type = path
+        .extension()
+        .and_then(|extension| ContentType::from_extension(&extension.to_string_lossy()))
+        .unwrap_or(ContentType::Binary);
+
+    Some(Asset {
+        contents,
+        content_type,
+        cache_control: if hash == current { CACHE_FOREVER } else { CACHE_REVALIDATE },
+        hash: current,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+
+    const TEST_DIR: &str = "test_asset_override";
+
+    async fn client(config: AssetConfig) -> Client {
+        let rocket = rocket::build().mount("/", routes![asset]).manage(config);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_hashed_asset_urls() {
+        let hash = content_hash(b"console.log(1);\n");
+        assert_eq!(AssetConfig::default().url("extra.js"), None);
+
+        std::fs::create_dir_all(format!("{}/assets", TEST_DIR)).unwrap();
+        std::fs::write(format!("{}/assets/extra.js", TEST_DIR), "console.log(1);\n").unwrap();
+        let config = AssetConfig { override_dir: Some(TEST_DIR.into()) };
+        assert_eq!(config.url("extra.js"), Some(format!("/assets/{}/extra.js", hash)));
+
+        let client = client(config).await;
+        let response = client.get(format!("/assets/{}/extra.js", hash)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JavaScript));
+        assert_eq!(response.headers().get_one("Cache-Control"), Some(CACHE_FOREVER));
+        assert_eq!(response.headers().get_one("ETag"), Some(format!("\"{}\"", hash).as_str()));
+
+        let response = client.get("/assets/0123456789abcdef/extra.js").dispatch().await;
+        assert_eq!(response.headers().get_one("Cache-Control"), Some(CACHE_REVALIDATE));
+
+        let response = client.get(format!("/assets/{}/extra.js", hash))
+            .header(Header::new("If-None-Match", format!("\"{}\"", hash)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotModified);
+
+        assert_eq!(client.get("/assets/x/../index.html").dispatch().await.status(), Status::NotFound);
+        assert_eq!(client.get("/assets/x/nothing.js").dispatch().await.status(), Status::NotFound);
+        std::fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use rocket::catcher::{BoxFuture, Catcher};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::{Request, Response};
+use rocket_db_pools::Database;
+
+use crate::cookies::{CookieConfig, SESSION_COOKIE};
+use crate::error::{is_api_request, ApiError};
+use crate::database::{DbError, NexoDB, get_username_by_id, validate_session};
+use crate::database::roles::get_user_access;
+
+/// The user behind a valid session cookie
+///
+/// This is the one place requests are authenticated. Routes that need a
+/// logged-in user take it as a guard; when there is none the request fails
+/// with 401, which [`unauthorized_catcher`] turns into a JSON error for the
+/// API and a redirect to the login page for everything else. Routes that
+/// only behave differently for logged-in users take `Option<AuthenticatedUser>`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: i32,
+    pub name: String,
+    /// The cookie's token, for routes that act on the current session
+    pub session_token: String,
+    pub roles: Vec<String>,
+    /// Everything the user's roles allow, e.g. `finance.view`
+    pub permissions: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+/// Look up the session cookie's user. Runs at most once per request.
+///
+/// Fails with 401 when there is no valid session and with 503 when the
+/// database is too busy to tell.
+async fn resolve(request: &Request<'_>) -> Result<AuthenticatedUser, Status> {
+    let token = request.cookies()
+        .get(&CookieConfig::of(request).name(SESSION_COOKIE))
+        .ok_or(Status::Unauthorized)?
+        .value()
+        .to_string();
+    let db = NexoDB::fetch(request.rocket()).ok_or(Status::Unauthorized)?;
+
+    let failed = |e: DbError| {
+        eprintln!("Failed to load session user: {}", e);
+        if e.is_unavailable() { Status::ServiceUnavailable } else { Status::Unauthorized }
+    };
+    let id = validate_session(db, &token).await.map_err(failed)?.ok_or(Status::Unauthorized)?;
+    let name = get_username_by_id(db, id).await.map_err(failed)?.ok_or(Status::Unauthorized)?;
+    let (roles, permissions) = get_user_access(db, id).await.map_err(failed)?;
+    Ok(AuthenticatedUser { id, name, session_token: token, roles, permissions })
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.local_cache_async(resolve(request)).await {
+            Ok(user) => Outcome::Success(user.clone()),
+            Err(status) => Outcome::Error((*status, ())),
+        }
+    }
+}
+
+/// A permission a route can require through [`Authorized`]
+pub trait Permission: Send + Sync + 'static {
+    /// The permission's name in the `permissions` table
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($(#[$doc:meta])* $kind:ident => $name:literal,)*) => {
+        $(
+            $(#[$doc])*
+            pub struct $kind;
+
+            impl Permission for $kind {
+                const NAME: &'static str = $name;
+            }
+        )*
+    };
+}
+
+permissions! {
+    /// See every user and change their roles
+    ManageUsers => "users.manage",
+    ManageInvitations => "invitations.manage",
+    ManageLockouts => "lockouts.manage",
+    ViewJobs => "jobs.view",
+    /// Run migrations and other database maintenance
+    ManageDatabase => "database.manage",
+}
+
+/// A logged-in user whose roles grant permission `P`
+///
+/// Fails with 401 like [`AuthenticatedUser`] when nobody is logged in, and
+/// with 403 when the user lacks the permission.
+pub struct Authorized<P: Permission> {
+    pub user: AuthenticatedUser,
+    permission: PhantomData<P>,
+}
+
+impl<P: Permission> Deref for Authorized<P> {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.user
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for Authorized<P> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = match AuthenticatedUser::from_request(request).await {
+            Outcome::Success(user) => user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        if user.has_permission(P::NAME) {
+            Outcome::Success(Authorized { user, permission: PhantomData })
+        } else {
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+fn unauthorized<'r>(status: Status, request: &'r Request<'_>) -> BoxFuture<'r> {
+    Box::pin(async move {
+        if is_api_request(request) {
+            return ApiError::Unauthorized.respond_to(request);
+        }
+
+        // HTMX swaps in the body of a plain redirect instead of following
+        // it, so tell it to navigate instead
+        if request.headers().contains("HX-Request") {
+            return Response::build()
+                .status(status)
+                .header(Header::new("HX-Redirect", "/"))
+                .ok();
+        }
+
+        Response::build()
+            .status(Status::SeeOther)
+            .header(Header::new("Location", "/"))
+            .ok()
+    })
+}
+
+/// Catcher for requests an [`AuthenticatedUser`] guard turned away
+///
+/// Written by hand rather than with `#[catch]`, which would force the 401
+/// status onto the redirect.
+pub fn unauthorized_catcher() -> Catcher {
+    Catcher::new(Status::Unauthorized.code, unauthorized)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    #[get("/page")]
+    fn page(_user: AuthenticatedUser) -> &'static str {
+        "secret page"
+    }
+
+    #[get("/api/data")]
+    fn data(_user: AuthenticatedUser) -> &'static str {
+        "secret data"
+    }
+
+    #[get("/api/admin")]
+    fn admin(_user: Authorized<ManageUsers>) -> &'static str {
+        "admin data"
+    }
+
+    #[get("/api/denied")]
+    fn denied() -> Status {
+        Status::Forbidden
+    }
+
+    #[get("/optional")]
+    fn optional(user: Option<AuthenticatedUser>) -> &'static str {
+        if user.is_some() { "hello" } else { "anonymous" }
+    }
+
+    fn client() -> Client {
+        // No database is attached, so no session can ever resolve
+        let rocket = rocket::build()
+            .mount("/", routes![page, data, admin, denied, optional])
+            .register("/", vec![unauthorized_catcher()])
+            .register("/", crate::error::catchers());
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn problem_code(response: rocket::local::blocking::LocalResponse<'_>) -> String {
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        body["code"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_pages_redirect_to_login() {
+        let client = client();
+        let response = client.get("/page").cookie(("session_token", "nope")).dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/"));
+    }
+
+    #[test]
+    fn test_htmx_requests_get_hx_redirect() {
+        let client = client();
+        let response = client.get("/page").header(Header::new("HX-Request", "true")).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("HX-Redirect"), Some("/"));
+    }
+
+    #[test]
+    fn test_api_gets_json_401() {
+        let client = client();
+        let response = client.get("/api/data").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("Location"), None);
+        assert_eq!(problem_code(response), "unauthorized");
+    }
+
+    #[test]
+    fn test_permission_guard_needs_a_user_first() {
+        let client = client();
+        let response = client.get("/api/admin").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(problem_code(response), "unauthorized");
+    }
+
+    #[test]
+    fn test_api_gets_json_403() {
+        let client = client();
+        let response = client.get("/api/denied").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(problem_code(response), "forbidden");
+    }
+
+    #[test]
+    fn test_has_permission() {
+        let user = AuthenticatedUser {
+            id: 1,
+            name: "maria".to_string(),
+            session_token: String::new(),
+            roles: vec!["finance".to_string()],
+            permissions: vec!["finance.view".to_string()],
+        };
+        assert!(user.has_permission("finance.view"));
+        assert!(!user.has_permission(ManageUsers::NAME));
+    }
+
+    #[test]
+    fn test_optional_guard_never_fails() {
+        let client = client();
+        let response = client.get("/optional").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().as_deref(), Some("anonymous"));
+    }
+}
@@ -0,0 +1,343 @@
+//! Operate a nexo deployment from the command line
+//!
+//! Works directly against the SQLite file named in Rocket.toml for the
+//! active profile (`ROCKET_PROFILE`), so it can be used before the server
+//! has ever started, e.g. to create the first admin.
+
+use std::error::Error;
+use std::io::BufRead;
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use rocket::time::OffsetDateTime;
+
+use nexo::config;
+use nexo::crypto::{hash_password, PasswordHashConfig};
+use nexo::database::{
+    self, NexoDB, UserConflict, create_user, delete_user_session, delete_user_sessions, find_user_conflict,
+    get_user_id_by_username, list_user_sessions, set_user_disabled, set_user_password,
+};
+use nexo::database::maintenance::{backup, database_status, vacuum};
+use nexo::database::migrations::{pending_migrations, run_migrations};
+use nexo::database::roles::{
+    ADMIN_ROLE, SetRolesError, get_user_access, is_last_admin, list_users_with_roles, role_exists, set_user_roles,
+};
+use nexo::validation::{validate_admin_password, validate_email, validate_password, validate_username};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type CliResult = Result<(), BoxError>;
+
+#[derive(Parser)]
+#[command(name = "nexo-admin", version, about = "Operate a nexo deployment")]
+struct Cli {
+    /// SQLite database to use instead of the one in Rocket.toml
+    #[arg(long, global = true)]
+    database: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the schema version, pending migrations and table sizes
+    Status,
+    /// Apply pending migrations
+    Migrate,
+    /// Rebuild the database file to reclaim unused space
+    Vacuum,
+    /// Write a consistent copy of the database to a new file
+    Backup {
+        path: String,
+    },
+    /// Manage users, their passwords and roles
+    #[command(subcommand)]
+    User(UserCommand),
+    /// List and revoke users' sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// List users with their roles
+    List,
+    /// Create a user. Prompts for the password.
+    Create {
+        name: String,
+        #[arg(long)]
+        email: Option<String>,
+        /// Role to grant instead of the default; repeat for several
+        #[arg(long = "role")]
+        roles: Vec<String>,
+        /// Read the password from the first line of stdin
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Stop a user from logging in and end their sessions
+    Disable {
+        name: String,
+    },
+    /// Let a disabled user log in again
+    Enable {
+        name: String,
+    },
+    /// Set a new password and end the user's sessions
+    ResetPassword {
+        name: String,
+        /// Read the password from the first line of stdin
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Replace a user's roles
+    Roles {
+        name: String,
+        #[arg(required = true)]
+        roles: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionCommand {
+    /// List a user's active sessions
+    List {
+        user: String,
+    },
+    /// Revoke one of a user's sessions, or all of them
+    Revoke {
+        user: String,
+        /// Session to revoke, as shown by `session list`
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        id: Option<i64>,
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+/// Read the database URL and password hashing settings like the server does
+fn load_config(database: Option<String>) -> Result<(String, PasswordHashConfig), BoxError> {
+    let figment = rocket::Config::figment();
+    let url = match database {
+        Some(url) => url,
+        None => figment
+            .extract_inner::<String>("databases.nexo_db.url")
+            .map_err(|e| format!("no database configured for this profile: {}", e))?,
+    };
+    let hash_config = config::extract::<PasswordHashConfig>(&figment, "password_hashing")?;
+
+    Ok((url, hash_config))
+}
+
+/// Refuse to work on a schema this binary would misread
+async fn require_current_schema(db: &NexoDB) -> CliResult {
+    let pending = pending_migrations(db).await?;
+    if !pending.is_empty() {
+        return Err(format!("{} migration(s) pending, run `nexo-admin migrate` first", pending.len()).into());
+    }
+    Ok(())
+}
+
+async fn user_id(db: &NexoDB, name: &str) -> Result<i32, BoxError> {
+    get_user_id_by_username(db, name).await?.ok_or_else(|| format!("no user named '{}'", name).into())
+}
+
+/// Ask for a new password twice, or take it from stdin for scripts. Admins
+/// get the stricter policy the setup page uses.
+fn read_password(username: &str, from_stdin: bool, admin: bool) -> Result<String, BoxError> {
+    let password = if from_stdin {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    } else {
+        let password = rpassword::prompt_password("Password: ")?;
+        if rpassword::prompt_password("Repeat password: ")? != password {
+            return Err("passwords do not match".into());
+        }
+        password
+    };
+
+    if admin {
+        validate_admin_password(&password, username)?;
+    } else {
+        validate_password(&password, username)?;
+    }
+    Ok(password)
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    match OffsetDateTime::from_unix_timestamp(timestamp) {
+        Ok(t) => format!("{}-{:02}-{:02} {:02}:{:02} UTC", t.year(), t.month() as u8, t.day(), t.hour(), t.minute()),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+async fn run(cli: Cli) -> CliResult {
+    let (url, hash_config) = load_config(cli.database)?;
+    let db = database::connect(&url).await?;
+
+    match cli.command {
+        Command::Status => {
+            let status = database_status(&db).await?;
+            println!("Database:       {}", url);
+            println!("Schema version: {} (latest {})", status.schema_version, status.latest_version);
+            for migration in &status.pending_migrations {
+                println!("Pending:        {:04}_{}", migration.version, migration.name);
+            }
+            for table in &status.tables {
+                println!("  {:<24} {:>8} rows", table.table, table.rows);
+            }
+        }
+        Command::Migrate => {
+            let applied = run_migrations(&db).await?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+        }
+        Command::Vacuum => {
+            vacuum(&db).await?;
+            println!("Vacuumed {}", url);
+        }
+        Command::Backup { path } => {
+            if std::path::Path::new(&path).exists() {
+                return Err(format!("{} already exists", path).into());
+            }
+            backup(&db, &path).await?;
+            println!("Backed up {} to {}", url, path);
+        }
+        Command::User(command) => {
+            require_current_schema(&db).await?;
+            run_user_command(&db, &hash_config, command).await?;
+        }
+        Command::Session(command) => {
+            require_current_schema(&db).await?;
+            run_session_command(&db, command).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_user_command(db: &NexoDB, hash_config: &PasswordHashConfig, command: UserCommand) -> CliResult {
+    match command {
+        UserCommand::List => {
+            for user in list_users_with_roles(db).await? {
+                let disabled = if user.disabled { " (disabled)" } else { "" };
+                println!("{:>5}  {:<32} {}{}", user.id, user.name, user.roles.join(","), disabled);
+            }
+        }
+        UserCommand::Create { name, email, roles, password_stdin } => {
+            let name = validate_username(&name)?;
+            let email = email.as_deref().map(validate_email).transpose()?;
+            match find_user_conflict(db, &name, email.as_deref(), None).await? {
+                Some(UserConflict::Name) => return Err(format!("a user named '{}' already exists", name).into()),
+                Some(_) => return Err("that email address is already in use".into()),
+                None => {}
+            }
+            for role in &roles {
+                if !role_exists(db, role).await? {
+                    return Err(format!("no role named '{}'", role).into());
+                }
+            }
+
+            let password = read_password(&name, password_stdin, roles.iter().any(|r| r == ADMIN_ROLE))?;
+            let psw_hash = hash_password(hash_config, &password)?;
+            let id = create_user(db, &name, &psw_hash, email.as_deref(), None).await?;
+            if !roles.is_empty() {
+                set_user_roles(db, id, &roles).await.map_err(|e| format!("{:?}", e))?;
+            }
+            println!("Created user '{}' with ID {}", name, id);
+        }
+        UserCommand::Disable { name } => {
+            let id = user_id(db, &name).await?;
+            if is_last_admin(db, id).await? {
+                return Err("at least one user must keep the admin role".into());
+            }
+            set_user_disabled(db, id, true).await?;
+            println!("Disabled '{}'", name);
+        }
+        UserCommand::Enable { name } => {
+            set_user_disabled(db, user_id(db, &name).await?, false).await?;
+            println!("Enabled '{}'", name);
+        }
+        UserCommand::ResetPassword { name, password_stdin } => {
+            let id = user_id(db, &name).await?;
+            let (roles, _) = get_user_access(db, id).await?;
+            let password = read_password(&name, password_stdin, roles.iter().any(|r| r == ADMIN_ROLE))?;
+            let psw_hash = hash_password(hash_config, &password)?;
+            set_user_password(db, id, &psw_hash).await?;
+            println!("Password changed for '{}'; their sessions were ended", name);
+        }
+        UserCommand::Roles { name, roles } => {
+            match set_user_roles(db, user_id(db, &name).await?, &roles).await {
+                Ok(()) => println!("'{}' now has roles: {}", name, roles.join(", ")),
+                Err(SetRolesError::UnknownRole(role)) => return Err(format!("no role named '{}'", role).into()),
+                Err(SetRolesError::LastAdmin) => return Err("at least one user must keep the admin role".into()),
+                Err(SetRolesError::UnknownUser) => return Err(format!("no user named '{}'", name).into()),
+                Err(SetRolesError::Database(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_session_command(db: &NexoDB, command: SessionCommand) -> CliResult {
+    match command {
+        SessionCommand::List { user } => {
+            let sessions = list_user_sessions(db, user_id(db, &user).await?, "").await?;
+            if sessions.is_empty() {
+                println!("'{}' has no active sessions", user);
+            }
+            for session in sessions {
+                println!(
+                    "{:>5}  last seen {}  from {}  {}",
+                    session.id,
+                    format_timestamp(session.last_seen_at),
+                    session.ip.as_deref().unwrap_or("unknown address"),
+                    session.name.or(session.user_agent).unwrap_or_default(),
+                );
+            }
+        }
+        SessionCommand::Revoke { user, id: Some(id), .. } => {
+            if !delete_user_session(db, user_id(db, &user).await?, id).await? {
+                return Err(format!("'{}' has no session {}", user, id).into());
+            }
+            println!("Revoked session {}", id);
+        }
+        SessionCommand::Revoke { user, id: None, .. } => {
+            let revoked = delete_user_sessions(db, user_id(db, &user).await?).await?;
+            println!("Revoked {} session(s) of '{}'", revoked, user);
+        }
+    }
+
+    Ok(())
+}
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_session_revoke_needs_an_id_or_all() {
+        assert!(Cli::try_parse_from(["nexo-admin", "session", "revoke", "maria"]).is_err());
+        assert!(Cli::try_parse_from(["nexo-admin", "session", "revoke", "maria", "3", "--all"]).is_err());
+        assert!(Cli::try_parse_from(["nexo-admin", "session", "revoke", "maria", "--all"]).is_ok());
+    }
+}
@@ -0,0 +1,37 @@
+use rocket::fairing::AdHoc;
+use rocket::figment::{self, Figment};
+use serde::de::DeserializeOwned;
+
+/// Read the `key` table of the active Rocket.toml profile into `T`.
+///
+/// A missing table falls back to `T::default()`, so a deployment only has to
+/// list the settings it wants to change.
+pub fn extract<T>(figment: &Figment, key: &str) -> Result<T, Box<figment::Error>>
+where
+    T: DeserializeOwned + Default,
+{
+    match figment.extract_inner::<T>(key) {
+        Ok(config) => Ok(config),
+        Err(e) if e.missing() => Ok(T::default()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Build a fairing that [`extract`]s `T` and places it in managed state.
+///
+/// A table that is present but invalid aborts ignition instead of silently
+/// running with defaults.
+pub fn section<T>(name: &'static str, key: &'static str) -> AdHoc
+where
+    T: DeserializeOwned + Default + Send + Sync + 'static,
+{
+    AdHoc::try_on_ignite(name, move |rocket| async move {
+        match extract::<T>(rocket.figment(), key) {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(e) => {
+                eprintln!("Invalid [{}] configuration: {}", key, e);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,192 @@
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, SameSite};
+use rocket::Request;
+use serde::Deserialize;
+
+use crate::config;
+
+/// Cookie that carries the session token
+pub const SESSION_COOKIE: &str = "session_token";
+
+/// Which cross-site requests the browser sends cookies with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    /// Top-level navigations from other sites, e.g. links in emails, stay
+    /// logged in
+    Lax,
+    /// Only requests started from nexo's own pages
+    Strict,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> Self {
+        match same_site {
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::Strict => SameSite::Strict,
+        }
+    }
+}
+
+/// Settings from the `cookies` table in Rocket.toml, applied to the session,
+/// CSRF and login challenge cookies
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct CookieConfig {
+    /// Only send the cookies over HTTPS
+    pub secure: bool,
+    pub same_site: CookieSameSite,
+    /// Share the cookies with subdomains of this domain. By default only the
+    /// host that set them gets them back.
+    pub domain: Option<String>,
+    /// Prefix cookie names with `__Host-`, which browsers only accept from a
+    /// secure origin for the whole host, so no subdomain can overwrite them
+    pub host_prefix: bool,
+}
+
+const DEFAULT_CONFIG: CookieConfig = CookieConfig {
+    secure: false,
+    same_site: CookieSameSite::Lax,
+    domain: None,
+    host_prefix: false,
+};
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+impl CookieConfig {
+    /// The configuration a request is served with; the defaults when
+    /// [`stage`] isn't attached
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r CookieConfig {
+        static DEFAULT: CookieConfig = DEFAULT_CONFIG;
+        request.rocket().state::<CookieConfig>().unwrap_or(&DEFAULT)
+    }
+
+    /// Settings browsers would refuse or that defeat each other
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.host_prefix && !self.secure {
+            return Err("host_prefix requires secure");
+        }
+        if self.host_prefix && self.domain.is_some() {
+            return Err("host_prefix cookies can't have a domain");
+        }
+        Ok(())
+    }
+
+    /// The name a cookie is stored under in the browser
+    pub fn name(&self, name: &str) -> String {
+        if self.host_prefix {
+            format!("__Host-{}", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// An HTTP-only cookie for the whole site with the configured attributes
+    pub fn cookie(&self, name: &str, value: String) -> Cookie<'static> {
+        self.cookie_for_path(name, value, "/")
+    }
+
+    /// Like [`CookieConfig::cookie`], but only sent to URLs under `path`.
+    /// `__Host-` cookies must cover the whole site, so with `host_prefix` the
+    /// path stays `/`.
+    pub fn cookie_for_path(&self, name: &str, value: String, path: &str) -> Cookie<'static> {
+        let mut cookie = self.removal_for_path(name, path);
+        cookie.set_value(value);
+        cookie.set_http_only(true); // Prevent XSS attacks
+        cookie.set_secure(self.secure);
+        cookie.set_same_site(SameSite::from(self.same_site));
+        cookie
+    }
+
+    /// What to pass to `CookieJar::remove` to delete a cookie set with
+    /// [`CookieConfig::cookie`]; the path and domain have to match
+    pub fn removal(&self, name: &str) -> Cookie<'static> {
+        self.removal_for_path(name, "/")
+    }
+
+    /// What to pass to `CookieJar::remove` to delete a cookie set with
+    /// [`CookieConfig::cookie_for_path`]
+    pub fn removal_for_path(&self, name: &str, path: &str) -> Cookie<'static> {
+        let mut cookie = Cookie::new(self.name(name), "");
+        cookie.set_path(if self.host_prefix { "/" } else { path }.to_string());
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+/// Fairing that reads and checks the `cookies` table and places it in
+/// managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Cookies", |rocket| async {
+        let config = match config::extract::<CookieConfig>(rocket.figment(), "cookies") {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid [cookies] configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match config.validate() {
+            Ok(()) => Ok(rocket.manage(config)),
+            Err(e) => {
+                eprintln!("Invalid [cookies] configuration: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_cookie() {
+        let cookie = CookieConfig::default().cookie(SESSION_COOKIE, "token".to_string());
+        assert_eq!(cookie.name(), "session_token");
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(false));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.domain(), None);
+
+        let cookie = CookieConfig::default().cookie_for_path("challenge", "token".to_string(), "/login");
+        assert_eq!(cookie.path(), Some("/login"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(CookieConfig::default().removal_for_path("challenge", "/login").path(), Some("/login"));
+    }
+
+    #[test]
+    fn test_host_prefix() {
+        let config = CookieConfig { secure: true, host_prefix: true, ..CookieConfig::default() };
+        assert_eq!(config.validate(), Ok(()));
+        let cookie = config.cookie(SESSION_COOKIE, "token".to_string());
+        assert_eq!(cookie.name(), "__Host-session_token");
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(config.removal(SESSION_COOKIE).name(), "__Host-session_token");
+        assert_eq!(config.cookie_for_path("challenge", "token".to_string(), "/login").path(), Some("/"));
+
+        // Browsers drop __Host- cookies that aren't secure or name a domain
+        assert!(CookieConfig { secure: false, ..config.clone() }.validate().is_err());
+        assert!(CookieConfig { domain: Some("example.com".to_string()), ..config }.validate().is_err());
+    }
+
+    #[test]
+    fn test_domain_cookie() {
+        let config = CookieConfig {
+            domain: Some("example.com".to_string()),
+            same_site: CookieSameSite::Strict,
+            ..CookieConfig::default()
+        };
+        let cookie = config.cookie(SESSION_COOKIE, "token".to_string());
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(config.removal(SESSION_COOKIE).domain(), Some("example.com"));
+    }
+}
@@ -1,24 +1,166 @@
 use sha2::{Sha256, Digest};
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 use rand::RngCore;
+use argon2::{Algorithm, Argon2, Params, Version};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+use serde::Deserialize;
 
-/// A pure function that takes a salt and password and returns a hash.
-/// 
-/// This function uses SHA-256 to create a secure hash of the password
-/// combined with the salt. The salt is prepended to the password before hashing.
-/// 
+/// Argon2id cost parameters for password hashing.
+///
+/// Read from the `password_hashing` table of the active Rocket.toml profile.
+/// Any field left out falls back to the OWASP-recommended minimum
+/// (19 MiB of memory, 2 iterations, 1 lane).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        PasswordHashConfig {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordVerification {
+    /// The password matches and the hash uses the configured parameters.
+    Valid,
+    /// The password matches, but the hash was produced with different
+    /// parameters and should be replaced with a fresh one.
+    ValidNeedsRehash,
+    /// The password does not match.
+    Invalid,
+}
+
+impl PasswordVerification {
+    pub fn is_valid(self) -> bool {
+        !matches!(self, PasswordVerification::Invalid)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordHashError {
+    /// The configured cost parameters are rejected by Argon2.
+    InvalidConfig(String),
+    /// The stored hash is not a PHC string we know how to verify.
+    MalformedHash(String),
+    /// Hashing itself failed.
+    Hashing(String),
+}
+
+impl fmt::Display for PasswordHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordHashError::InvalidConfig(e) => write!(f, "invalid password hashing parameters: {}", e),
+            PasswordHashError::MalformedHash(e) => write!(f, "malformed password hash: {}", e),
+            PasswordHashError::Hashing(e) => write!(f, "password hashing failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PasswordHashError {}
+
+fn argon2_from_config(config: &PasswordHashConfig) -> Result<Argon2<'static>, PasswordHashError> {
+    let params = Params::new(config.memory_kib, config.iterations, config.parallelism, None)
+        .map_err(|e| PasswordHashError::InvalidConfig(e.to_string()))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+/// Hash a password with Argon2id and a freshly generated random salt.
+///
 /// # Arguments
-/// * `salt` - A string used to salt the password
+/// * `config` - Argon2id cost parameters
 /// * `password` - The password to hash
-/// 
+///
 /// # Returns
-/// A hexadecimal string representation of the hash
-pub fn hash_password(salt: &str, password: &str) -> String {
+/// A PHC string (`$argon2id$v=19$m=...,t=...,p=...$salt$hash`) that embeds
+/// the salt and parameters, suitable for storing in `users.psw_hash`
+pub fn hash_password(config: &PasswordHashConfig, password: &str) -> Result<String, PasswordHashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2_from_config(config)?
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| PasswordHashError::Hashing(e.to_string()))?;
+    Ok(hash.to_string())
+}
+
+/// Salt that every pre-Argon2 hash was computed with.
+const LEGACY_SALT: &str = "salt";
+
+/// Whether a stored hash is a bare hex SHA-256 digest from before the move
+/// to Argon2id.
+pub fn is_legacy_hash(stored_hash: &str) -> bool {
+    stored_hash.len() == 64 && stored_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The pre-Argon2 scheme: a single SHA-256 over the shared salt and password.
+/// Only kept so that existing rows can be verified and upgraded.
+fn legacy_sha256_hash(password: &str) -> String {
     let mut hasher = Sha256::new();
-    Digest::update(&mut hasher, salt.as_bytes());
+    Digest::update(&mut hasher, LEGACY_SALT.as_bytes());
     Digest::update(&mut hasher, password.as_bytes());
-    let result = hasher.finalize();
-    format!("{:x}", result)
+    format!("{:x}", hasher.finalize())
+}
+
+/// Compare two byte strings without short-circuiting on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check a password against a stored hash.
+///
+/// Argon2 PHC strings take their salt and parameters from the stored hash, so
+/// hashes made with older settings keep verifying. Legacy SHA-256 digests are
+/// also accepted. Both of those come back as
+/// [`PasswordVerification::ValidNeedsRehash`] so callers can upgrade them.
+pub fn verify_password(
+    config: &PasswordHashConfig,
+    stored_hash: &str,
+    password: &str,
+) -> Result<PasswordVerification, PasswordHashError> {
+    if is_legacy_hash(stored_hash) {
+        let computed = legacy_sha256_hash(password);
+        return if constant_time_eq(computed.as_bytes(), stored_hash.to_ascii_lowercase().as_bytes()) {
+            Ok(PasswordVerification::ValidNeedsRehash)
+        } else {
+            Ok(PasswordVerification::Invalid)
+        };
+    }
+
+    let parsed = PasswordHash::new(stored_hash)
+        .map_err(|e| PasswordHashError::MalformedHash(e.to_string()))?;
+
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => {}
+        Err(argon2::password_hash::Error::Password) => return Ok(PasswordVerification::Invalid),
+        Err(e) => return Err(PasswordHashError::MalformedHash(e.to_string())),
+    }
+
+    let params = Params::try_from(&parsed)
+        .map_err(|e| PasswordHashError::MalformedHash(e.to_string()))?;
+    let up_to_date = parsed.algorithm == Algorithm::Argon2id.ident()
+        && parsed.version == Some(Version::V0x13.into())
+        && params.m_cost() == config.memory_kib
+        && params.t_cost() == config.iterations
+        && params.p_cost() == config.parallelism;
+
+    if up_to_date {
+        Ok(PasswordVerification::Valid)
+    } else {
+        Ok(PasswordVerification::ValidNeedsRehash)
+    }
 }
 
 /// Generate a secure random token for session management
@@ -47,11 +189,24 @@ pub fn generate_session_token() -> String {
     let entropy = format!("{}{}", timestamp, std::process::id());
     
     Digest::update(&mut hasher, entropy.as_bytes());
-    Digest::update(&mut hasher, &random_bytes);
+    Digest::update(&mut hasher, random_bytes);
     let result = hasher.finalize();
     format!("{:x}", result)
 }
 
+/// Hash a high-entropy secret token for storage
+///
+/// Session tokens, recovery codes and similar values are random enough that a
+/// single SHA-256 is sufficient; unlike passwords they don't need a slow hash.
+///
+/// # Returns
+/// A hexadecimal string representation of the hash
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    Digest::update(&mut hasher, token.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
 /// Get current timestamp as Unix timestamp
 pub fn get_current_timestamp() -> i64 {
     SystemTime::now()
@@ -64,53 +219,83 @@ pub fn get_current_timestamp() -> i64 {
 mod tests {
     use super::*;
 
+    // Cheap parameters so the tests don't spend seconds inside Argon2.
+    const TEST_CONFIG: PasswordHashConfig = PasswordHashConfig {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    };
+
     #[test]
     fn test_hash_password() {
-        let salt = "salt";
-        let password = "1234";
-        let hash1 = hash_password(salt, password);
-        let hash2 = hash_password(salt, password);
-        
-        // Same inputs should produce same hash
-        assert_eq!(hash1, hash2);
-        
-        // Different salt should produce different hash
-        let hash3 = hash_password("differentsalt", password);
-        assert_ne!(hash1, hash3);
-        
-        // Different password should produce different hash
-        let hash4 = hash_password(salt, "differentpassword");
-        assert_ne!(hash1, hash4);
+        let hash1 = hash_password(&TEST_CONFIG, "1234").unwrap();
+        let hash2 = hash_password(&TEST_CONFIG, "1234").unwrap();
+
+        // Every hash gets its own salt
+        assert_ne!(hash1, hash2);
+
+        // Both still verify
+        assert_eq!(verify_password(&TEST_CONFIG, &hash1, "1234"), Ok(PasswordVerification::Valid));
+        assert_eq!(verify_password(&TEST_CONFIG, &hash2, "1234"), Ok(PasswordVerification::Valid));
+    }
+
+    #[test]
+    fn test_hash_is_argon2id_phc_string() {
+        let hash = hash_password(&TEST_CONFIG, "password").unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"), "unexpected hash format: {}", hash);
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let hash = hash_password(&TEST_CONFIG, "correct horse").unwrap();
+        assert_eq!(verify_password(&TEST_CONFIG, &hash, "battery staple"), Ok(PasswordVerification::Invalid));
     }
 
     #[test]
     fn test_empty_password() {
-        let hash = hash_password("salt", "");
-        assert!(!hash.is_empty());
+        let hash = hash_password(&TEST_CONFIG, "").unwrap();
+        assert_eq!(verify_password(&TEST_CONFIG, &hash, ""), Ok(PasswordVerification::Valid));
+        assert_eq!(verify_password(&TEST_CONFIG, &hash, " "), Ok(PasswordVerification::Invalid));
     }
-    
+
+    #[test]
+    fn test_changed_parameters_need_rehash() {
+        let hash = hash_password(&TEST_CONFIG, "1234").unwrap();
+        let stronger = PasswordHashConfig { iterations: 2, ..TEST_CONFIG };
+
+        assert_eq!(verify_password(&stronger, &hash, "1234"), Ok(PasswordVerification::ValidNeedsRehash));
+        assert_eq!(verify_password(&stronger, &hash, "4321"), Ok(PasswordVerification::Invalid));
+    }
+
     #[test]
-    fn test_know_hash(){
-        let salt = "salt";
-        let password = "1234";
-        let hash1 = hash_password(salt, password);
+    fn test_malformed_hash() {
+        let result = verify_password(&TEST_CONFIG, "not a phc string", "1234");
+        assert!(matches!(result, Err(PasswordHashError::MalformedHash(_))));
+    }
 
+    #[test]
+    fn test_legacy_hash_needs_rehash() {
         // This hash was generated using: echo -n "salt1234" | sha256sum
-        let password_hash = "ea32961dbd579ef5697c367f9267921ee07f14d77fb2d4fb9500d4221d615695";
-        assert_eq!(hash1.as_str(), password_hash);
+        let legacy = "ea32961dbd579ef5697c367f9267921ee07f14d77fb2d4fb9500d4221d615695";
+
+        assert!(is_legacy_hash(legacy));
+        assert_eq!(verify_password(&TEST_CONFIG, legacy, "1234"), Ok(PasswordVerification::ValidNeedsRehash));
+        assert_eq!(verify_password(&TEST_CONFIG, legacy, "12345"), Ok(PasswordVerification::Invalid));
     }
+
     #[test]
-    fn test_empty_salt() {
-        let hash = hash_password("", "password");
-        assert!(!hash.is_empty());
+    fn test_argon2_hash_is_not_legacy() {
+        let hash = hash_password(&TEST_CONFIG, "1234").unwrap();
+        assert!(!is_legacy_hash(&hash));
+        assert!(!is_legacy_hash("ea32961d"));
     }
 
     #[test]
-    fn test_hash_output_is_hex() {
-        let hash = hash_password("salt", "password");
-        
-        assert_eq!(hash.len(), 64);
-        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    fn test_invalid_config() {
+        let config = PasswordHashConfig { memory_kib: 1, ..TEST_CONFIG };
+        let result = hash_password(&config, "1234");
+        assert!(matches!(result, Err(PasswordHashError::InvalidConfig(_))));
     }
 
     #[test]
@@ -181,6 +366,17 @@ mod tests {
         assert_eq!(final_tokens.len(), 1000, "Expected 1000 unique tokens, got {}", final_tokens.len());
     }
 
+    #[test]
+    fn test_hash_token() {
+        let token = generate_session_token();
+        let hash = hash_token(&token);
+
+        assert_eq!(hash, hash_token(&token));
+        assert_ne!(hash, token);
+        assert_ne!(hash, hash_token(&generate_session_token()));
+        assert_eq!(hash.len(), 64);
+    }
+
     #[test]
     fn test_get_current_timestamp() {
         let timestamp1 = get_current_timestamp();
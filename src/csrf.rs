@@ -0,0 +1,281 @@
+//! Cross-site request forgery protection
+//!
+//! Every page is served with a CSRF token, and every state-changing request
+//! has to send it back, either in the `X-CSRF-Token` header (HTMX and
+//! `fetch`) or in a hidden `csrf_token` form field. Logged-in users get
+//! their session's token; visitors who aren't logged in get one in a
+//! `SameSite=Strict` cookie, so login and signup forms are covered too.
+
+use std::convert::Infallible;
+use std::ops::Deref;
+
+use rocket::data::{self, Data, FromData};
+use rocket::form::{self, DataField, Form, FromForm, Options, ValueField};
+use rocket::http::{SameSite, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use rocket_db_pools::Database;
+
+use crate::auth::AuthenticatedUser;
+use crate::cookies::CookieConfig;
+use crate::crypto::{constant_time_eq, generate_session_token};
+use crate::database::{NexoDB, get_session_csrf_token};
+
+/// Cookie holding the token of a visitor who isn't logged in
+pub const CSRF_COOKIE: &str = "csrf_token";
+/// Header HTMX and `fetch` requests carry the token in
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// Hidden field plain HTML forms carry the token in
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Whether a token looks like one we issued, and so is safe to put in a page
+fn is_well_formed(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The token this request is expected to carry: the session's for a
+/// logged-in user, otherwise the one in the CSRF cookie
+async fn expected_token(request: &Request<'_>) -> Option<String> {
+    if let Outcome::Success(user) = request.guard::<AuthenticatedUser>().await {
+        let db = NexoDB::fetch(request.rocket())?;
+        return get_session_csrf_token(db, &user.session_token)
+            .await
+            .inspect_err(|e| eprintln!("Failed to load CSRF token: {:?}", e))
+            .ok()
+            .flatten();
+    }
+
+    let token = request.cookies().get(&CookieConfig::of(request).name(CSRF_COOKIE))?.value().to_string();
+    is_well_formed(&token).then_some(token)
+}
+
+async fn verify(request: &Request<'_>, submitted: Option<&str>) -> bool {
+    match (expected_token(request).await, submitted) {
+        (Some(expected), Some(submitted)) => constant_time_eq(expected.as_bytes(), submitted.as_bytes()),
+        _ => false,
+    }
+}
+
+/// The token to put into the page a request is served
+///
+/// Starts a new cookie token for visitors who don't have one yet.
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(token) = expected_token(request).await {
+            return Outcome::Success(CsrfToken(token));
+        }
+
+        let token = generate_session_token();
+        let mut cookie = CookieConfig::of(request).cookie(CSRF_COOKIE, token.clone());
+        cookie.set_same_site(SameSite::Strict);
+        request.cookies().add(cookie);
+        Outcome::Success(CsrfToken(token))
+    }
+}
+
+/// A request that sent the right token in the `X-CSRF-Token` header
+///
+/// For state-changing routes without a form body; fails with 403 when the
+/// token is missing or wrong.
+pub struct CsrfVerified;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfVerified {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if verify(request, request.headers().get_one(CSRF_HEADER)).await {
+            Outcome::Success(CsrfVerified)
+        } else {
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+/// A form whose request sent the right token, in the header or in a
+/// `csrf_token` field
+///
+/// Used in place of `Form<T>`; fails with 403 when the token is missing or
+/// wrong.
+pub struct CsrfForm<T>(T);
+
+impl<T> Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// A form with nothing in it besides the token, e.g. a logout button
+pub struct TokenOnly;
+
+#[rocket::async_trait]
+impl<'r> FromForm<'r> for TokenOnly {
+    type Context = ();
+
+    fn init(_opts: Options) -> Self::Context {}
+
+    fn push_value(_ctxt: &mut Self::Context, _field: ValueField<'r>) {}
+
+    async fn push_data(_ctxt: &mut Self::Context, _field: DataField<'r, '_>) {}
+
+    fn finalize(_ctxt: Self::Context) -> form::Result<'r, Self> {
+        Ok(TokenOnly)
+    }
+}
+
+/// A form with the `csrf_token` field taken out
+struct WithToken<T> {
+    token: Option<String>,
+    form: T,
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromForm<'r> for WithToken<T> {
+    type Context = (Option<String>, T::Context);
+
+    fn init(opts: Options) -> Self::Context {
+        (None, T::init(opts))
+    }
+
+    fn push_value((token, ctxt): &mut Self::Context, field: ValueField<'r>) {
+        if field.name.source() == CSRF_FIELD {
+            *token = Some(field.value.to_string());
+        } else {
+            T::push_value(ctxt, field);
+        }
+    }
+
+    async fn push_data((_, ctxt): &mut Self::Context, field: DataField<'r, '_>) {
+        T::push_data(ctxt, field).await
+    }
+
+    fn push_error((_, ctxt): &mut Self::Context, error: form::Error<'r>) {
+        T::push_error(ctxt, error)
+    }
+
+    fn finalize((token, ctxt): Self::Context) -> form::Result<'r, Self> {
+        Ok(WithToken { token, form: T::finalize(ctxt)? })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromData<'r> for CsrfForm<T> {
+    type Error = form::Errors<'r>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let submitted = match Form::<WithToken<T>>::from_data(request, data).await {
+            data::Outcome::Success(form) => form.into_inner(),
+            data::Outcome::Error(e) => return data::Outcome::Error(e),
+            data::Outcome::Forward(f) => return data::Outcome::Forward(f),
+        };
+
+        let token = request.headers().get_one(CSRF_HEADER).or(submitted.token.as_deref());
+        if verify(request, token).await {
+            data::Outcome::Success(CsrfForm(submitted.form))
+        } else {
+            data::Outcome::Error((Status::Forbidden, form::Error::validation("invalid CSRF token").into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::blocking::Client;
+
+    #[derive(FromForm)]
+    struct NoteForm {
+        note: String,
+    }
+
+    #[get("/token")]
+    fn token(csrf: CsrfToken) -> String {
+        csrf.0
+    }
+
+    #[post("/form", data = "<form>")]
+    fn submit(form: CsrfForm<NoteForm>) -> String {
+        form.note.clone()
+    }
+
+    #[post("/action")]
+    fn action(_csrf: CsrfVerified) -> &'static str {
+        "done"
+    }
+
+    /// A client holding a cookie token, and that token
+    fn client() -> (Client, String) {
+        // No database is attached, so requests are never logged in
+        let rocket = rocket::build().mount("/", routes![token, submit, action]);
+        let client = Client::tracked(rocket).unwrap();
+        let token = client.get("/token").dispatch().into_string().unwrap();
+        (client, token)
+    }
+
+    #[test]
+    fn test_token_cookie_is_reused() {
+        let (client, token) = client();
+        assert!(is_well_formed(&token));
+        let cookie = client.cookies().get(CSRF_COOKIE).unwrap().clone();
+        assert_eq!(cookie.value(), token);
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+
+        assert_eq!(client.get("/token").dispatch().into_string().unwrap(), token);
+    }
+
+    #[test]
+    fn test_form_accepts_field_or_header() {
+        let (client, token) = client();
+        let response = client.post("/form")
+            .header(ContentType::Form)
+            .body(format!("note=hello&csrf_token={}", token))
+            .dispatch();
+        assert_eq!(response.into_string().as_deref(), Some("hello"));
+
+        let response = client.post("/form")
+            .header(ContentType::Form)
+            .header(Header::new(CSRF_HEADER, token))
+            .body("note=hello")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_missing_or_wrong_token_is_forbidden() {
+        let (client, token) = client();
+        let response = client.post("/form").header(ContentType::Form).body("note=hello").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client.post("/form")
+            .header(ContentType::Form)
+            .body(format!("note=hello&csrf_token={}", "0".repeat(64)))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        assert_eq!(client.post("/action").dispatch().status(), Status::Forbidden);
+        let response = client.post("/action").header(Header::new(CSRF_HEADER, token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_token_only_works_with_its_cookie() {
+        let (_, token) = client();
+        let (other, _) = client();
+        let response = other.post("/action").header(Header::new(CSRF_HEADER, token)).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
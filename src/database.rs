@@ -1,120 +1,526 @@
+use std::str::FromStr;
+
+use serde::Serialize;
 use sqlx::Row;
+use sqlx::error::ErrorKind;
 
 use rocket_db_pools::Database;
 use rocket_db_pools::*;
-use crate::crypto::{generate_session_token, get_current_timestamp};
-
-#[derive(Database)]
+use crate::crypto::{generate_session_token, get_current_timestamp, hash_token};
+
+pub mod invitations;
+pub mod jobs;
+pub mod login_throttle;
+pub mod maintenance;
+pub mod migrations;
+pub mod password_resets;
+pub mod roles;
+pub mod setup;
+pub mod totp;
+
+#[derive(Database, Clone)]
 #[database("nexo_db")]
 pub struct NexoDB(rocket_db_pools::sqlx::SqlitePool);
 
-pub async fn init_db(db: &NexoDB) -> Result<(), sqlx::Error> {
-    let sql = include_str!("../data/db.sql");
-    sqlx::query(sql).execute(&db.0).await?;
-    Ok(())
+/// SQLite's primary result codes for a database another connection holds
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+/// Why a database call failed
+///
+/// The underlying [`sqlx::Error`] is kept for the log; callers decide what
+/// to tell the user from the variant. A missing row is not an error: lookups
+/// return `Ok(None)`.
+#[derive(Debug)]
+pub enum DbError {
+    /// A write broke a UNIQUE, FOREIGN KEY, NOT NULL or CHECK constraint
+    Constraint(sqlx::Error),
+    /// Another connection holds the database and the busy timeout ran out,
+    /// or every pooled connection is in use
+    Busy(sqlx::Error),
+    /// The database can't be reached at all
+    Connection(sqlx::Error),
+    Other(sqlx::Error),
 }
 
-// Lazy initialization - call this when you first need the database
-pub async fn ensure_db_initialized(db: &NexoDB) -> Result<(), sqlx::Error> {
-    // Check if tables exist by trying to query them
-    let result = sqlx::query("SELECT name FROM sqlite_master WHERE type='table' AND name='users'")
-        .fetch_optional(&db.0)
-        .await?;
-    
-    if result.is_none() {
-        println!("Database not initialized, running init_db...");
-        init_db(db).await?;
-        println!("Database initialized successfully");
+impl DbError {
+    /// Whether trying again later could succeed
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, DbError::Busy(_) | DbError::Connection(_))
+    }
+
+    /// Whether a UNIQUE or PRIMARY KEY constraint rejected the write
+    pub fn is_unique_violation(&self) -> bool {
+        matches!(self, DbError::Constraint(sqlx::Error::Database(e)) if e.is_unique_violation())
     }
-    
-    Ok(())
 }
 
-pub async fn get_password_hash_from_username(db: &NexoDB, username: &str) -> Option<String>{
-    let sql = "SELECT name, psw_hash FROM users WHERE name = ?";
-    let result = sqlx::query(sql)
-        .bind(username.to_string())
-        .fetch_one(&db.0)
-        .await;
-    match result {
-        Ok(row) =>{
-            row.get("psw_hash")
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Constraint(e) => write!(f, "constraint violated: {}", e),
+            DbError::Busy(e) => write!(f, "database is busy: {}", e),
+            DbError::Connection(e) => write!(f, "database is unreachable: {}", e),
+            DbError::Other(e) => e.fmt(f),
         }
-        Err(e) => {
-            println!("Database error: {:?}", e);
-            None
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(db) => {
+                // Extended result codes keep the primary code in the low byte
+                let code = db.code().and_then(|code| code.parse::<i32>().ok()).map(|code| code & 0xff);
+                if matches!(code, Some(SQLITE_BUSY | SQLITE_LOCKED)) {
+                    return DbError::Busy(e);
+                }
+                match db.kind() {
+                    ErrorKind::UniqueViolation
+                    | ErrorKind::ForeignKeyViolation
+                    | ErrorKind::NotNullViolation
+                    | ErrorKind::CheckViolation => DbError::Constraint(e),
+                    _ => DbError::Other(e),
+                }
+            }
+            sqlx::Error::PoolTimedOut => DbError::Busy(e),
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolClosed | sqlx::Error::WorkerCrashed => {
+                DbError::Connection(e)
+            }
+            _ => DbError::Other(e),
         }
     }
 }
 
-/// Get user ID by username
-pub async fn get_user_id_by_username(db: &NexoDB, username: &str) -> Option<i32> {
-    let sql = "SELECT id FROM users WHERE name = ?";
+/// Open the SQLite database at `url` outside of Rocket, creating the file if
+/// it doesn't exist. Used by `nexo-admin`; the server gets its pool from
+/// `NexoDB::init()`.
+pub async fn connect(url: &str) -> Result<NexoDB, DbError> {
+    let options = sqlx::sqlite::SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    let pool = sqlx::SqlitePool::connect_with(options).await?;
+    Ok(NexoDB(pool))
+}
+
+/// Password hash of a user who may log in. Disabled users have none, so
+/// they get `None` like unknown names do.
+pub async fn get_password_hash_from_username(db: &NexoDB, username: &str) -> Result<Option<String>, DbError> {
+    let sql = "SELECT name, psw_hash FROM users WHERE name = ? AND disabled_at IS NULL";
+    let row = sqlx::query(sql)
+        .bind(username)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| row.get("psw_hash")))
+}
+
+/// Replace the stored password hash for a user
+pub async fn update_password_hash(db: &NexoDB, username: &str, psw_hash: &str) -> Result<u64, DbError> {
+    let sql = "UPDATE users SET psw_hash = ? WHERE name = ?";
     let result = sqlx::query(sql)
-        .bind(username.to_string())
-        .fetch_one(&db.0)
-        .await;
-    match result {
-        Ok(row) => Some(row.get("id")),
-        Err(_) => None,
+        .bind(psw_hash)
+        .bind(username)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// A field of a new user that is already used by an existing one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserConflict {
+    Name,
+    Email,
+    Cpf,
+}
+
+/// Find the first of name, email and CPF that already belongs to a user
+pub async fn find_user_conflict(
+    db: &NexoDB,
+    name: &str,
+    email: Option<&str>,
+    cpf: Option<&str>,
+) -> Result<Option<UserConflict>, DbError> {
+    let sql = "SELECT name = ? AS name_taken, email = ? AS email_taken, cpf = ? AS cpf_taken
+               FROM users WHERE name = ? OR email = ? OR cpf = ?";
+    let rows = sqlx::query(sql)
+        .bind(name)
+        .bind(email)
+        .bind(cpf)
+        .bind(name)
+        .bind(email)
+        .bind(cpf)
+        .fetch_all(&db.0)
+        .await?;
+
+    let taken = |column: &str| rows.iter().any(|row| row.get::<Option<bool>, _>(column) == Some(true));
+    Ok(if taken("name_taken") {
+        Some(UserConflict::Name)
+    } else if taken("email_taken") {
+        Some(UserConflict::Email)
+    } else if taken("cpf_taken") {
+        Some(UserConflict::Cpf)
+    } else {
+        None
+    })
+}
+
+/// Insert a new user with the default role and return its ID
+pub async fn create_user(
+    db: &NexoDB,
+    name: &str,
+    psw_hash: &str,
+    email: Option<&str>,
+    cpf: Option<&str>,
+) -> Result<i32, DbError> {
+    let mut tx = db.0.begin().await?;
+    let sql = "INSERT INTO users (name, psw_hash, email, cpf) VALUES (?, ?, ?, ?)";
+    let result = sqlx::query(sql)
+        .bind(name)
+        .bind(psw_hash)
+        .bind(email)
+        .bind(cpf)
+        .execute(&mut *tx)
+        .await?;
+    let user_id = result.last_insert_rowid() as i32;
+
+    roles::grant_role(&mut tx, user_id, roles::DEFAULT_ROLE).await?;
+    tx.commit().await?;
+    Ok(user_id)
+}
+
+/// Disable or re-enable a user. Disabling also ends all their sessions.
+/// Returns `false` if there is no such user.
+pub async fn set_user_disabled(db: &NexoDB, user_id: i32, disabled: bool) -> Result<bool, DbError> {
+    let mut tx = db.0.begin().await?;
+    let result = sqlx::query("UPDATE users SET disabled_at = ? WHERE id = ?")
+        .bind(disabled.then(get_current_timestamp))
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    if disabled {
+        sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
     }
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Set a new password hash for a user and end all their sessions
+pub async fn set_user_password(db: &NexoDB, user_id: i32, psw_hash: &str) -> Result<bool, DbError> {
+    let mut tx = db.0.begin().await?;
+    let result = sqlx::query("UPDATE users SET psw_hash = ? WHERE id = ?")
+        .bind(psw_hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Get user ID by username
+pub async fn get_user_id_by_username(db: &NexoDB, username: &str) -> Result<Option<i32>, DbError> {
+    let sql = "SELECT id FROM users WHERE name = ?";
+    let row = sqlx::query(sql)
+        .bind(username)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| row.get("id")))
+}
+
+/// Where a session was started from, as reported by the client
+#[derive(Debug, Clone, Default)]
+pub struct SessionClient {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
 }
 
+/// A session as shown on the session management page
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: i64,
+    pub name: Option<String>,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    pub expires_at: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+/// `last_seen_at` and the idle expiry are only pushed forward once they are
+/// this many seconds stale, so activity doesn't turn every request into a
+/// write
+const LAST_SEEN_RESOLUTION: i64 = 60;
+
+/// Matches a session by its current token, or by the token it was rotated
+/// away from while that is still in its grace window. Binds the token hash
+/// twice, then the current time. `IS` keeps the result false rather than
+/// NULL for sessions that were never rotated, so it can be negated.
+const MATCHES_TOKEN: &str =
+    "(token_hash = ? OR (previous_token_hash IS ? AND previous_valid_until > ?))";
+
 /// Create a new session for a user
-pub async fn create_session(db: &NexoDB, user_id: i32, expires_in_seconds: i64) -> Option<String> {
+///
+/// The session expires after `idle_seconds` without activity and after
+/// `absolute_seconds` no matter what. Only the token's hash is stored; the
+/// token itself is returned for the cookie and can't be recovered from the
+/// database.
+/// The session also gets its own CSRF token, see [`get_session_csrf_token`].
+pub async fn create_session(
+    db: &NexoDB,
+    user_id: i32,
+    idle_seconds: i64,
+    absolute_seconds: i64,
+    client: &SessionClient,
+) -> Result<String, DbError> {
     let token = generate_session_token();
-    let expires_at = get_current_timestamp() + expires_in_seconds;
+    let now = get_current_timestamp();
+    let absolute_expires_at = now + absolute_seconds;
+    let expires_at = (now + idle_seconds).min(absolute_expires_at);
     
-    let sql = "INSERT INTO sessions (user_id, token, expires_at) VALUES (?, ?, ?)";
-    let result = sqlx::query(sql)
+    let sql = "INSERT INTO sessions (user_id, token_hash, expires_at, absolute_expires_at, created_at,
+                                     last_seen_at, rotated_at, user_agent, ip, csrf_token)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    sqlx::query(sql)
         .bind(user_id)
-        .bind(&token)
+        .bind(hash_token(&token))
         .bind(expires_at)
+        .bind(absolute_expires_at)
+        .bind(now)
+        .bind(now)
+        .bind(now)
+        .bind(&client.user_agent)
+        .bind(&client.ip)
+        .bind(generate_session_token())
         .execute(&db.0)
-        .await;
-    
-    match result {
-        Ok(_) => Some(token),
-        Err(e) => {
-            println!("Failed to create session: {:?}", e);
-            None
-        }
-    }
+        .await?;
+
+    Ok(token)
 }
 
-/// Validate a session token and return user ID if valid
-pub async fn validate_session(db: &NexoDB, token: &str) -> Option<i32> {
+/// Validate a session token and return its user's ID, or `None` if the
+/// session is unknown, expired or its user is disabled
+pub async fn validate_session(db: &NexoDB, token: &str) -> Result<Option<i32>, DbError> {
     let current_time = get_current_timestamp();
+    let token_hash = hash_token(token);
     
-    let sql = "SELECT user_id FROM sessions WHERE token = ? AND expires_at > ?";
-    let result = sqlx::query(sql)
-        .bind(token)
+    let sql = format!(
+        "SELECT user_id FROM sessions JOIN users ON users.id = sessions.user_id
+         WHERE {} AND expires_at > ? AND users.disabled_at IS NULL",
+        MATCHES_TOKEN
+    );
+    let row = sqlx::query(&sql)
+        .bind(&token_hash)
+        .bind(&token_hash)
         .bind(current_time)
-        .fetch_one(&db.0)
-        .await;
-    
-    match result {
-        Ok(row) => Some(row.get("user_id")),
-        Err(_) => None,
-    }
+        .bind(current_time)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| row.get("user_id")))
+}
+
+/// The CSRF token of the session a token belongs to
+///
+/// Unlike the session token it is stored as is, since it has to be put into
+/// pages, and it doesn't change when the session token is rotated.
+pub async fn get_session_csrf_token(db: &NexoDB, token: &str) -> Result<Option<String>, DbError> {
+    let current_time = get_current_timestamp();
+    let token_hash = hash_token(token);
+
+    let sql = format!("SELECT csrf_token FROM sessions WHERE {} AND expires_at > ?", MATCHES_TOKEN);
+    let row = sqlx::query(&sql)
+        .bind(&token_hash)
+        .bind(&token_hash)
+        .bind(current_time)
+        .bind(current_time)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.and_then(|row| row.get("csrf_token")))
+}
+
+/// Record activity on a session and rotate its token when it is due
+///
+/// Pushes the idle expiry `idle_seconds` past now, capped at the absolute
+/// expiry. If the token is older than `rotate_after_seconds` it is replaced
+/// and the new one returned; the old one keeps working for `grace_seconds`
+/// so requests sent before the browser sees the new cookie still succeed.
+/// A token that was already rotated away is accepted without renewing.
+pub async fn renew_session(
+    db: &NexoDB,
+    token: &str,
+    idle_seconds: i64,
+    rotate_after_seconds: i64,
+    grace_seconds: i64,
+) -> Result<Option<String>, DbError> {
+    let now = get_current_timestamp();
+    let token_hash = hash_token(token);
+    let new_token = generate_session_token();
+    let new_hash = hash_token(&new_token);
+
+    // A single statement, so concurrent requests on one session never read
+    // and then fail to upgrade to the write lock. SET expressions see the
+    // row as it was: a rotation is due when `rotated_at <= ?1 - ?2`, and
+    // otherwise only a stale `last_seen_at` is worth a write.
+    let rows = sqlx::query(
+        "UPDATE sessions SET
+             token_hash = CASE WHEN rotated_at <= ?1 - ?2 THEN ?3 ELSE token_hash END,
+             previous_token_hash = CASE WHEN rotated_at <= ?1 - ?2 THEN token_hash ELSE previous_token_hash END,
+             previous_valid_until = CASE WHEN rotated_at <= ?1 - ?2 THEN ?1 + ?4 ELSE previous_valid_until END,
+             rotated_at = CASE WHEN rotated_at <= ?1 - ?2 THEN ?1 ELSE rotated_at END,
+             last_seen_at = ?1,
+             expires_at = MIN(?1 + ?5, absolute_expires_at)
+         WHERE token_hash = ?6 AND expires_at > ?1
+           AND (rotated_at <= ?1 - ?2 OR last_seen_at <= ?1 - ?7)
+         RETURNING token_hash = ?3 AS rotated",
+    )
+    .bind(now)
+    .bind(rotate_after_seconds)
+    .bind(&new_hash)
+    .bind(grace_seconds)
+    .bind(idle_seconds)
+    .bind(&token_hash)
+    .bind(LAST_SEEN_RESOLUTION)
+    .fetch_all(&db.0)
+    .await?;
+
+    let rotated = rows.first().is_some_and(|row| row.get::<bool, _>("rotated"));
+    Ok(rotated.then_some(new_token))
+}
+
+/// A user's unexpired sessions, most recently used first. `current_token`
+/// is the token of the session asking, which gets marked as current.
+pub async fn list_user_sessions(db: &NexoDB, user_id: i32, current_token: &str) -> Result<Vec<SessionInfo>, DbError> {
+    let sql = "SELECT id, token_hash, previous_token_hash, name, created_at, last_seen_at, expires_at, user_agent, ip
+               FROM sessions WHERE user_id = ? AND expires_at > ? ORDER BY last_seen_at DESC, id DESC";
+    let rows = sqlx::query(sql)
+        .bind(user_id)
+        .bind(get_current_timestamp())
+        .fetch_all(&db.0)
+        .await?;
+
+    let current_hash = hash_token(current_token);
+    Ok(rows.iter().map(|row| SessionInfo {
+        id: row.get("id"),
+        name: row.get("name"),
+        created_at: row.get("created_at"),
+        last_seen_at: row.get("last_seen_at"),
+        expires_at: row.get("expires_at"),
+        user_agent: row.get("user_agent"),
+        ip: row.get("ip"),
+        current: row.get::<String, _>("token_hash") == current_hash
+            || row.get::<Option<String>, _>("previous_token_hash").as_ref() == Some(&current_hash),
+    }).collect())
+}
+
+/// Set or clear the name of one of a user's sessions. Returns `false` if
+/// the user has no such session.
+pub async fn rename_user_session(db: &NexoDB, user_id: i32, session_id: i64, name: Option<&str>) -> Result<bool, DbError> {
+    let result = sqlx::query("UPDATE sessions SET name = ? WHERE id = ? AND user_id = ?")
+        .bind(name)
+        .bind(session_id)
+        .bind(user_id)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Delete one of a user's sessions by ID. Returns `false` if the user has
+/// no such session.
+pub async fn delete_user_session(db: &NexoDB, user_id: i32, session_id: i64) -> Result<bool, DbError> {
+    let result = sqlx::query("DELETE FROM sessions WHERE id = ? AND user_id = ?")
+        .bind(session_id)
+        .bind(user_id)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Delete all of a user's sessions except the one with `keep_token`
+pub async fn delete_other_sessions(db: &NexoDB, user_id: i32, keep_token: &str) -> Result<u64, DbError> {
+    let keep_hash = hash_token(keep_token);
+    let sql = format!("DELETE FROM sessions WHERE user_id = ? AND NOT {}", MATCHES_TOKEN);
+    let result = sqlx::query(&sql)
+        .bind(user_id)
+        .bind(&keep_hash)
+        .bind(&keep_hash)
+        .bind(get_current_timestamp())
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Delete every session of a user
+pub async fn delete_user_sessions(db: &NexoDB, user_id: i32) -> Result<u64, DbError> {
+    let result = sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Get the ID and name of the user with an email address
+pub async fn get_user_by_email(db: &NexoDB, email: &str) -> Result<Option<(i32, String)>, DbError> {
+    let sql = "SELECT id, name FROM users WHERE email = ?";
+    let row = sqlx::query(sql)
+        .bind(email)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| (row.get("id"), row.get("name"))))
 }
 
 /// Get username by user ID
-pub async fn get_username_by_id(db: &NexoDB, user_id: i32) -> Option<String> {
+pub async fn get_username_by_id(db: &NexoDB, user_id: i32) -> Result<Option<String>, DbError> {
     let sql = "SELECT name FROM users WHERE id = ?";
-    let result = sqlx::query(sql)
+    let row = sqlx::query(sql)
         .bind(user_id)
-        .fetch_one(&db.0)
-        .await;
-    
-    match result {
-        Ok(row) => Some(row.get("name")),
-        Err(_) => None,
-    }
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| row.get("name")))
+}
+
+/// The locale tag a user picked, or `None` to follow their browser
+pub async fn get_user_locale(db: &NexoDB, user_id: i32) -> Result<Option<String>, DbError> {
+    let row = sqlx::query("SELECT locale FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.and_then(|row| row.get("locale")))
+}
+
+/// Set or clear a user's locale
+pub async fn set_user_locale(db: &NexoDB, user_id: i32, locale: Option<&str>) -> Result<(), DbError> {
+    sqlx::query("UPDATE users SET locale = ? WHERE id = ?")
+        .bind(locale)
+        .bind(user_id)
+        .execute(&db.0)
+        .await?;
+
+    Ok(())
 }
 
 /// Clean up expired sessions
-pub async fn cleanup_expired_sessions(db: &NexoDB) -> Result<u64, sqlx::Error> {
+pub async fn cleanup_expired_sessions(db: &NexoDB) -> Result<u64, DbError> {
     let current_time = get_current_timestamp();
     
     let sql = "DELETE FROM sessions WHERE expires_at <= ?";
@@ -127,10 +533,13 @@ pub async fn cleanup_expired_sessions(db: &NexoDB) -> Result<u64, sqlx::Error> {
 }
 
 /// Delete a specific session by token
-pub async fn delete_session(db: &NexoDB, token: &str) -> Result<u64, sqlx::Error> {
-    let sql = "DELETE FROM sessions WHERE token = ?";
-    let result = sqlx::query(sql)
-        .bind(token)
+pub async fn delete_session(db: &NexoDB, token: &str) -> Result<u64, DbError> {
+    let token_hash = hash_token(token);
+    let sql = format!("DELETE FROM sessions WHERE {}", MATCHES_TOKEN);
+    let result = sqlx::query(&sql)
+        .bind(&token_hash)
+        .bind(&token_hash)
+        .bind(get_current_timestamp())
         .execute(&db.0)
         .await?;
     
@@ -138,85 +547,75 @@ pub async fn delete_session(db: &NexoDB, token: &str) -> Result<u64, sqlx::Error
 }
 
 
+/// Create an empty SQLite file, replacing any leftover from a previous run,
+/// and connect to it without applying migrations.
+#[cfg(test)]
+pub async fn open_empty_test_db(db_path: &str) -> NexoDB {
+    if std::path::Path::new(db_path).exists() {
+        std::fs::remove_file(db_path).expect("Failed to delete existing database file");
+    }
+    std::fs::File::create(db_path).expect("Failed to create database file");
+
+    let database_url = format!("sqlite://{}", db_path);
+    let pool = sqlx::SqlitePool::connect(&database_url)
+        .await
+        .expect("Failed to create database pool");
+    NexoDB(pool)
+}
+
+/// Create a fresh SQLite file with every migration applied.
+#[cfg(test)]
+pub async fn open_test_db(db_path: &str) -> NexoDB {
+    let db = open_empty_test_db(db_path).await;
+    migrations::run_migrations(&db).await.expect("Failed to migrate database");
+    db
+}
+
+/// Add a user with a placeholder password hash. Migrated databases start
+/// without users, so tests create the ones they need.
+#[cfg(test)]
+pub async fn create_test_user(db: &NexoDB, name: &str) -> i32 {
+    create_user(db, name, "hash", None, None).await.expect("Failed to create test user")
+}
+
+/// Close a database opened by [`open_test_db`] and delete its file.
+#[cfg(test)]
+pub async fn close_test_db(db: NexoDB, db_path: &str) {
+    db.0.close().await;
+    if std::path::Path::new(db_path).exists() {
+        std::fs::remove_file(db_path).expect("Failed to delete test database file");
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
-    use std::path::Path;
+    use crate::crypto::{hash_password, verify_password, PasswordHashConfig, PasswordVerification};
 
     #[test]
     fn test_init_db() {
         rocket::async_test(async {
             let db_path = "test_db.sqlite";
+            let db = open_test_db(db_path).await;
 
-            // Delete the file if it exists
-            if Path::new(db_path).exists() {
-                std::fs::remove_file(db_path).expect("Failed to delete existing database file");
-                println!("Deleted existing database file");
-            }
-
-            // Explicitly create an empty file
-            match std::fs::File::create(db_path) {
-                Ok(_) => println!("Created empty test_db.sqlite file"),
-                Err(e) => println!("Failed to create test_db.sqlite file: {}", e),
-            }
-
-            // Print file metadata
-            match std::fs::metadata(db_path) {
-                Ok(meta) => println!("test_db.sqlite metadata: permissions: {:?}, len: {}", meta.permissions(), meta.len()),
-                Err(e) => println!("Failed to get metadata: {}", e),
-            }
-
-            // Create a test database connection
-            let database_url = format!("sqlite://{}", db_path);
-            let pool = sqlx::SqlitePool::connect(&database_url)
-                .await
-                .expect("Failed to create database pool");
-            let db = NexoDB(pool);
-
-            // Initialize the database
-            println!("Initializing database...");
-            init_db(&db).await.expect("Failed to initialize database");
-            println!("Database initialized successfully");
-
-            // Test if it was correctly initialized by running a query
-            println!("Testing database initialization...");
+            // A fresh database has its tables but no users; the first one
+            // comes from the setup page
             let result = sqlx::query("SELECT COUNT(*) as count FROM users")
                 .fetch_one(&db.0)
                 .await
                 .expect("Failed to query users table");
-            
             let user_count: i64 = result.get("count");
-            println!("Found {} users in the database", user_count);
-            
-            // Test the specific user that should be inserted
-            let username = "thiago";
-            let password_hash = get_password_hash_from_username(&db, username).await;
-            match password_hash {
-                Some(hash) => println!("Found user '{}' with hash: {}", username, hash),
-                None => println!("User '{}' not found", username),
-            }
+            assert_eq!(user_count, 0);
+            assert_eq!(get_password_hash_from_username(&db, "thiago").await.unwrap(), None);
 
-            // Test sessions table
             let sessions_result = sqlx::query("SELECT COUNT(*) as count FROM sessions")
                 .fetch_one(&db.0)
                 .await
                 .expect("Failed to query sessions table");
-            
             let sessions_count: i64 = sessions_result.get("count");
-            println!("Found {} sessions in the database", sessions_count);
-
-            // Close connection
-            db.0.close().await;
-            println!("Database connection closed");
+            assert_eq!(sessions_count, 0);
 
-            // Delete the file
-            if Path::new(db_path).exists() {
-                fs::remove_file(db_path).expect("Failed to delete test database file");
-                println!("Test database file deleted");
-            }
-            
-            println!("Test completed successfully!");
+            close_test_db(db, db_path).await;
         });
     }
 
@@ -224,42 +623,25 @@ mod tests {
     fn test_session_management() {
         rocket::async_test(async {
             let db_path = "test_session_db.sqlite";
-
-            // Clean up existing file
-            if Path::new(db_path).exists() {
-                std::fs::remove_file(db_path).expect("Failed to delete existing database file");
-            }
-
-            // Create an empty file first
-            std::fs::File::create(db_path).expect("Failed to create database file");
-
-            // Create database
-            let database_url = format!("sqlite://{}", db_path);
-            let pool = sqlx::SqlitePool::connect(&database_url)
-                .await
-                .expect("Failed to create database pool");
-            let db = NexoDB(pool);
-
-            // Initialize database
-            init_db(&db).await.expect("Failed to initialize database");
+            let db = open_test_db(db_path).await;
 
             // Test user lookup
-            let user_id = get_user_id_by_username(&db, "thiago").await;
-            assert!(user_id.is_some());
-            let user_id = user_id.unwrap();
+            let created = create_test_user(&db, "thiago").await;
+            let user_id = get_user_id_by_username(&db, "thiago").await.unwrap().unwrap();
+            assert_eq!(user_id, created);
 
             // Test session creation
-            let session_token = create_session(&db, user_id, 3600).await; // 1 hour
-            assert!(session_token.is_some());
+            let session_token = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await; // 1 hour
+            assert!(session_token.is_ok());
             let session_token = session_token.unwrap();
 
             // Test session validation
             let validated_user_id = validate_session(&db, &session_token).await;
-            assert_eq!(validated_user_id, Some(user_id));
+            assert_eq!(validated_user_id.unwrap(), Some(user_id));
 
             // Test username lookup
             let username = get_username_by_id(&db, user_id).await;
-            assert_eq!(username, Some("thiago".to_string()));
+            assert_eq!(username.unwrap(), Some("thiago".to_string()));
 
             // Test session deletion
             let deleted_count = delete_session(&db, &session_token).await;
@@ -267,21 +649,320 @@ mod tests {
 
             // Test session validation after deletion
             let validated_user_id = validate_session(&db, &session_token).await;
-            assert!(validated_user_id.is_none());
+            assert_eq!(validated_user_id.unwrap(), None);
 
             // Test expired session
-            let expired_token = create_session(&db, user_id, -1).await; // Expired immediately
-            assert!(expired_token.is_some());
+            let expired_token = create_session(&db, user_id, -1, 3600, &SessionClient::default()).await; // Expired immediately
+            assert!(expired_token.is_ok());
             let expired_token = expired_token.unwrap();
 
             let validated_user_id = validate_session(&db, &expired_token).await;
-            assert!(validated_user_id.is_none());
+            assert_eq!(validated_user_id.unwrap(), None);
 
-            // Clean up
-            db.0.close().await;
-            if Path::new(db_path).exists() {
-                fs::remove_file(db_path).expect("Failed to delete test database file");
-            }
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_session_tokens_stored_hashed() {
+        rocket::async_test(async {
+            let db_path = "test_session_hash_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let token = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+
+            let stored: String = sqlx::query("SELECT token_hash FROM sessions WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_one(&db.0)
+                .await
+                .unwrap()
+                .get("token_hash");
+            assert_eq!(stored, hash_token(&token));
+            assert_ne!(stored, token);
+
+            // What's in the database is not itself a usable token
+            assert_eq!(validate_session(&db, &stored).await.unwrap(), None);
+            assert_eq!(validate_session(&db, &token).await.unwrap(), Some(user_id));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_user_session_management() {
+        rocket::async_test(async {
+            let db_path = "test_user_sessions_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let other_user = create_user(&db, "other", "hash", None, None).await.unwrap();
+
+            let client = SessionClient { user_agent: Some("curl/8.0".to_string()), ip: Some("10.0.0.1".to_string()) };
+            let current = create_session(&db, user_id, 3600, 3600, &client).await.unwrap();
+            let laptop = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+            create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+            create_session(&db, user_id, -1, 3600, &SessionClient::default()).await.unwrap();
+            let foreign = create_session(&db, other_user, 3600, 3600, &SessionClient::default()).await.unwrap();
+
+            let sessions = list_user_sessions(&db, user_id, &current).await.unwrap();
+            assert_eq!(sessions.len(), 3, "expired and other users' sessions are not listed");
+            let mine = sessions.iter().find(|s| s.current).unwrap();
+            assert_eq!(sessions.iter().filter(|s| s.current).count(), 1);
+            assert_eq!(mine.user_agent.as_deref(), Some("curl/8.0"));
+            assert_eq!(mine.ip.as_deref(), Some("10.0.0.1"));
+
+            let laptop_id = list_user_sessions(&db, user_id, &laptop).await.unwrap()
+                .into_iter().find(|s| s.current).unwrap().id;
+            assert!(rename_user_session(&db, user_id, laptop_id, Some("laptop")).await.unwrap());
+            assert!(!rename_user_session(&db, other_user, laptop_id, Some("mine now")).await.unwrap());
+            let sessions = list_user_sessions(&db, user_id, &current).await.unwrap();
+            assert_eq!(sessions.iter().find(|s| s.id == laptop_id).unwrap().name.as_deref(), Some("laptop"));
+
+            // Users can only revoke their own sessions
+            assert!(!delete_user_session(&db, other_user, laptop_id).await.unwrap());
+            assert!(delete_user_session(&db, user_id, laptop_id).await.unwrap());
+            assert_eq!(validate_session(&db, &laptop).await.unwrap(), None);
+
+            assert_eq!(delete_other_sessions(&db, user_id, &current).await.unwrap(), 2);
+            assert_eq!(validate_session(&db, &current).await.unwrap(), Some(user_id));
+            assert_eq!(validate_session(&db, &foreign).await.unwrap(), Some(other_user));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    async fn session_expiry(db: &NexoDB, session_id: i64) -> (i64, i64) {
+        let row = sqlx::query("SELECT expires_at, absolute_expires_at FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        (row.get("expires_at"), row.get("absolute_expires_at"))
+    }
+
+    #[test]
+    fn test_session_sliding_expiration() {
+        rocket::async_test(async {
+            let db_path = "test_session_sliding_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let token = create_session(&db, user_id, 600, 3600, &SessionClient::default()).await.unwrap();
+            let id = list_user_sessions(&db, user_id, &token).await.unwrap()[0].id;
+            let (expires_at, absolute) = session_expiry(&db, id).await;
+            assert_eq!(absolute - expires_at, 3000);
+
+            // Pretend the session was last used a while ago
+            sqlx::query("UPDATE sessions SET last_seen_at = last_seen_at - 300, expires_at = expires_at - 300 WHERE id = ?")
+                .bind(id)
+                .execute(&db.0)
+                .await
+                .unwrap();
+            assert_eq!(renew_session(&db, &token, 600, 3600, 60).await.unwrap(), None);
+            assert_eq!(session_expiry(&db, id).await.0, expires_at);
+
+            // The idle expiry never passes the absolute one
+            sqlx::query("UPDATE sessions SET last_seen_at = 0 WHERE id = ?").bind(id).execute(&db.0).await.unwrap();
+            renew_session(&db, &token, 100_000, 3600, 60).await.unwrap();
+            assert_eq!(session_expiry(&db, id).await, (absolute, absolute));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_session_token_rotation() {
+        rocket::async_test(async {
+            let db_path = "test_session_rotation_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let token = create_session(&db, user_id, 600, 3600, &SessionClient::default()).await.unwrap();
+
+            assert_eq!(renew_session(&db, &token, 600, 900, 60).await.unwrap(), None, "not due yet");
+
+            let csrf_token = get_session_csrf_token(&db, &token).await.unwrap().expect("session has a CSRF token");
+            let rotated = renew_session(&db, &token, 600, 0, 60).await.unwrap().expect("rotation due");
+            assert_ne!(rotated, token);
+            assert_eq!(validate_session(&db, &rotated).await.unwrap(), Some(user_id));
+            // Pages opened before the rotation keep a valid CSRF token
+            assert_eq!(get_session_csrf_token(&db, &rotated).await.unwrap(), Some(csrf_token));
+            // The old token still works during the grace window, but can't
+            // rotate the session again
+            assert_eq!(validate_session(&db, &token).await.unwrap(), Some(user_id));
+            assert_eq!(renew_session(&db, &token, 600, 0, 60).await.unwrap(), None);
+            assert!(list_user_sessions(&db, user_id, &token).await.unwrap()[0].current);
+
+            // Without a grace window the old token stops working at once
+            let newest = renew_session(&db, &rotated, 600, 0, 0).await.unwrap().expect("rotation due");
+            assert_eq!(validate_session(&db, &rotated).await.unwrap(), None);
+            assert_eq!(validate_session(&db, &newest).await.unwrap(), Some(user_id));
+
+            // Logging out with the new token ends the session for both
+            assert_eq!(delete_session(&db, &newest).await.unwrap(), 1);
+            assert_eq!(validate_session(&db, &token).await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_create_user_and_conflicts() {
+        rocket::async_test(async {
+            let db_path = "test_create_user_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            let conflict = find_user_conflict(&db, "maria", Some("maria@example.com"), Some("52998224725")).await;
+            assert_eq!(conflict.unwrap(), None);
+
+            let user_id = create_user(&db, "maria", "hash", Some("maria@example.com"), Some("52998224725"))
+                .await
+                .expect("Failed to create user");
+            assert_eq!(get_user_id_by_username(&db, "maria").await.unwrap(), Some(user_id));
+
+            let conflict = |name: &'static str, email: Option<&'static str>, cpf: Option<&'static str>| {
+                let db = &db;
+                async move { find_user_conflict(db, name, email, cpf).await.unwrap() }
+            };
+            assert_eq!(conflict("maria", None, None).await, Some(UserConflict::Name));
+            assert_eq!(conflict("ana", Some("maria@example.com"), None).await, Some(UserConflict::Email));
+            assert_eq!(conflict("ana", Some("ana@example.com"), Some("52998224725")).await, Some(UserConflict::Cpf));
+            assert_eq!(conflict("ana", None, None).await, None);
+
+            // The unique indexes back up the pre-check, while NULLs never clash
+            assert!(create_user(&db, "ana", "hash", Some("maria@example.com"), None).await.unwrap_err().is_unique_violation());
+            assert!(create_user(&db, "ana", "hash", None, None).await.is_ok());
+            assert!(create_user(&db, "bia", "hash", None, None).await.is_ok());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_db_errors_are_classified() {
+        rocket::async_test(async {
+            let db_path = "test_db_errors_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "maria").await;
+
+            assert_eq!(get_user_id_by_username(&db, "nobody").await.unwrap(), None);
+            assert!(matches!(create_user(&db, "maria", "hash", None, None).await, Err(DbError::Constraint(_))));
+            let null = sqlx::query("INSERT INTO users (name, psw_hash) VALUES ('ana', NULL)").execute(&db.0).await;
+            assert!(matches!(null.map_err(DbError::from), Err(DbError::Constraint(_))));
+            let typo = sqlx::query("SELECT nope FROM users").execute(&db.0).await;
+            assert!(matches!(typo.map_err(DbError::from), Err(DbError::Other(_))));
+
+            // A second pool that gives up at once while the first holds the
+            // write lock, like a request arriving during a backup
+            let options = sqlx::sqlite::SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path))
+                .unwrap()
+                .busy_timeout(std::time::Duration::ZERO);
+            let other = NexoDB(sqlx::SqlitePool::connect_with(options).await.unwrap());
+            let mut lock = db.0.acquire().await.unwrap();
+            sqlx::query("BEGIN EXCLUSIVE").execute(&mut *lock).await.unwrap();
+
+            let busy = set_user_locale(&other, user_id, Some("pt-BR")).await.unwrap_err();
+            assert!(matches!(busy, DbError::Busy(_)), "{:?}", busy);
+            assert!(busy.is_unavailable());
+
+            sqlx::query("ROLLBACK").execute(&mut *lock).await.unwrap();
+            drop(lock);
+            other.0.close().await;
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_legacy_hash_upgraded_on_login() {
+        rocket::async_test(async {
+            let db_path = "test_legacy_hash_db.sqlite";
+            let db = open_test_db(db_path).await;
+            // A row as it was written before Argon2: SHA-256 of "salt" + "1234"
+            let legacy = "ea32961dbd579ef5697c367f9267921ee07f14d77fb2d4fb9500d4221d615695";
+            sqlx::query("INSERT INTO users (name, psw_hash) VALUES ('legacy', ?)")
+                .bind(legacy)
+                .execute(&db.0)
+                .await
+                .expect("Failed to insert legacy user");
+
+            let config = PasswordHashConfig { memory_kib: 1024, iterations: 1, parallelism: 1 };
+
+            // A wrong password is rejected and leaves the row alone
+            let stored = get_password_hash_from_username(&db, "legacy").await.unwrap().unwrap();
+            assert_eq!(verify_password(&config, &stored, "wrong"), Ok(PasswordVerification::Invalid));
+            assert_eq!(get_password_hash_from_username(&db, "legacy").await.unwrap().as_deref(), Some(legacy));
+
+            // The right password verifies and asks for an upgrade
+            assert_eq!(verify_password(&config, &stored, "1234"), Ok(PasswordVerification::ValidNeedsRehash));
+            let new_hash = hash_password(&config, "1234").unwrap();
+            assert_eq!(update_password_hash(&db, "legacy", &new_hash).await.unwrap(), 1);
+
+            // The row now holds an Argon2id hash that verifies without further upgrades
+            let upgraded = get_password_hash_from_username(&db, "legacy").await.unwrap().unwrap();
+            assert!(upgraded.starts_with("$argon2id$"), "hash was not upgraded: {}", upgraded);
+            assert_eq!(verify_password(&config, &upgraded, "1234"), Ok(PasswordVerification::Valid));
+            assert_eq!(verify_password(&config, &upgraded, "wrong"), Ok(PasswordVerification::Invalid));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_disabled_user_is_locked_out() {
+        rocket::async_test(async {
+            let db_path = "test_disabled_user_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_user(&db, "maria", "hash", None, None).await.unwrap();
+            let token = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+
+            assert!(set_user_disabled(&db, user_id, true).await.unwrap());
+            assert_eq!(validate_session(&db, &token).await.unwrap(), None);
+            assert_eq!(get_password_hash_from_username(&db, "maria").await.unwrap(), None);
+
+            // Sessions made after disabling (e.g. a second factor entered late) don't work either
+            let late = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+            assert_eq!(validate_session(&db, &late).await.unwrap(), None);
+
+            assert!(set_user_disabled(&db, user_id, false).await.unwrap());
+            assert_eq!(get_password_hash_from_username(&db, "maria").await.unwrap().as_deref(), Some("hash"));
+            assert_eq!(validate_session(&db, &late).await.unwrap(), Some(user_id));
+            assert!(!set_user_disabled(&db, 999, true).await.unwrap());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_set_user_password_ends_sessions() {
+        rocket::async_test(async {
+            let db_path = "test_set_user_password_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let token = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+
+            assert!(set_user_password(&db, user_id, "new-hash").await.unwrap());
+            assert_eq!(get_password_hash_from_username(&db, "thiago").await.unwrap().as_deref(), Some("new-hash"));
+            assert_eq!(validate_session(&db, &token).await.unwrap(), None);
+
+            let token = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+            assert_eq!(delete_user_sessions(&db, user_id).await.unwrap(), 1);
+            assert_eq!(validate_session(&db, &token).await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_user_locale() {
+        rocket::async_test(async {
+            let db_path = "test_user_locale_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+
+            assert_eq!(get_user_locale(&db, user_id).await.unwrap(), None);
+            set_user_locale(&db, user_id, Some("pt-BR")).await.unwrap();
+            assert_eq!(get_user_locale(&db, user_id).await.unwrap().as_deref(), Some("pt-BR"));
+            set_user_locale(&db, user_id, None).await.unwrap();
+            assert_eq!(get_user_locale(&db, user_id).await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
         });
     }
 }
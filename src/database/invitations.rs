@@ -0,0 +1,233 @@
+use rocket_db_pools::sqlx::{self, Row};
+use rocket_db_pools::sqlx::sqlite::SqliteRow;
+use serde::Serialize;
+
+use crate::crypto::{generate_session_token, get_current_timestamp, hash_token};
+use super::{DbError, NexoDB};
+use super::roles::{DEFAULT_ROLE, grant_role};
+
+/// An invitation as shown to admins. The token itself is never stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct Invitation {
+    pub id: i64,
+    pub role: Option<String>,
+    pub created_by: Option<i32>,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+    pub used_by: Option<i32>,
+    pub revoked_at: Option<i64>,
+}
+
+impl Invitation {
+    fn from_row(row: &SqliteRow) -> Self {
+        Invitation {
+            id: row.get("id"),
+            role: row.get("role"),
+            created_by: row.get("created_by"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+            used_at: row.get("used_at"),
+            used_by: row.get("used_by"),
+            revoked_at: row.get("revoked_at"),
+        }
+    }
+
+    /// `pending`, `used`, `revoked` or `expired`
+    pub fn status(&self, now: i64) -> &'static str {
+        if self.used_at.is_some() {
+            "used"
+        } else if self.revoked_at.is_some() {
+            "revoked"
+        } else if self.expires_at <= now {
+            "expired"
+        } else {
+            "pending"
+        }
+    }
+}
+
+const COLUMNS: &str = "id, role, created_by, created_at, expires_at, used_at, used_by, revoked_at";
+
+/// Create an invitation and return it together with its raw token
+pub async fn create_invitation(
+    db: &NexoDB,
+    created_by: i32,
+    role: Option<&str>,
+    expires_in_seconds: i64,
+) -> Result<(Invitation, String), DbError> {
+    let token = generate_session_token();
+    let now = get_current_timestamp();
+
+    let sql = "INSERT INTO invitations (token_hash, role, created_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?)";
+    let result = sqlx::query(sql)
+        .bind(hash_token(&token))
+        .bind(role)
+        .bind(created_by)
+        .bind(now)
+        .bind(now + expires_in_seconds)
+        .execute(&db.0)
+        .await?;
+
+    let invitation = Invitation {
+        id: result.last_insert_rowid(),
+        role: role.map(str::to_string),
+        created_by: Some(created_by),
+        created_at: now,
+        expires_at: now + expires_in_seconds,
+        used_at: None,
+        used_by: None,
+        revoked_at: None,
+    };
+    Ok((invitation, token))
+}
+
+/// All invitations, newest first
+pub async fn list_invitations(db: &NexoDB) -> Result<Vec<Invitation>, DbError> {
+    let sql = format!("SELECT {} FROM invitations ORDER BY created_at DESC, id DESC", COLUMNS);
+    let rows = sqlx::query(&sql).fetch_all(&db.0).await?;
+    Ok(rows.iter().map(Invitation::from_row).collect())
+}
+
+/// Find an invitation that can still be accepted
+pub async fn find_pending_invitation(db: &NexoDB, token: &str) -> Result<Option<Invitation>, DbError> {
+    let sql = format!(
+        "SELECT {} FROM invitations
+         WHERE token_hash = ? AND used_at IS NULL AND revoked_at IS NULL AND expires_at > ?",
+        COLUMNS
+    );
+    let row = sqlx::query(&sql)
+        .bind(hash_token(token))
+        .bind(get_current_timestamp())
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.as_ref().map(Invitation::from_row))
+}
+
+/// Revoke a pending invitation. Returns `false` if there is no such
+/// invitation or it was already used or revoked.
+pub async fn revoke_invitation(db: &NexoDB, id: i64) -> Result<bool, DbError> {
+    let sql = "UPDATE invitations SET revoked_at = ? WHERE id = ? AND used_at IS NULL AND revoked_at IS NULL";
+    let result = sqlx::query(sql)
+        .bind(get_current_timestamp())
+        .bind(id)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Use an invitation to create a user, atomically
+///
+/// Returns the new user's ID and the invitation it came from, or `None` if
+/// the token is unknown, expired, revoked or was used in the meantime.
+pub async fn accept_invitation(
+    db: &NexoDB,
+    token: &str,
+    name: &str,
+    psw_hash: &str,
+) -> Result<Option<(i32, Invitation)>, DbError> {
+    let now = get_current_timestamp();
+    let mut tx = db.0.begin().await?;
+
+    // Claiming the invitation first means two concurrent acceptances can't
+    // both succeed.
+    let sql = format!(
+        "UPDATE invitations SET used_at = ?
+         WHERE token_hash = ? AND used_at IS NULL AND revoked_at IS NULL AND expires_at > ?
+         RETURNING {}",
+        COLUMNS
+    );
+    let rows = sqlx::query(&sql)
+        .bind(now)
+        .bind(hash_token(token))
+        .bind(now)
+        .fetch_all(&mut *tx)
+        .await?;
+    let Some(invitation) = rows.first().map(Invitation::from_row) else {
+        return Ok(None);
+    };
+
+    let result = sqlx::query("INSERT INTO users (name, psw_hash) VALUES (?, ?)")
+        .bind(name)
+        .bind(psw_hash)
+        .execute(&mut *tx)
+        .await?;
+    let user_id = result.last_insert_rowid() as i32;
+
+    sqlx::query("UPDATE invitations SET used_by = ? WHERE id = ?")
+        .bind(user_id)
+        .bind(invitation.id)
+        .execute(&mut *tx)
+        .await?;
+
+    let role = invitation.role.as_deref().unwrap_or(DEFAULT_ROLE);
+    grant_role(&mut tx, user_id, role).await?;
+
+    tx.commit().await?;
+    Ok(Some((user_id, Invitation { used_by: Some(user_id), ..invitation })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db, create_test_user, get_user_id_by_username};
+    use crate::database::roles::get_user_access;
+
+    #[test]
+    fn test_invitation_is_single_use() {
+        rocket::async_test(async {
+            let db_path = "test_invitations_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let admin = create_test_user(&db, "thiago").await;
+
+            let (invitation, token) = create_invitation(&db, admin, Some("finance"), 3600).await.unwrap();
+            assert_eq!(invitation.status(get_current_timestamp()), "pending");
+            assert!(find_pending_invitation(&db, &token).await.unwrap().is_some());
+            assert!(find_pending_invitation(&db, "not-a-token").await.unwrap().is_none());
+
+            let (user_id, accepted) = accept_invitation(&db, &token, "maria", "hash").await.unwrap().unwrap();
+            assert_eq!(get_user_id_by_username(&db, "maria").await.unwrap(), Some(user_id));
+            assert_eq!(accepted.role.as_deref(), Some("finance"));
+            assert_eq!(accepted.used_by, Some(user_id));
+            assert_eq!(get_user_access(&db, user_id).await.unwrap().0, ["finance"]);
+
+            // The same token can't create a second account
+            assert!(accept_invitation(&db, &token, "ana", "hash").await.unwrap().is_none());
+            assert!(find_pending_invitation(&db, &token).await.unwrap().is_none());
+            assert_eq!(get_user_id_by_username(&db, "ana").await.unwrap(), None);
+
+            let listed = list_invitations(&db).await.unwrap();
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].status(get_current_timestamp()), "used");
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_revoked_and_expired_invitations() {
+        rocket::async_test(async {
+            let db_path = "test_invitations_revoke_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let admin = create_test_user(&db, "thiago").await;
+
+            let (invitation, token) = create_invitation(&db, admin, None, 3600).await.unwrap();
+            assert!(revoke_invitation(&db, invitation.id).await.unwrap());
+            assert!(!revoke_invitation(&db, invitation.id).await.unwrap());
+            assert!(accept_invitation(&db, &token, "maria", "hash").await.unwrap().is_none());
+
+            let (_, expired) = create_invitation(&db, admin, None, -1).await.unwrap();
+            assert!(find_pending_invitation(&db, &expired).await.unwrap().is_none());
+            assert!(accept_invitation(&db, &expired, "maria", "hash").await.unwrap().is_none());
+
+            // A failed insert (duplicate name) leaves the invitation usable
+            let (_, token) = create_invitation(&db, admin, None, 3600).await.unwrap();
+            assert!(accept_invitation(&db, &token, "thiago", "hash").await.is_err());
+            assert!(find_pending_invitation(&db, &token).await.unwrap().is_some());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
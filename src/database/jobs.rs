@@ -0,0 +1,110 @@
+use rocket_db_pools::sqlx::{self, Row};
+use rocket_db_pools::sqlx::sqlite::SqliteRow;
+use serde::Serialize;
+
+use super::{DbError, NexoDB};
+
+/// What is known about a background job's last run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct JobRecord {
+    pub last_started_at: Option<i64>,
+    pub last_finished_at: Option<i64>,
+    pub last_outcome: Option<String>,
+    pub last_message: Option<String>,
+    pub run_count: i64,
+    pub failure_count: i64,
+}
+
+impl JobRecord {
+    fn from_row(row: &SqliteRow) -> Self {
+        JobRecord {
+            last_started_at: row.get("last_started_at"),
+            last_finished_at: row.get("last_finished_at"),
+            last_outcome: row.get("last_outcome"),
+            last_message: row.get("last_message"),
+            run_count: row.get("run_count"),
+            failure_count: row.get("failure_count"),
+        }
+    }
+}
+
+/// Get a job's record. A job that never ran has an empty one.
+pub async fn get_job_record(db: &NexoDB, name: &str) -> Result<JobRecord, DbError> {
+    let sql = "SELECT last_started_at, last_finished_at, last_outcome, last_message, run_count, failure_count
+               FROM scheduled_jobs WHERE name = ?";
+    let row = sqlx::query(sql)
+        .bind(name)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.as_ref().map(JobRecord::from_row).unwrap_or_default())
+}
+
+/// Record a finished run of a job, `Ok` with its report or `Err` with why it
+/// failed
+pub async fn record_job_run(
+    db: &NexoDB,
+    name: &str,
+    started_at: i64,
+    finished_at: i64,
+    result: Result<&str, &str>,
+) -> Result<(), DbError> {
+    let (outcome, message, failed) = match result {
+        Ok(message) => ("success", message, 0),
+        Err(message) => ("failure", message, 1),
+    };
+
+    sqlx::query(
+        "INSERT INTO scheduled_jobs (name, last_started_at, last_finished_at, last_outcome, last_message,
+                                     run_count, failure_count)
+         VALUES (?, ?, ?, ?, ?, 1, ?)
+         ON CONFLICT (name) DO UPDATE SET
+             last_started_at = excluded.last_started_at,
+             last_finished_at = excluded.last_finished_at,
+             last_outcome = excluded.last_outcome,
+             last_message = excluded.last_message,
+             run_count = run_count + 1,
+             failure_count = failure_count + excluded.failure_count",
+    )
+    .bind(name)
+    .bind(started_at)
+    .bind(finished_at)
+    .bind(outcome)
+    .bind(message)
+    .bind(failed)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db};
+
+    #[test]
+    fn test_job_runs_are_recorded() {
+        rocket::async_test(async {
+            let db_path = "test_jobs_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            assert_eq!(get_job_record(&db, "cleanup").await.unwrap(), JobRecord::default());
+
+            record_job_run(&db, "cleanup", 100, 101, Ok("deleted 3")).await.unwrap();
+            record_job_run(&db, "cleanup", 200, 205, Err("database is locked")).await.unwrap();
+
+            let record = get_job_record(&db, "cleanup").await.unwrap();
+            assert_eq!(record, JobRecord {
+                last_started_at: Some(200),
+                last_finished_at: Some(205),
+                last_outcome: Some("failure".to_string()),
+                last_message: Some("database is locked".to_string()),
+                run_count: 2,
+                failure_count: 1,
+            });
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
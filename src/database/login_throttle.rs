@@ -0,0 +1,292 @@
+use rocket_db_pools::sqlx::{self, Row};
+use rocket_db_pools::sqlx::sqlite::SqliteRow;
+use serde::Serialize;
+
+use super::{DbError, NexoDB};
+
+/// Failed login bookkeeping for one account or IP address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ThrottleEntry {
+    pub scope: String,
+    pub key: String,
+    pub failures: i64,
+    pub last_failure_at: i64,
+    pub blocked_until: i64,
+    pub locked: bool,
+}
+
+impl ThrottleEntry {
+    fn from_row(row: &SqliteRow) -> Self {
+        ThrottleEntry {
+            scope: row.get("scope"),
+            key: row.get("key"),
+            failures: row.get("failures"),
+            last_failure_at: row.get("last_failure_at"),
+            blocked_until: row.get("blocked_until"),
+            locked: row.get("locked"),
+        }
+    }
+}
+
+const COLUMNS: &str = "scope, key, failures, last_failure_at, blocked_until, locked";
+
+/// Get the entry for a scope and key, if there is one
+pub async fn get_throttle_entry(db: &NexoDB, scope: &str, key: &str) -> Result<Option<ThrottleEntry>, DbError> {
+    let sql = format!("SELECT {} FROM login_throttle WHERE scope = ? AND key = ?", COLUMNS);
+    let row = sqlx::query(&sql)
+        .bind(scope)
+        .bind(key)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.as_ref().map(ThrottleEntry::from_row))
+}
+
+/// What [`reserve_login_attempt`] did with an attempt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attempt {
+    /// Counted as a failure up front; the entry holds the new count and any
+    /// block it started
+    Counted(ThrottleEntry),
+    /// The key is blocked, so the attempt was refused and not counted
+    Blocked(ThrottleEntry),
+}
+
+/// Count a login attempt as a failure before it is checked, unless the key
+/// is blocked, and set how long the key stays blocked
+///
+/// Counting and blocking happen in one write transaction, so concurrent
+/// attempts each see the count and block the others left. A counter whose
+/// last failure is older than `forget_before` starts over from one.
+/// `block_for` maps the new count to the end of the block, if any, and
+/// whether it is a lockout. Attempts that succeed are handed back with
+/// [`refund_login_attempt`].
+pub async fn reserve_login_attempt(
+    db: &NexoDB,
+    scope: &str,
+    key: &str,
+    now: i64,
+    forget_before: i64,
+    block_for: impl Fn(i64) -> Option<(i64, bool)>,
+) -> Result<Attempt, DbError> {
+    let mut tx = db.0.begin().await?;
+
+    // The upsert leaves a blocked row alone and returns nothing
+    let counted = sqlx::query(
+        "INSERT INTO login_throttle (scope, key, failures, last_failure_at) VALUES (?, ?, 1, ?)
+         ON CONFLICT (scope, key) DO UPDATE SET
+             failures = CASE WHEN last_failure_at < ? THEN 1 ELSE failures + 1 END,
+             last_failure_at = excluded.last_failure_at
+         WHERE blocked_until <= excluded.last_failure_at
+         RETURNING failures",
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(now)
+    .bind(forget_before)
+    .fetch_all(&mut *tx)
+    .await?;
+    let Some(counted) = counted.first() else {
+        let sql = format!("SELECT {} FROM login_throttle WHERE scope = ? AND key = ?", COLUMNS);
+        let row = sqlx::query(&sql).bind(scope).bind(key).fetch_one(&mut *tx).await?;
+        tx.commit().await?;
+        return Ok(Attempt::Blocked(ThrottleEntry::from_row(&row)));
+    };
+    let failures: i64 = counted.get("failures");
+
+    let (blocked_until, locked) = block_for(failures).unwrap_or((0, false));
+    sqlx::query("UPDATE login_throttle SET blocked_until = ?, locked = ? WHERE scope = ? AND key = ?")
+        .bind(blocked_until)
+        .bind(locked)
+        .bind(scope)
+        .bind(key)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Attempt::Counted(ThrottleEntry {
+        scope: scope.to_string(),
+        key: key.to_string(),
+        failures,
+        last_failure_at: now,
+        blocked_until,
+        locked,
+    }))
+}
+
+/// Take back an attempt [`reserve_login_attempt`] counted, because it
+/// turned out not to be a failure, and recompute the block from the lower
+/// count with `block_for`
+pub async fn refund_login_attempt(
+    db: &NexoDB,
+    scope: &str,
+    key: &str,
+    block_for: impl Fn(i64) -> Option<(i64, bool)>,
+) -> Result<(), DbError> {
+    let mut tx = db.0.begin().await?;
+
+    let rows = sqlx::query(
+        "UPDATE login_throttle SET failures = failures - 1 WHERE scope = ? AND key = ? AND failures > 0
+         RETURNING failures",
+    )
+    .bind(scope)
+    .bind(key)
+    .fetch_all(&mut *tx)
+    .await?;
+    if let Some(row) = rows.first() {
+        let (blocked_until, locked) = block_for(row.get("failures")).unwrap_or((0, false));
+        sqlx::query("UPDATE login_throttle SET blocked_until = ?, locked = ? WHERE scope = ? AND key = ?")
+            .bind(blocked_until)
+            .bind(locked)
+            .bind(scope)
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Delete entries whose failures no longer count and that aren't blocked
+pub async fn prune_login_throttle(db: &NexoDB, now: i64, forget_before: i64) -> Result<u64, DbError> {
+    let result = sqlx::query("DELETE FROM login_throttle WHERE last_failure_at < ? AND blocked_until <= ?")
+        .bind(forget_before)
+        .bind(now)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Entries that are blocked or have failures after `since`, most recent first
+pub async fn list_throttle_entries(db: &NexoDB, now: i64, since: i64) -> Result<Vec<ThrottleEntry>, DbError> {
+    let sql = format!(
+        "SELECT {} FROM login_throttle WHERE blocked_until > ? OR last_failure_at >= ? ORDER BY last_failure_at DESC",
+        COLUMNS
+    );
+    let rows = sqlx::query(&sql)
+        .bind(now)
+        .bind(since)
+        .fetch_all(&db.0)
+        .await?;
+
+    Ok(rows.iter().map(ThrottleEntry::from_row).collect())
+}
+
+/// Forget the failures of a scope and key. Returns `false` if there were none.
+pub async fn clear_throttle_entry(db: &NexoDB, scope: &str, key: &str) -> Result<bool, DbError> {
+    let result = sqlx::query("DELETE FROM login_throttle WHERE scope = ? AND key = ?")
+        .bind(scope)
+        .bind(key)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db};
+
+    fn block_after_two(failures: i64) -> Option<(i64, bool)> {
+        (failures >= 2).then_some((5000, failures >= 3))
+    }
+
+    fn counted(attempt: Attempt) -> ThrottleEntry {
+        match attempt {
+            Attempt::Counted(entry) => entry,
+            Attempt::Blocked(entry) => panic!("attempt was blocked: {:?}", entry),
+        }
+    }
+
+    #[test]
+    fn test_attempts_accumulate_and_clear() {
+        rocket::async_test(async {
+            let db_path = "test_login_throttle_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            let first = counted(reserve_login_attempt(&db, "account", "thiago", 1000, 0, block_after_two).await.unwrap());
+            assert_eq!((first.failures, first.blocked_until, first.locked), (1, 0, false));
+            let second = counted(reserve_login_attempt(&db, "account", "thiago", 1001, 0, block_after_two).await.unwrap());
+            assert_eq!((second.failures, second.blocked_until, second.locked), (2, 5000, false));
+            assert_eq!(get_throttle_entry(&db, "account", "thiago").await.unwrap(), Some(second.clone()));
+
+            // Attempts during the block are refused without being counted
+            let refused = reserve_login_attempt(&db, "account", "thiago", 1002, 0, block_after_two).await.unwrap();
+            assert_eq!(refused, Attempt::Blocked(second.clone()));
+            let third = counted(reserve_login_attempt(&db, "account", "thiago", 5000, 0, block_after_two).await.unwrap());
+            assert_eq!((third.failures, third.blocked_until, third.locked), (3, 5000, true));
+
+            // Scopes are tracked separately
+            assert_eq!(get_throttle_entry(&db, "ip", "thiago").await.unwrap(), None);
+
+            assert!(clear_throttle_entry(&db, "account", "thiago").await.unwrap());
+            assert!(!clear_throttle_entry(&db, "account", "thiago").await.unwrap());
+            assert_eq!(get_throttle_entry(&db, "account", "thiago").await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_refund_lowers_the_count_and_the_block() {
+        rocket::async_test(async {
+            let db_path = "test_login_throttle_refund_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            reserve_login_attempt(&db, "ip", "127.0.0.1", 1000, 0, block_after_two).await.unwrap();
+            reserve_login_attempt(&db, "ip", "127.0.0.1", 1001, 0, block_after_two).await.unwrap();
+            refund_login_attempt(&db, "ip", "127.0.0.1", block_after_two).await.unwrap();
+            let entry = get_throttle_entry(&db, "ip", "127.0.0.1").await.unwrap().unwrap();
+            assert_eq!((entry.failures, entry.blocked_until, entry.locked), (1, 0, false));
+
+            // Nothing to refund for unknown keys or empty counters
+            refund_login_attempt(&db, "ip", "127.0.0.1", block_after_two).await.unwrap();
+            refund_login_attempt(&db, "ip", "127.0.0.1", block_after_two).await.unwrap();
+            refund_login_attempt(&db, "ip", "10.0.0.1", block_after_two).await.unwrap();
+            assert_eq!(get_throttle_entry(&db, "ip", "127.0.0.1").await.unwrap().unwrap().failures, 0);
+            assert_eq!(get_throttle_entry(&db, "ip", "10.0.0.1").await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_old_failures_are_forgotten() {
+        rocket::async_test(async {
+            let db_path = "test_login_throttle_forget_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            reserve_login_attempt(&db, "ip", "127.0.0.1", 1000, 0, |_| None).await.unwrap();
+            reserve_login_attempt(&db, "ip", "127.0.0.1", 1001, 0, |_| None).await.unwrap();
+            let entry = counted(reserve_login_attempt(&db, "ip", "127.0.0.1", 9000, 5000, block_after_two).await.unwrap());
+            assert_eq!((entry.failures, entry.blocked_until), (1, 0));
+
+            assert_eq!(list_throttle_entries(&db, 9000, 8000).await.unwrap(), vec![entry]);
+            assert!(list_throttle_entries(&db, 9000, 9500).await.unwrap().is_empty());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_prune_keeps_recent_and_blocked_entries() {
+        rocket::async_test(async {
+            let db_path = "test_login_throttle_prune_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            reserve_login_attempt(&db, "ip", "old", 1000, 0, |_| None).await.unwrap();
+            reserve_login_attempt(&db, "ip", "locked", 1000, 0, |_| Some((9000, true))).await.unwrap();
+            reserve_login_attempt(&db, "ip", "recent", 7000, 0, |_| None).await.unwrap();
+
+            assert_eq!(prune_login_throttle(&db, 8000, 5000).await.unwrap(), 1);
+            assert_eq!(get_throttle_entry(&db, "ip", "old").await.unwrap(), None);
+            assert!(get_throttle_entry(&db, "ip", "locked").await.unwrap().is_some());
+            assert!(get_throttle_entry(&db, "ip", "recent").await.unwrap().is_some());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
@@ -0,0 +1,201 @@
+use rocket_db_pools::sqlx::{self, Row};
+use serde::Serialize;
+
+use super::{DbError, NexoDB};
+use super::migrations::{MigrationError, applied_versions, latest_version, pending_migrations};
+
+/// A migration the database hasn't had yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PendingMigration {
+    pub version: i64,
+    pub name: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TableCount {
+    pub table: String,
+    pub rows: i64,
+}
+
+/// Where the database's schema stands and how much is in it
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseStatus {
+    /// Highest applied migration, 0 for a database nexo never migrated
+    pub schema_version: i64,
+    /// Highest migration this binary knows about
+    pub latest_version: i64,
+    pub pending_migrations: Vec<PendingMigration>,
+    pub tables: Vec<TableCount>,
+}
+
+/// A row whose foreign key points at nothing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub rowid: Option<i64>,
+    pub parent: String,
+}
+
+/// Result of SQLite's own consistency checks
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityReport {
+    pub ok: bool,
+    /// Problems reported by `PRAGMA integrity_check`; empty when it said "ok"
+    pub integrity_errors: Vec<String>,
+    pub foreign_key_violations: Vec<ForeignKeyViolation>,
+}
+
+/// Schema version, pending migrations and row counts of every table
+pub async fn database_status(db: &NexoDB) -> Result<DatabaseStatus, MigrationError> {
+    let schema_version = applied_versions(db).await?.last().copied().unwrap_or(0);
+    let pending_migrations = pending_migrations(db)
+        .await?
+        .into_iter()
+        .map(|m| PendingMigration { version: m.version, name: m.name })
+        .collect();
+
+    let names = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+        .fetch_all(&db.0)
+        .await?;
+    let mut tables = Vec::with_capacity(names.len());
+    for row in names {
+        let table: String = row.get("name");
+        // Table names can't be bound, so quote the identifier instead
+        let sql = format!("SELECT COUNT(*) AS count FROM \"{}\"", table.replace('"', "\"\""));
+        let rows = sqlx::query(&sql).fetch_one(&db.0).await?.get("count");
+        tables.push(TableCount { table, rows });
+    }
+
+    Ok(DatabaseStatus { schema_version, latest_version: latest_version(), pending_migrations, tables })
+}
+
+/// Run `PRAGMA integrity_check` and `PRAGMA foreign_key_check`
+pub async fn check_integrity(db: &NexoDB) -> Result<IntegrityReport, DbError> {
+    let integrity_errors: Vec<String> = sqlx::query("PRAGMA integrity_check")
+        .fetch_all(&db.0)
+        .await?
+        .iter()
+        .map(|row| row.get::<String, _>(0))
+        .filter(|message| message != "ok")
+        .collect();
+
+    let foreign_key_violations: Vec<ForeignKeyViolation> = sqlx::query("PRAGMA foreign_key_check")
+        .fetch_all(&db.0)
+        .await?
+        .iter()
+        .map(|row| ForeignKeyViolation { table: row.get("table"), rowid: row.get("rowid"), parent: row.get("parent") })
+        .collect();
+
+    Ok(IntegrityReport {
+        ok: integrity_errors.is_empty() && foreign_key_violations.is_empty(),
+        integrity_errors,
+        foreign_key_violations,
+    })
+}
+
+/// Rebuild the database file to reclaim the space of deleted rows
+pub async fn vacuum(db: &NexoDB) -> Result<(), DbError> {
+    sqlx::query("VACUUM").execute(&db.0).await?;
+    Ok(())
+}
+
+/// Write a consistent copy of the database to `path`, which must not exist
+///
+/// Uses `VACUUM INTO`, so it is safe while the server is running.
+pub async fn backup(db: &NexoDB, path: &str) -> Result<(), DbError> {
+    sqlx::query("VACUUM INTO ?").bind(path).execute(&db.0).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, open_empty_test_db, close_test_db, create_test_user};
+    use crate::database::migrations::MIGRATIONS;
+
+    #[test]
+    fn test_status_of_migrated_database() {
+        rocket::async_test(async {
+            let db_path = "test_maintenance_status_db.sqlite";
+            let db = open_test_db(db_path).await;
+            create_test_user(&db, "maria").await;
+
+            let status = database_status(&db).await.unwrap();
+            assert_eq!(status.schema_version, latest_version());
+            assert!(status.pending_migrations.is_empty());
+            let users = status.tables.iter().find(|t| t.table == "users").unwrap();
+            assert_eq!(users.rows, 1);
+            assert!(status.tables.iter().any(|t| t.table == "schema_migrations"));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_status_lists_pending_migrations() {
+        rocket::async_test(async {
+            let db_path = "test_maintenance_pending_db.sqlite";
+            let db = open_empty_test_db(db_path).await;
+
+            let status = database_status(&db).await.unwrap();
+            assert_eq!(status.schema_version, 0);
+            assert_eq!(status.pending_migrations.len(), MIGRATIONS.len());
+            assert_eq!(status.pending_migrations[0], PendingMigration { version: 1, name: "initial" });
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_backup_is_a_working_copy() {
+        rocket::async_test(async {
+            let db_path = "test_maintenance_backup_db.sqlite";
+            let backup_path = "test_maintenance_backup_copy.sqlite";
+            let _ = std::fs::remove_file(backup_path);
+            let db = open_test_db(db_path).await;
+            create_test_user(&db, "maria").await;
+
+            vacuum(&db).await.unwrap();
+            backup(&db, backup_path).await.unwrap();
+            // Never overwrites an existing file
+            assert!(backup(&db, backup_path).await.is_err());
+
+            let copy = crate::database::connect(backup_path).await.unwrap();
+            let status = database_status(&copy).await.unwrap();
+            assert_eq!(status.schema_version, latest_version());
+            assert_eq!(status.tables.iter().find(|t| t.table == "users").unwrap().rows, 1);
+
+            close_test_db(copy, backup_path).await;
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        rocket::async_test(async {
+            let db_path = "test_maintenance_integrity_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            let report = check_integrity(&db).await.unwrap();
+            assert!(report.ok, "{:?}", report);
+
+            // A role for a user that doesn't exist, written with enforcement off
+            let mut conn = db.0.acquire().await.unwrap();
+            sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await.unwrap();
+            sqlx::query("INSERT INTO user_roles (user_id, role_id) VALUES (999, 1)")
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+            sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await.unwrap();
+            drop(conn);
+
+            let report = check_integrity(&db).await.unwrap();
+            assert!(!report.ok);
+            assert_eq!(report.foreign_key_violations.len(), 1);
+            assert_eq!(report.foreign_key_violations[0].table, "user_roles");
+            assert_eq!(report.foreign_key_violations[0].parent, "users");
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
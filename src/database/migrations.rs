@@ -0,0 +1,330 @@
+use std::fmt;
+
+use rocket::fairing::AdHoc;
+use rocket_db_pools::Database;
+use rocket_db_pools::sqlx::{self, Row};
+
+use crate::crypto::get_current_timestamp;
+use super::{DbError, NexoDB};
+
+/// A forward-only schema change embedded in the binary.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order it must be applied.
+///
+/// Versions are never reused or edited once released; schema changes always
+/// go into a new file under `data/migrations/`.
+pub const MIGRATIONS: &[Migration] = &[
+    // Byte for byte the old data/db.sql, which existing deployments were
+    // created from; it is idempotent so they adopt the migration table
+    // cleanly. Seed and hash changes belong in later migrations.
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../data/migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "totp",
+        sql: include_str!("../../data/migrations/0002_totp.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "unique_user_contacts",
+        sql: include_str!("../../data/migrations/0003_unique_user_contacts.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "invitations",
+        sql: include_str!("../../data/migrations/0004_invitations.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "password_resets",
+        sql: include_str!("../../data/migrations/0005_password_resets.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "login_throttle",
+        sql: include_str!("../../data/migrations/0006_login_throttle.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "hashed_session_tokens",
+        sql: include_str!("../../data/migrations/0007_hashed_session_tokens.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "session_details",
+        sql: include_str!("../../data/migrations/0008_session_details.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "session_renewal",
+        sql: include_str!("../../data/migrations/0009_session_renewal.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "scheduled_jobs",
+        sql: include_str!("../../data/migrations/0010_scheduled_jobs.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "roles",
+        sql: include_str!("../../data/migrations/0011_roles.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "disabled_users",
+        sql: include_str!("../../data/migrations/0012_disabled_users.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "first_run_setup",
+        sql: include_str!("../../data/migrations/0013_first_run_setup.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "session_csrf_tokens",
+        sql: include_str!("../../data/migrations/0014_session_csrf_tokens.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "user_locale",
+        sql: include_str!("../../data/migrations/0015_user_locale.sql"),
+    },
+];
+
+const CREATE_MIGRATIONS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS "schema_migrations" (
+    "version" INTEGER NOT NULL PRIMARY KEY,
+    "name" VARCHAR NOT NULL,
+    "applied_at" INTEGER NOT NULL
+)"#;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(DbError),
+    /// The database has a migration this binary doesn't know about, i.e. it
+    /// was last opened by a newer version of nexo.
+    UnknownVersion(i64),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "database error: {}", e),
+            MigrationError::UnknownVersion(v) => write!(
+                f,
+                "database is at schema version {}, newer than this binary supports ({})",
+                v,
+                latest_version()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Database(e.into())
+    }
+}
+
+impl From<DbError> for MigrationError {
+    fn from(e: DbError) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+/// Highest migration version embedded in this binary
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Versions recorded in `schema_migrations`, oldest first
+pub async fn applied_versions(db: &NexoDB) -> Result<Vec<i64>, DbError> {
+    sqlx::query(CREATE_MIGRATIONS_TABLE).execute(&db.0).await?;
+
+    let rows = sqlx::query("SELECT version FROM schema_migrations ORDER BY version")
+        .fetch_all(&db.0)
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get("version")).collect())
+}
+
+/// Migrations that have not been applied yet, in order
+pub async fn pending_migrations(db: &NexoDB) -> Result<Vec<&'static Migration>, MigrationError> {
+    let applied = applied_versions(db).await?;
+
+    if let Some(&unknown) = applied.iter().find(|v| !MIGRATIONS.iter().any(|m| m.version == **v)) {
+        return Err(MigrationError::UnknownVersion(unknown));
+    }
+
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect())
+}
+
+/// Apply every pending migration and return the versions that ran.
+///
+/// Each migration runs in its own transaction together with its
+/// `schema_migrations` row, so a failure leaves the database at the last
+/// migration that succeeded.
+pub async fn run_migrations(db: &NexoDB) -> Result<Vec<i64>, MigrationError> {
+    let mut ran = Vec::new();
+
+    for migration in pending_migrations(db).await? {
+        let mut tx = db.0.begin().await?;
+
+        sqlx::query(migration.sql).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(get_current_timestamp())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        println!("Applied migration {:04}_{}", migration.version, migration.name);
+        ran.push(migration.version);
+    }
+
+    Ok(ran)
+}
+
+/// Fairing that brings the schema up to date before the server starts
+/// accepting requests. Must be attached after `NexoDB::init()`.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Database Migrations", |rocket| async {
+        let Some(db) = NexoDB::fetch(&rocket) else {
+            return Err(rocket);
+        };
+
+        match run_migrations(db).await {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                eprintln!("Failed to migrate database: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db, open_empty_test_db, get_user_id_by_username};
+    use crate::database::roles::{get_user_access, has_active_admin};
+    use crate::database::setup::setup_required;
+
+    /// The data/db.sql that deployments were created from before migrations
+    const LEGACY_DB_SQL: &str = include_str!("fixtures/legacy_db.sql");
+
+    #[test]
+    fn test_versions_are_strictly_increasing() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version,
+                    "migration {} must come after {}", pair[1].version, pair[0].version);
+        }
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[test]
+    fn test_initial_migration_is_the_legacy_script() {
+        assert_eq!(MIGRATIONS[0].sql, LEGACY_DB_SQL, "0001 must stay byte for byte the old data/db.sql");
+    }
+
+    #[test]
+    fn test_all_migrations_apply_to_empty_database() {
+        rocket::async_test(async {
+            let db_path = "test_migrations_db.sqlite";
+            let db = open_empty_test_db(db_path).await;
+
+            let ran = run_migrations(&db).await.expect("Failed to run migrations");
+            let expected: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+            assert_eq!(ran, expected);
+            assert_eq!(applied_versions(&db).await.unwrap(), expected);
+
+            // Running again is a no-op
+            assert!(run_migrations(&db).await.unwrap().is_empty());
+            assert!(pending_migrations(&db).await.unwrap().is_empty());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_adopts_database_created_by_legacy_script() {
+        rocket::async_test(async {
+            let db_path = "test_migrations_legacy_db.sqlite";
+            let db = open_empty_test_db(db_path).await;
+
+            // What data/db.sql used to leave behind: tables and the seed user
+            // with its SHA-256 password, but no schema_migrations
+            sqlx::query(LEGACY_DB_SQL).execute(&db.0).await.unwrap();
+
+            run_migrations(&db).await.expect("Failed to adopt legacy database");
+            assert_eq!(applied_versions(&db).await.unwrap().last(), Some(&latest_version()));
+
+            // The seed user still had its published password, so it is gone
+            // and the instance waits for setup
+            assert_eq!(get_user_id_by_username(&db, "thiago").await.unwrap(), None);
+            assert!(setup_required(&db).await.unwrap());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_keeps_seed_user_whose_password_changed() {
+        rocket::async_test(async {
+            let db_path = "test_migrations_seed_db.sqlite";
+            let db = open_empty_test_db(db_path).await;
+
+            sqlx::query(LEGACY_DB_SQL).execute(&db.0).await.unwrap();
+            sqlx::query("UPDATE users SET psw_hash = 'changed' WHERE name = 'thiago'")
+                .execute(&db.0)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO users (name, psw_hash) VALUES ('maria', 'hash')")
+                .execute(&db.0)
+                .await
+                .unwrap();
+
+            run_migrations(&db).await.expect("Failed to migrate database");
+            let thiago = get_user_id_by_username(&db, "thiago").await.unwrap().expect("seed user was removed");
+            // Nobody is promoted; the operator grants admin with nexo-admin
+            assert_eq!(get_user_access(&db, thiago).await.unwrap().0, ["member"]);
+            assert!(!has_active_admin(&db).await.unwrap());
+            assert!(!setup_required(&db).await.unwrap());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_rejects_database_from_newer_binary() {
+        rocket::async_test(async {
+            let db_path = "test_migrations_newer_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, 'future', 0)")
+                .bind(latest_version() + 1)
+                .execute(&db.0)
+                .await
+                .unwrap();
+
+            let result = run_migrations(&db).await;
+            assert!(matches!(result, Err(MigrationError::UnknownVersion(v)) if v == latest_version() + 1));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
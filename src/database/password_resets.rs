@@ -0,0 +1,129 @@
+use rocket_db_pools::sqlx::{self, Row};
+
+use crate::crypto::{generate_session_token, get_current_timestamp, hash_token};
+use super::{DbError, NexoDB};
+
+/// Issue a reset token for a user and return it
+///
+/// Any earlier unused token for the same user stops working, so only the
+/// most recent email's link is valid.
+pub async fn create_password_reset(db: &NexoDB, user_id: i32, expires_in_seconds: i64) -> Result<String, DbError> {
+    let token = generate_session_token();
+    let now = get_current_timestamp();
+    let mut tx = db.0.begin().await?;
+
+    sqlx::query("DELETE FROM password_resets WHERE user_id = ? AND used_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO password_resets (user_id, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?)")
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(now)
+        .bind(now + expires_in_seconds)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(token)
+}
+
+/// Get the user a reset token belongs to, if it is unused and unexpired
+pub async fn find_password_reset_user(db: &NexoDB, token: &str) -> Result<Option<i32>, DbError> {
+    let sql = "SELECT user_id FROM password_resets WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?";
+    let row = sqlx::query(sql)
+        .bind(hash_token(token))
+        .bind(get_current_timestamp())
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| row.get("user_id")))
+}
+
+/// Use a reset token to set a new password hash
+///
+/// In the same transaction the token is marked used and every session of the
+/// user is deleted, so whoever had access before the reset is logged out.
+/// Returns the user's ID, or `None` if the token is no longer valid.
+pub async fn complete_password_reset(db: &NexoDB, token: &str, psw_hash: &str) -> Result<Option<i32>, DbError> {
+    let now = get_current_timestamp();
+    let mut tx = db.0.begin().await?;
+
+    let rows = sqlx::query(
+        "UPDATE password_resets SET used_at = ?
+         WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+         RETURNING user_id",
+    )
+    .bind(now)
+    .bind(hash_token(token))
+    .bind(now)
+    .fetch_all(&mut *tx)
+    .await?;
+    let Some(user_id) = rows.first().map(|row| row.get::<i32, _>("user_id")) else {
+        return Ok(None);
+    };
+
+    sqlx::query("UPDATE users SET psw_hash = ? WHERE id = ?")
+        .bind(psw_hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db, create_session, get_password_hash_from_username,
+                          create_test_user, validate_session, SessionClient};
+
+    #[test]
+    fn test_reset_changes_password_and_ends_sessions() {
+        rocket::async_test(async {
+            let db_path = "test_password_reset_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let session = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+
+            let token = create_password_reset(&db, user_id, 1800).await.unwrap();
+            assert_eq!(find_password_reset_user(&db, &token).await.unwrap(), Some(user_id));
+
+            assert_eq!(complete_password_reset(&db, &token, "new-hash").await.unwrap(), Some(user_id));
+            assert_eq!(get_password_hash_from_username(&db, "thiago").await.unwrap().as_deref(), Some("new-hash"));
+            assert_eq!(validate_session(&db, &session).await.unwrap(), None);
+
+            // Tokens are single use
+            assert_eq!(find_password_reset_user(&db, &token).await.unwrap(), None);
+            assert_eq!(complete_password_reset(&db, &token, "other-hash").await.unwrap(), None);
+            assert_eq!(get_password_hash_from_username(&db, "thiago").await.unwrap().as_deref(), Some("new-hash"));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_only_latest_unexpired_token_works() {
+        rocket::async_test(async {
+            let db_path = "test_password_reset_expiry_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+
+            let expired = create_password_reset(&db, user_id, -1).await.unwrap();
+            assert_eq!(find_password_reset_user(&db, &expired).await.unwrap(), None);
+            assert_eq!(complete_password_reset(&db, &expired, "hash").await.unwrap(), None);
+
+            let first = create_password_reset(&db, user_id, 1800).await.unwrap();
+            let second = create_password_reset(&db, user_id, 1800).await.unwrap();
+            assert_eq!(find_password_reset_user(&db, &first).await.unwrap(), None);
+            assert_eq!(find_password_reset_user(&db, &second).await.unwrap(), Some(user_id));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
@@ -0,0 +1,271 @@
+use rocket_db_pools::sqlx::{self, Row, SqliteConnection};
+use serde::Serialize;
+
+use super::{DbError, NexoDB};
+
+/// Role every new user gets unless an invitation says otherwise
+pub const DEFAULT_ROLE: &str = "member";
+/// Role that holds every permission. There is always at least one user
+/// with it.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// A role and the permissions it grants
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+}
+
+/// A user as shown to admins managing roles
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserRoles {
+    pub id: i32,
+    pub name: String,
+    pub roles: Vec<String>,
+    pub disabled: bool,
+}
+
+/// Why a user's roles were not changed
+#[derive(Debug)]
+pub enum SetRolesError {
+    UnknownUser,
+    UnknownRole(String),
+    /// The change would leave nobody with the admin role
+    LastAdmin,
+    Database(DbError),
+}
+
+impl From<sqlx::Error> for SetRolesError {
+    fn from(e: sqlx::Error) -> Self {
+        SetRolesError::Database(e.into())
+    }
+}
+
+impl From<DbError> for SetRolesError {
+    fn from(e: DbError) -> Self {
+        SetRolesError::Database(e)
+    }
+}
+
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.map(|list| list.split(',').map(str::to_string).collect()).unwrap_or_default()
+}
+
+/// The names of a user's roles and of every permission they grant, sorted
+pub async fn get_user_access(db: &NexoDB, user_id: i32) -> Result<(Vec<String>, Vec<String>), DbError> {
+    let roles = sqlx::query(
+        "SELECT roles.name FROM user_roles JOIN roles ON roles.id = user_roles.role_id
+         WHERE user_roles.user_id = ? ORDER BY roles.name",
+    )
+    .bind(user_id)
+    .fetch_all(&db.0)
+    .await?;
+
+    let permissions = sqlx::query(
+        "SELECT DISTINCT role_permissions.permission FROM user_roles
+         JOIN role_permissions ON role_permissions.role_id = user_roles.role_id
+         WHERE user_roles.user_id = ? ORDER BY role_permissions.permission",
+    )
+    .bind(user_id)
+    .fetch_all(&db.0)
+    .await?;
+
+    Ok((
+        roles.iter().map(|row| row.get("name")).collect(),
+        permissions.iter().map(|row| row.get("permission")).collect(),
+    ))
+}
+
+/// Every role with its permissions
+pub async fn list_roles(db: &NexoDB) -> Result<Vec<Role>, DbError> {
+    let rows = sqlx::query(
+        "SELECT roles.name, roles.description, GROUP_CONCAT(role_permissions.permission) AS permissions
+         FROM roles LEFT JOIN role_permissions ON role_permissions.role_id = roles.id
+         GROUP BY roles.id ORDER BY roles.name",
+    )
+    .fetch_all(&db.0)
+    .await?;
+
+    Ok(rows.iter().map(|row| {
+        let mut permissions = split_list(row.get("permissions"));
+        permissions.sort();
+        Role { name: row.get("name"), description: row.get("description"), permissions }
+    }).collect())
+}
+
+/// Whether a role with this name exists
+pub async fn role_exists(db: &NexoDB, name: &str) -> Result<bool, DbError> {
+    let row = sqlx::query("SELECT 1 FROM roles WHERE name = ?")
+        .bind(name)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+/// Every user with their roles
+pub async fn list_users_with_roles(db: &NexoDB) -> Result<Vec<UserRoles>, DbError> {
+    let rows = sqlx::query(
+        "SELECT users.id, users.name, users.disabled_at IS NOT NULL AS disabled, GROUP_CONCAT(roles.name) AS roles
+         FROM users
+         LEFT JOIN user_roles ON user_roles.user_id = users.id
+         LEFT JOIN roles ON roles.id = user_roles.role_id
+         GROUP BY users.id ORDER BY users.id",
+    )
+    .fetch_all(&db.0)
+    .await?;
+
+    Ok(rows.iter().map(|row| {
+        let mut roles = split_list(row.get("roles"));
+        roles.sort();
+        UserRoles { id: row.get("id"), name: row.get("name"), roles, disabled: row.get("disabled") }
+    }).collect())
+}
+
+/// Give a user a role, inside a caller's transaction. Returns false when
+/// nothing was granted, because the role doesn't exist or the user already
+/// has it.
+pub async fn grant_role(conn: &mut SqliteConnection, user_id: i32, role: &str) -> Result<bool, DbError> {
+    let result = sqlx::query("INSERT OR IGNORE INTO user_roles (user_id, role_id) SELECT ?, id FROM roles WHERE name = ?")
+        .bind(user_id)
+        .bind(role)
+        .execute(conn)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Counts enabled users with the admin role; binds [`ADMIN_ROLE`]
+const ACTIVE_ADMINS: &str =
+    "SELECT COUNT(*) AS count FROM user_roles JOIN roles ON roles.id = user_roles.role_id
+     JOIN users ON users.id = user_roles.user_id
+     WHERE roles.name = ? AND users.disabled_at IS NULL";
+
+/// Whether any enabled user holds the admin role
+pub async fn has_active_admin(db: &NexoDB) -> Result<bool, DbError> {
+    let admins: i64 = sqlx::query(ACTIVE_ADMINS).bind(ADMIN_ROLE).fetch_one(&db.0).await?.get("count");
+    Ok(admins > 0)
+}
+
+/// Whether a user is the only enabled admin, who must not be disabled
+pub async fn is_last_admin(db: &NexoDB, user_id: i32) -> Result<bool, DbError> {
+    let (roles, _) = get_user_access(db, user_id).await?;
+    if !roles.iter().any(|role| role == ADMIN_ROLE) {
+        return Ok(false);
+    }
+
+    let admins: i64 = sqlx::query(ACTIVE_ADMINS).bind(ADMIN_ROLE).fetch_one(&db.0).await?.get("count");
+    Ok(admins <= 1)
+}
+
+/// Replace a user's roles. At least one enabled user must stay admin.
+pub async fn set_user_roles(db: &NexoDB, user_id: i32, roles: &[String]) -> Result<(), SetRolesError> {
+    let mut tx = db.0.begin().await?;
+
+    // Writing first takes the write lock up front, so a competing writer
+    // makes this wait out the busy timeout instead of failing on the upgrade
+    sqlx::query("DELETE FROM user_roles WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    let user = sqlx::query("SELECT 1 FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if user.is_none() {
+        return Err(SetRolesError::UnknownUser);
+    }
+
+    let mut roles = roles.to_vec();
+    roles.sort();
+    roles.dedup();
+    for role in roles {
+        // The user has no roles left, so nothing granted means no such role
+        if !grant_role(&mut tx, user_id, &role).await? {
+            return Err(SetRolesError::UnknownRole(role));
+        }
+    }
+
+    let admins: i64 = sqlx::query(ACTIVE_ADMINS).bind(ADMIN_ROLE).fetch_one(&mut *tx).await?.get("count");
+    if admins == 0 {
+        return Err(SetRolesError::LastAdmin);
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db, create_user};
+    use crate::database::setup::create_first_admin;
+
+    #[test]
+    fn test_seeded_roles() {
+        rocket::async_test(async {
+            let db_path = "test_roles_seed_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            let roles = list_roles(&db).await.unwrap();
+            let names: Vec<&str> = roles.iter().map(|r| r.name.as_str()).collect();
+            assert_eq!(names, ["admin", "documents", "finance", "member"]);
+            assert!(roles.iter().find(|r| r.name == "member").unwrap().permissions.is_empty());
+            assert_eq!(roles.iter().find(|r| r.name == "finance").unwrap().permissions, ["finance.view"]);
+
+            // The admin role holds every permission
+            assert!(!has_active_admin(&db).await.unwrap());
+            let admin = create_first_admin(&db, "thiago", "hash", None).await.unwrap().unwrap();
+            let (roles, permissions) = get_user_access(&db, admin).await.unwrap();
+            assert_eq!(roles, ["admin"]);
+            assert!(permissions.contains(&"users.manage".to_string()));
+            assert!(has_active_admin(&db).await.unwrap());
+            assert!(permissions.contains(&"finance.view".to_string()));
+
+            // New users start out as members
+            let member = create_user(&db, "member", "hash", None, None).await.unwrap();
+            assert_eq!(get_user_access(&db, member).await.unwrap(), (vec!["member".to_string()], vec![]));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_set_user_roles() {
+        rocket::async_test(async {
+            let db_path = "test_roles_set_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let admin = create_first_admin(&db, "thiago", "hash", None).await.unwrap().unwrap();
+            let member = create_user(&db, "member", "hash", None, None).await.unwrap();
+            let roles = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+            set_user_roles(&db, member, &roles(&["member", "finance", "finance"])).await.unwrap();
+            let (member_roles, permissions) = get_user_access(&db, member).await.unwrap();
+            assert_eq!(member_roles, ["finance", "member"]);
+            assert_eq!(permissions, ["finance.view"]);
+
+            assert!(matches!(set_user_roles(&db, member, &roles(&["wizard"])).await,
+                             Err(SetRolesError::UnknownRole(role)) if role == "wizard"));
+            assert!(matches!(set_user_roles(&db, 999, &roles(&["member"])).await, Err(SetRolesError::UnknownUser)));
+            // Failed changes leave the roles alone
+            assert_eq!(get_user_access(&db, member).await.unwrap().0, ["finance", "member"]);
+
+            // The only admin can't be demoted, but can once there is another
+            assert!(is_last_admin(&db, admin).await.unwrap());
+            assert!(!is_last_admin(&db, member).await.unwrap());
+            assert!(matches!(set_user_roles(&db, admin, &roles(&["member"])).await, Err(SetRolesError::LastAdmin)));
+            set_user_roles(&db, member, &roles(&["admin"])).await.unwrap();
+            assert!(!is_last_admin(&db, admin).await.unwrap());
+            set_user_roles(&db, admin, &roles(&["member"])).await.unwrap();
+
+            let users = list_users_with_roles(&db).await.unwrap();
+            assert_eq!(users, vec![
+                UserRoles { id: admin, name: "thiago".to_string(), roles: roles(&["member"]), disabled: false },
+                UserRoles { id: member, name: "member".to_string(), roles: roles(&["admin"]), disabled: false },
+            ]);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
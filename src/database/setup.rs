@@ -0,0 +1,97 @@
+use rocket_db_pools::sqlx::{self, Row};
+
+use crate::crypto::get_current_timestamp;
+use super::{DbError, NexoDB};
+use super::roles::{ADMIN_ROLE, grant_role};
+
+/// Whether the instance still needs its first admin: setup never ran and
+/// nobody has an account
+pub async fn setup_required(db: &NexoDB) -> Result<bool, DbError> {
+    let row = sqlx::query(
+        "SELECT NOT EXISTS (SELECT 1 FROM setup) AND NOT EXISTS (SELECT 1 FROM users) AS required",
+    )
+    .fetch_one(&db.0)
+    .await?;
+
+    Ok(row.get("required"))
+}
+
+/// Create the first user as admin and close setup for good
+///
+/// Returns `None` without creating anything if setup is no longer
+/// required, so of two racing submissions only one gets through.
+pub async fn create_first_admin(
+    db: &NexoDB,
+    name: &str,
+    psw_hash: &str,
+    email: Option<&str>,
+) -> Result<Option<i32>, DbError> {
+    let mut tx = db.0.begin().await?;
+
+    let claimed = sqlx::query(
+        "INSERT OR IGNORE INTO setup (id, completed_at) SELECT 1, ? WHERE NOT EXISTS (SELECT 1 FROM users)",
+    )
+    .bind(get_current_timestamp())
+    .execute(&mut *tx)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let result = sqlx::query("INSERT INTO users (name, psw_hash, email) VALUES (?, ?, ?)")
+        .bind(name)
+        .bind(psw_hash)
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    let user_id = result.last_insert_rowid() as i32;
+    grant_role(&mut tx, user_id, ADMIN_ROLE).await?;
+
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db, create_user};
+    use crate::database::roles::get_user_access;
+
+    #[test]
+    fn test_first_admin_closes_setup() {
+        rocket::async_test(async {
+            let db_path = "test_setup_db.sqlite";
+            let db = open_test_db(db_path).await;
+            assert!(setup_required(&db).await.unwrap());
+
+            let admin = create_first_admin(&db, "maria", "hash", Some("maria@example.com")).await.unwrap().unwrap();
+            assert_eq!(get_user_access(&db, admin).await.unwrap().0, [ADMIN_ROLE]);
+            assert!(!setup_required(&db).await.unwrap());
+
+            // There is only ever one first admin
+            assert_eq!(create_first_admin(&db, "ana", "hash", None).await.unwrap(), None);
+
+            // Even once every user is gone
+            sqlx::query("DELETE FROM users").execute(&db.0).await.unwrap();
+            assert!(!setup_required(&db).await.unwrap());
+            assert_eq!(create_first_admin(&db, "ana", "hash", None).await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_existing_users_skip_setup() {
+        rocket::async_test(async {
+            let db_path = "test_setup_existing_db.sqlite";
+            let db = open_test_db(db_path).await;
+
+            // e.g. a user created with nexo-admin before anyone opened the site
+            create_user(&db, "maria", "hash", None, None).await.unwrap();
+            assert!(!setup_required(&db).await.unwrap());
+            assert_eq!(create_first_admin(&db, "ana", "hash", None).await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
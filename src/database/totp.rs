@@ -0,0 +1,283 @@
+use rocket_db_pools::sqlx::{self, Row};
+
+use crate::crypto::{generate_session_token, get_current_timestamp, hash_token};
+use super::{DbError, NexoDB};
+
+/// A user's TOTP enrollment, confirmed or not
+pub struct TotpRecord {
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: Option<i64>,
+}
+
+/// Get the TOTP enrollment for a user, if any
+pub async fn get_totp(db: &NexoDB, user_id: i32) -> Result<Option<TotpRecord>, DbError> {
+    let sql = "SELECT secret, enabled, last_used_step FROM user_totp WHERE user_id = ?";
+    let row = sqlx::query(sql)
+        .bind(user_id)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| TotpRecord {
+        secret: row.get("secret"),
+        enabled: row.get("enabled"),
+        last_used_step: row.get("last_used_step"),
+    }))
+}
+
+/// Whether a user has a confirmed second factor
+pub async fn is_totp_enabled(db: &NexoDB, user_id: i32) -> Result<bool, DbError> {
+    Ok(get_totp(db, user_id).await?.is_some_and(|totp| totp.enabled))
+}
+
+/// Store a new, unconfirmed secret for a user
+///
+/// Replaces any earlier unconfirmed secret. Returns `false` without changing
+/// anything if the user already has TOTP enabled.
+pub async fn start_totp_enrollment(db: &NexoDB, user_id: i32, secret: &str) -> Result<bool, DbError> {
+    let sql = "INSERT INTO user_totp (user_id, secret, enabled, created_at) VALUES (?, ?, 0, ?)
+               ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, created_at = excluded.created_at
+               WHERE user_totp.enabled = 0";
+    let result = sqlx::query(sql)
+        .bind(user_id)
+        .bind(secret)
+        .bind(get_current_timestamp())
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Enable a pending enrollment and replace the user's recovery codes
+pub async fn confirm_totp_enrollment(
+    db: &NexoDB,
+    user_id: i32,
+    step: i64,
+    recovery_code_hashes: &[String],
+) -> Result<(), DbError> {
+    let mut tx = db.0.begin().await?;
+
+    sqlx::query("UPDATE user_totp SET enabled = 1, confirmed_at = ?, last_used_step = ? WHERE user_id = ?")
+        .bind(get_current_timestamp())
+        .bind(step)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for code_hash in recovery_code_hashes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Remove a user's second factor and recovery codes
+pub async fn disable_totp(db: &NexoDB, user_id: i32) -> Result<(), DbError> {
+    let mut tx = db.0.begin().await?;
+
+    sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Record that a code from `step` was accepted
+///
+/// Returns `false` if a code from this step or a later one was already used,
+/// which means the code is a replay and must be rejected.
+pub async fn record_totp_step(db: &NexoDB, user_id: i32, step: i64) -> Result<bool, DbError> {
+    let sql = "UPDATE user_totp SET last_used_step = ?
+               WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)";
+    let result = sqlx::query(sql)
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Mark a recovery code as used. Returns `false` if it doesn't exist or was
+/// already used.
+pub async fn consume_recovery_code(db: &NexoDB, user_id: i32, code_hash: &str) -> Result<bool, DbError> {
+    let sql = "UPDATE recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL";
+    let result = sqlx::query(sql)
+        .bind(get_current_timestamp())
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Number of recovery codes a user has not used yet
+pub async fn count_unused_recovery_codes(db: &NexoDB, user_id: i32) -> Result<i64, DbError> {
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM recovery_codes WHERE user_id = ? AND used_at IS NULL")
+        .bind(user_id)
+        .fetch_one(&db.0)
+        .await?;
+
+    Ok(row.get("count"))
+}
+
+/// Start a login challenge for a user whose password checked out
+///
+/// Returns the raw token for the challenge cookie; only its hash is stored.
+pub async fn create_login_challenge(db: &NexoDB, user_id: i32, expires_in_seconds: i64) -> Result<String, DbError> {
+    let token = generate_session_token();
+
+    sqlx::query("INSERT INTO login_challenges (token_hash, user_id, expires_at) VALUES (?, ?, ?)")
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(get_current_timestamp() + expires_in_seconds)
+        .execute(&db.0)
+        .await?;
+
+    Ok(token)
+}
+
+/// Get the user behind an unexpired login challenge
+pub async fn get_login_challenge_user(db: &NexoDB, token: &str) -> Result<Option<i32>, DbError> {
+    let row = sqlx::query("SELECT user_id FROM login_challenges WHERE token_hash = ? AND expires_at > ?")
+        .bind(hash_token(token))
+        .bind(get_current_timestamp())
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| row.get("user_id")))
+}
+
+/// Count a wrong code against a challenge, deleting the challenge once it
+/// reaches `max_attempts`. Returns `true` if the challenge is still usable.
+pub async fn record_login_challenge_failure(db: &NexoDB, token: &str, max_attempts: i64) -> Result<bool, DbError> {
+    let token_hash = hash_token(token);
+
+    sqlx::query("UPDATE login_challenges SET failed_attempts = failed_attempts + 1 WHERE token_hash = ?")
+        .bind(&token_hash)
+        .execute(&db.0)
+        .await?;
+    let result = sqlx::query("DELETE FROM login_challenges WHERE token_hash = ? AND failed_attempts >= ?")
+        .bind(&token_hash)
+        .bind(max_attempts)
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected() == 0)
+}
+
+/// Delete a login challenge once it has been completed
+pub async fn delete_login_challenge(db: &NexoDB, token: &str) -> Result<u64, DbError> {
+    let result = sqlx::query("DELETE FROM login_challenges WHERE token_hash = ?")
+        .bind(hash_token(token))
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Delete challenges that expired before their second step was completed
+pub async fn cleanup_expired_login_challenges(db: &NexoDB) -> Result<u64, DbError> {
+    let result = sqlx::query("DELETE FROM login_challenges WHERE expires_at <= ?")
+        .bind(get_current_timestamp())
+        .execute(&db.0)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{open_test_db, close_test_db, create_test_user};
+
+    #[test]
+    fn test_enrollment_and_replay_protection() {
+        rocket::async_test(async {
+            let db_path = "test_totp_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+
+            assert!(!is_totp_enabled(&db, user_id).await.unwrap());
+            assert!(start_totp_enrollment(&db, user_id, "SECRETONE").await.unwrap());
+            // Restarting an unconfirmed enrollment replaces the secret
+            assert!(start_totp_enrollment(&db, user_id, "SECRETTWO").await.unwrap());
+            assert!(!is_totp_enabled(&db, user_id).await.unwrap());
+
+            let hashes = vec![hash_token("code-one"), hash_token("code-two")];
+            confirm_totp_enrollment(&db, user_id, 100, &hashes).await.unwrap();
+
+            let totp = get_totp(&db, user_id).await.unwrap().unwrap();
+            assert!(totp.enabled);
+            assert_eq!(totp.secret, "SECRETTWO");
+            assert_eq!(totp.last_used_step, Some(100));
+
+            // Enabled secrets can't be swapped out by a new enrollment
+            assert!(!start_totp_enrollment(&db, user_id, "SECRETTHREE").await.unwrap());
+
+            // Steps only move forward
+            assert!(!record_totp_step(&db, user_id, 100).await.unwrap());
+            assert!(record_totp_step(&db, user_id, 101).await.unwrap());
+            assert!(!record_totp_step(&db, user_id, 99).await.unwrap());
+
+            // Recovery codes are single use
+            assert_eq!(count_unused_recovery_codes(&db, user_id).await.unwrap(), 2);
+            assert!(consume_recovery_code(&db, user_id, &hash_token("code-one")).await.unwrap());
+            assert!(!consume_recovery_code(&db, user_id, &hash_token("code-one")).await.unwrap());
+            assert!(!consume_recovery_code(&db, user_id, &hash_token("unknown")).await.unwrap());
+            assert_eq!(count_unused_recovery_codes(&db, user_id).await.unwrap(), 1);
+
+            disable_totp(&db, user_id).await.unwrap();
+            assert!(get_totp(&db, user_id).await.unwrap().is_none());
+            assert_eq!(count_unused_recovery_codes(&db, user_id).await.unwrap(), 0);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_login_challenge_lifecycle() {
+        rocket::async_test(async {
+            let db_path = "test_login_challenge_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+
+            let token = create_login_challenge(&db, user_id, 300).await.unwrap();
+            assert_eq!(get_login_challenge_user(&db, &token).await.unwrap(), Some(user_id));
+
+            // The challenge survives failures until the limit
+            assert!(record_login_challenge_failure(&db, &token, 2).await.unwrap());
+            assert!(!record_login_challenge_failure(&db, &token, 2).await.unwrap());
+            assert_eq!(get_login_challenge_user(&db, &token).await.unwrap(), None);
+
+            let expired = create_login_challenge(&db, user_id, -1).await.unwrap();
+            assert_eq!(get_login_challenge_user(&db, &expired).await.unwrap(), None);
+
+            let token = create_login_challenge(&db, user_id, 300).await.unwrap();
+            assert_eq!(cleanup_expired_login_challenges(&db).await.unwrap(), 1);
+            assert_eq!(delete_login_challenge(&db, &expired).await.unwrap(), 0);
+            assert_eq!(delete_login_challenge(&db, &token).await.unwrap(), 1);
+            assert_eq!(get_login_challenge_user(&db, &token).await.unwrap(), None);
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
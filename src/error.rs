@@ -0,0 +1,279 @@
+//! Errors from the JSON API, and the catchers for every error status
+//!
+//! `/api` routes fail with [`ApiError`], sent as an RFC 9457 problem details
+//! body whose `code` is stable for clients to match on. Failures that happen
+//! before a route runs, like a rejected guard or a path with no route, reach
+//! [`catchers`] instead, which answer the same way under `/api` and with an
+//! error page everywhere else.
+
+use std::fmt;
+use std::io::Cursor;
+
+use minijinja::context;
+use rocket::catcher::{BoxFuture, Catcher};
+use rocket::http::{ContentType, Status};
+use rocket::response::{Responder, Response};
+use rocket::Request;
+use rocket_db_pools::sqlx;
+use serde_json::json;
+
+use crate::database::DbError;
+use crate::database::migrations::MigrationError;
+use crate::templates::Template;
+use crate::validation::ValidationError;
+
+/// Why an `/api` request failed
+#[derive(Debug)]
+pub enum ApiError {
+    /// No valid session
+    Unauthorized,
+    /// Signed in, but without the permission the route needs
+    Forbidden,
+    /// There is no such thing, e.g. `"session"`
+    NotFound(&'static str),
+    /// The request clashes with the current state; `code` says how
+    Conflict { code: &'static str, detail: String },
+    /// Input that parsed but isn't acceptable; `code` says why
+    Invalid { code: &'static str, detail: String },
+    Validation(ValidationError),
+    /// 503 when the database is busy or unreachable, 500 otherwise
+    Database(DbError),
+    /// Anything else that broke on our side, described for the log only
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn conflict(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::Conflict { code, detail: detail.into() }
+    }
+
+    pub fn invalid(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::Invalid { code, detail: detail.into() }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::Unauthorized => Status::Unauthorized,
+            ApiError::Forbidden => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict { .. } => Status::Conflict,
+            ApiError::Invalid { .. } | ApiError::Validation(_) => Status::UnprocessableEntity,
+            ApiError::Database(e) if e.is_unavailable() => Status::ServiceUnavailable,
+            ApiError::Database(_) | ApiError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    /// The `code` member of the problem body
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Conflict { code, .. } | ApiError::Invalid { code, .. } => code,
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Database(e) if e.is_unavailable() => "database_unavailable",
+            ApiError::Database(_) => "database_error",
+            _ => status_code(self.status()),
+        }
+    }
+
+    /// What the client is told; internal details stay in the log
+    fn detail(&self) -> Option<String> {
+        match self {
+            ApiError::NotFound(what) => Some(format!("No such {}", what)),
+            ApiError::Conflict { detail, .. } | ApiError::Invalid { detail, .. } => Some(detail.clone()),
+            ApiError::Validation(e) => Some(e.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Database(e) => write!(f, "database error: {}", e),
+            ApiError::Internal(message) => f.write_str(message),
+            _ => write!(f, "{}: {}", self.code(), self.detail().unwrap_or_default()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::Database(e.into())
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+impl From<MigrationError> for ApiError {
+    fn from(e: MigrationError) -> Self {
+        match e {
+            MigrationError::Database(e) => ApiError::Database(e),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(e: ValidationError) -> Self {
+        ApiError::Validation(e)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        if self.status().class().is_server_error() {
+            eprintln!("{} {} failed: {}", request.method(), request.uri(), self);
+        }
+        Ok(problem(self.status(), self.code(), self.detail()))
+    }
+}
+
+/// The default `code` for an error status
+fn status_code(status: Status) -> &'static str {
+    match status.code {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        409 => "conflict",
+        413 => "payload_too_large",
+        422 => "unprocessable_entity",
+        429 => "too_many_requests",
+        500 => "internal_error",
+        503 => "service_unavailable",
+        _ => "error",
+    }
+}
+
+/// An `application/problem+json` response
+pub fn problem(status: Status, code: &str, detail: Option<String>) -> Response<'static> {
+    let mut body = json!({
+        "type": "about:blank",
+        "title": status.reason_lossy(),
+        "status": status.code,
+        "code": code,
+    });
+    if let Some(detail) = detail {
+        body["detail"] = detail.into();
+    }
+
+    let body = body.to_string();
+    Response::build()
+        .status(status)
+        .header(ContentType::new("application", "problem+json"))
+        .sized_body(body.len(), Cursor::new(body))
+        .finalize()
+}
+
+/// Whether a request expects data rather than a page
+pub fn is_api_request(request: &Request<'_>) -> bool {
+    request.uri().path().starts_with("/api/")
+}
+
+fn handle<'r>(status: Status, request: &'r Request<'_>) -> BoxFuture<'r> {
+    Box::pin(async move {
+        if is_api_request(request) {
+            return Ok(problem(status, status_code(status), None));
+        }
+
+        let message = match status.code {
+            400 => "error-page-bad-request",
+            403 => "error-page-forbidden",
+            404 => "error-page-not-found",
+            422 => "error-page-unprocessable",
+            429 => "error-page-too-many-requests",
+            503 => "error-page-unavailable",
+            _ => "error-page-internal",
+        };
+        let page = Template::render("error.html", context! { status => status.code, message });
+        Response::build_from(page.respond_to(request)?).status(status).ok()
+    })
+}
+
+/// Catchers for 400, 403, 404, 422, 429, 500 and 503; 401 has its own in
+/// [`crate::auth::unauthorized_catcher`]
+pub fn catchers() -> Vec<Catcher> {
+    [400, 403, 404, 422, 429, 500, 503].into_iter().map(|code| Catcher::new(code, handle)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    #[get("/api/sessions/<id>")]
+    fn session(id: i64) -> Result<&'static str, ApiError> {
+        match id {
+            1 => Err(ApiError::NotFound("session")),
+            2 => Err(ApiError::conflict("totp_already_enabled", "Two-factor authentication is already on")),
+            3 => Err(ApiError::Validation(ValidationError::PasswordTooShort)),
+            4 => Err(ApiError::Database(DbError::Other(sqlx::Error::RowNotFound))),
+            _ => Err(ApiError::Database(DbError::Busy(sqlx::Error::PoolTimedOut))),
+        }
+    }
+
+    #[get("/api/broken")]
+    fn broken() -> Status {
+        Status::BadRequest
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::build()
+            .mount("/", routes![session, broken])
+            .register("/", catchers())
+            .attach(crate::templates::stage());
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn json(response: rocket::local::blocking::LocalResponse<'_>) -> serde_json::Value {
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "problem+json")));
+        serde_json::from_str(&response.into_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_api_errors_are_problem_details() {
+        let client = client();
+        let cases = [
+            (1, 404, "not_found", Some("No such session")),
+            (2, 409, "totp_already_enabled", Some("Two-factor authentication is already on")),
+            (3, 422, "validation_failed", Some("Password must be at least 8 characters")),
+            // The database's own message stays in the log
+            (4, 500, "database_error", None),
+            (5, 503, "database_unavailable", None),
+        ];
+        for (id, status, code, detail) in cases {
+            let response = client.get(format!("/api/sessions/{}", id)).dispatch();
+            assert_eq!(response.status().code, status);
+            let body = json(response);
+            assert_eq!(body["status"], status);
+            assert_eq!(body["code"], code);
+            assert_eq!(body["detail"].as_str(), detail);
+        }
+    }
+
+    #[test]
+    fn test_catchers_answer_json_under_api() {
+        let client = client();
+        let body = json(client.get("/api/broken").dispatch());
+        assert_eq!(body, json!({ "type": "about:blank", "title": "Bad Request", "status": 400, "code": "bad_request" }));
+
+        let body = json(client.get("/api/nothing").dispatch());
+        assert_eq!(body["code"], "not_found");
+        // Route parameters that don't parse never reach a route
+        assert_eq!(json(client.get("/api/sessions/abc").dispatch())["code"], "unprocessable_entity");
+    }
+
+    #[test]
+    fn test_catchers_answer_html_elsewhere() {
+        let client = client();
+        let response = client.get("/nothing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert!(response.into_string().unwrap().contains("This page doesn&#x27;t exist."));
+    }
+}
@@ -0,0 +1,228 @@
+//! Certificate reloading and the plain HTTP redirect
+//!
+//! TLS itself is Rocket's, set up with the `tls` table in Rocket.toml.
+//! Rocket only reads the certificate at launch, so when the files change the
+//! watcher shuts the server down gracefully and `main` launches it again.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+use rocket::catcher::{BoxFuture, Catcher};
+use rocket::fairing::AdHoc;
+use rocket::figment::{self, Figment};
+use rocket::http::Status;
+use rocket::http::uri::Host;
+use rocket::response::{Redirect, Responder};
+use rocket::{Build, Phase, Request, Response, Rocket, Shutdown};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+
+use crate::config;
+
+/// Settings from the `https` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpsConfig {
+    /// How often the `tls` certificate and key files are checked for
+    /// changes; 0 turns checking off
+    pub certificate_check_seconds: u64,
+    /// Port that answers plain HTTP with a redirect to HTTPS. Off when unset.
+    pub redirect_port: Option<u16>,
+}
+
+impl Default for HttpsConfig {
+    fn default() -> Self {
+        HttpsConfig { certificate_check_seconds: 60, redirect_port: None }
+    }
+}
+
+/// Set when the server was shut down to pick up a new certificate
+#[derive(Debug, Clone, Default)]
+pub struct CertificateReload(Arc<AtomicBool>);
+
+/// Whether a server stopped to load a new certificate, and should be
+/// launched again
+pub fn reload_requested<P: Phase>(rocket: &Rocket<P>) -> bool {
+    rocket.state::<CertificateReload>().is_some_and(|reload| reload.0.load(Ordering::SeqCst))
+}
+
+/// When a file was last modified and how big it is, or `None` if it can't
+/// be read
+fn fingerprint(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn invalid(path: &Path, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+}
+
+/// Check that the files hold PEM certificates and a private key, so a
+/// renewal that is still being written doesn't take the server down
+fn check_certificate(certs: &Path, key: &Path) -> io::Result<()> {
+    let chain = rustls_pemfile::certs(&mut BufReader::new(File::open(certs)?))?;
+    if chain.is_empty() {
+        return Err(invalid(certs, "no certificates found"));
+    }
+
+    let items = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?;
+    if !items.iter().any(|item| matches!(item, Item::RSAKey(_) | Item::PKCS8Key(_) | Item::ECKey(_))) {
+        return Err(invalid(key, "no private key found"));
+    }
+    Ok(())
+}
+
+/// Poll the certificate and key until they change into a usable pair, then
+/// request a reload and shut the server down
+async fn watch(certs: PathBuf, key: PathBuf, interval: Duration, reload: CertificateReload, mut shutdown: Shutdown) {
+    let mut seen = (fingerprint(&certs), fingerprint(&key));
+    loop {
+        rocket::tokio::select! {
+            _ = rocket::tokio::time::sleep(interval) => {}
+            _ = &mut shutdown => return,
+        }
+
+        let current = (fingerprint(&certs), fingerprint(&key));
+        if current == seen {
+            continue;
+        }
+        seen = current;
+
+        match check_certificate(&certs, &key) {
+            Ok(()) => {
+                println!("TLS certificate changed, restarting to load it");
+                reload.0.store(true, Ordering::SeqCst);
+                shutdown.notify();
+                return;
+            }
+            // Checked again on the next change
+            Err(e) => eprintln!("Not loading changed TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Fairing that watches the `tls` certificate files once the server is up
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("HTTPS", |rocket| async {
+        let config = match config::extract::<HttpsConfig>(rocket.figment(), "https") {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid [https] configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let reload = CertificateReload::default();
+        let rocket = rocket.manage(reload.clone());
+        if config.certificate_check_seconds == 0 {
+            return Ok(rocket);
+        }
+
+        Ok(rocket.attach(AdHoc::on_liftoff("Certificate Watcher", move |rocket| Box::pin(async move {
+            let Some(tls) = rocket.config().tls.as_ref() else {
+                return;
+            };
+            // Certificates given inline in the configuration can't change
+            let (Some(certs), Some(key)) = (tls.certs().left(), tls.key().left()) else {
+                return;
+            };
+            let interval = Duration::from_secs(config.certificate_check_seconds);
+            rocket::tokio::spawn(watch(certs, key, interval, reload, rocket.shutdown()));
+        }))))
+    })
+}
+
+/// Port the redirect server sends browsers to
+struct HttpsPort(u16);
+
+/// The HTTPS URL for a request that came in over plain HTTP
+fn https_url(host: &str, port: u16, path: &str) -> String {
+    if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    }
+}
+
+fn redirect_to_https<'r>(_status: Status, request: &'r Request<'_>) -> BoxFuture<'r> {
+    Box::pin(async move {
+        let Some(host) = request.headers().get_one("Host").and_then(|host| Host::parse(host).ok()) else {
+            return Response::build().status(Status::BadRequest).ok();
+        };
+        let port = request.rocket().state::<HttpsPort>().map_or(443, |port| port.0);
+
+        Redirect::permanent(https_url(host.domain().as_str(), port, &request.uri().to_string())).respond_to(request)
+    })
+}
+
+/// A server that answers every request on `redirect_port` with a redirect
+/// to the same URL over HTTPS, or `None` when no port is configured
+///
+/// It has no routes: every request falls through to a catcher that does the
+/// redirect.
+pub fn redirect_server(figment: &Figment) -> Result<Option<Rocket<Build>>, Box<figment::Error>> {
+    let config = config::extract::<HttpsConfig>(figment, "https")?;
+    let Some(port) = config.redirect_port else {
+        return Ok(None);
+    };
+
+    let server: rocket::Config = figment.extract()?;
+    let https_port = server.port;
+    let redirect = rocket::Config { port, tls: None, ..server };
+
+    Ok(Some(
+        rocket::custom(redirect)
+            .manage(HttpsPort(https_port))
+            .register("/", vec![Catcher::new(None, redirect_to_https)]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    #[test]
+    fn test_redirects_to_https() {
+        let figment = rocket::Config::figment().merge(("port", 8443)).merge(("https.redirect_port", 8080));
+        let client = Client::tracked(redirect_server(&figment).unwrap().unwrap()).unwrap();
+
+        let response = client.get("/reset-password/abc?x=1").header(Header::new("Host", "nexo.example.com:8080")).dispatch();
+        assert_eq!(response.status(), Status::PermanentRedirect);
+        assert_eq!(response.headers().get_one("Location"), Some("https://nexo.example.com:8443/reset-password/abc?x=1"));
+
+        let response = client.post("/login").header(Header::new("Host", "nexo.example.com")).dispatch();
+        assert_eq!(response.status(), Status::PermanentRedirect);
+    }
+
+    #[test]
+    fn test_no_redirect_server_by_default() {
+        assert!(redirect_server(&rocket::Config::figment()).unwrap().is_none());
+        assert_eq!(https_url("nexo.example.com", 443, "/"), "https://nexo.example.com/");
+    }
+
+    #[test]
+    fn test_half_written_certificate_is_rejected() {
+        let certs = "test_https_certs.pem";
+        let key = "test_https_key.pem";
+        let pem = |label: &str| format!("-----BEGIN {0}-----\nMIIB\n-----END {0}-----\n", label);
+
+        std::fs::write(certs, pem("CERTIFICATE")).unwrap();
+        std::fs::write(key, pem("PRIVATE KEY")).unwrap();
+        assert!(check_certificate(Path::new(certs), Path::new(key)).is_ok());
+
+        std::fs::write(certs, "-----BEGIN CERTIFICATE-----\nMII").unwrap();
+        assert!(check_certificate(Path::new(certs), Path::new(key)).is_err());
+        std::fs::write(certs, pem("CERTIFICATE")).unwrap();
+        std::fs::write(key, "").unwrap();
+        assert!(check_certificate(Path::new(certs), Path::new(key)).is_err());
+
+        std::fs::remove_file(certs).unwrap();
+        std::fs::remove_file(key).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+//! Translations and locale-aware formatting
+//!
+//! Messages live in Fluent catalogs under `locales/`, one per [`Locale`],
+//! compiled into the binary. Each request is served in the signed-in user's
+//! chosen locale, else the best match for `Accept-Language`, else the
+//! `default_locale` from the `i18n` table in Rocket.toml. Templates translate
+//! with `{{ t("message-id", name=value) }}` and format values with the
+//! `number`, `brl`, `date` and `datetime` filters; Rust code uses
+//! [`translate`] and the `format_*` functions.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::LazyLock;
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource, FluentValue};
+use minijinja::value::{Kwargs, Value, ValueKind};
+use minijinja::{Environment, State as TemplateState};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::time::OffsetDateTime;
+use rocket::Request;
+use rocket_db_pools::Database;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthenticatedUser;
+use crate::config;
+use crate::csrf::CsrfVerified;
+use crate::error::ApiError;
+use crate::database::{get_user_locale, set_user_locale, NexoDB};
+
+/// A language Nexo has a catalog for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Locale {
+    #[default]
+    #[serde(rename = "en-US")]
+    EnUs,
+    #[serde(rename = "pt-BR")]
+    PtBr,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::EnUs, Locale::PtBr];
+
+    /// The BCP 47 tag, e.g. `pt-BR`
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::EnUs => "en-US",
+            Locale::PtBr => "pt-BR",
+        }
+    }
+
+    /// The locale for a language tag. A tag for another region of a
+    /// supported language, e.g. `pt-PT` or plain `en`, gets that language.
+    pub fn parse(tag: &str) -> Option<Locale> {
+        let tag = tag.trim();
+        if let Some(locale) = Locale::ALL.into_iter().find(|l| l.tag().eq_ignore_ascii_case(tag)) {
+            return Some(locale);
+        }
+        let language = tag.split(['-', '_']).next()?;
+        Locale::ALL.into_iter().find(|l| l.tag().split('-').next().is_some_and(|own| own.eq_ignore_ascii_case(language)))
+    }
+
+    fn catalog(self) -> &'static str {
+        match self {
+            Locale::EnUs => include_str!("../locales/en-US.ftl"),
+            Locale::PtBr => include_str!("../locales/pt-BR.ftl"),
+        }
+    }
+
+    /// Thousands and decimal separators
+    fn separators(self) -> (char, char) {
+        match self {
+            Locale::EnUs => (',', '.'),
+            Locale::PtBr => ('.', ','),
+        }
+    }
+
+    /// The locale resolved for this request by the guard, or else from
+    /// `Accept-Language` alone
+    pub fn of(request: &Request<'_>) -> Locale {
+        *request.local_cache(|| from_headers(request))
+    }
+}
+
+/// The best supported locale for an `Accept-Language` header
+pub fn negotiate(accept_language: &str) -> Option<Locale> {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equal weights keep the browser's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().find_map(|(tag, _)| Locale::parse(tag))
+}
+
+/// Settings from the `i18n` table in Rocket.toml
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct I18nConfig {
+    /// For requests whose `Accept-Language` matches no catalog
+    pub default_locale: Locale,
+}
+
+fn from_headers(request: &Request<'_>) -> Locale {
+    request
+        .headers()
+        .get_one("Accept-Language")
+        .and_then(negotiate)
+        .unwrap_or_else(|| request.rocket().state::<I18nConfig>().map(|c| c.default_locale).unwrap_or_default())
+}
+
+async fn resolve(request: &Request<'_>) -> Locale {
+    if let (Some(user), Some(db)) = (request.guard::<AuthenticatedUser>().await.succeeded(), NexoDB::fetch(request.rocket())) {
+        match get_user_locale(db, user.id).await {
+            Ok(Some(tag)) => match Locale::parse(&tag) {
+                Some(locale) => return locale,
+                None => eprintln!("User {} has unknown locale {}", user.id, tag),
+            },
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to load user locale: {:?}", e),
+        }
+    }
+    from_headers(request)
+}
+
+/// The locale to answer in, taking the signed-in user's preference into
+/// account; later [`Locale::of`] calls in the same request agree with it
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Locale {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(*request.local_cache_async(resolve(request)).await)
+    }
+}
+
+type Bundle = FluentBundle<FluentResource>;
+
+fn bundle(locale: Locale) -> Result<Bundle, String> {
+    let resource = FluentResource::try_new(locale.catalog().to_string())
+        .map_err(|(_, errors)| format!("{} catalog: {:?}", locale.tag(), errors))?;
+    let language = locale.tag().parse().map_err(|e| format!("{}: {}", locale.tag(), e))?;
+    let mut bundle = FluentBundle::new_concurrent(vec![language]);
+    // Isolation marks show up as stray characters in titles and attributes
+    bundle.set_use_isolating(false);
+    bundle
+        .add_resource(resource)
+        .map_err(|errors| format!("{} catalog: {:?}", locale.tag(), errors))?;
+    Ok(bundle)
+}
+
+static BUNDLES: LazyLock<Result<HashMap<Locale, Bundle>, String>> =
+    LazyLock::new(|| Locale::ALL.into_iter().map(|locale| Ok((locale, bundle(locale)?))).collect());
+
+/// The message `id` in `locale`, or the id itself if the catalog lacks it
+pub fn translate(locale: Locale, id: &str, args: Option<&FluentArgs>) -> String {
+    let found = BUNDLES.as_ref().ok().and_then(|bundles| {
+        let bundle = bundles.get(&locale)?;
+        Some((bundle, bundle.get_message(id)?.value()?))
+    });
+    let Some((bundle, pattern)) = found else {
+        eprintln!("Missing {} translation for {}", locale.tag(), id);
+        return id.to_string();
+    };
+
+    let mut errors = Vec::new();
+    let message = bundle.format_pattern(pattern, args, &mut errors);
+    if !errors.is_empty() {
+        eprintln!("Failed to format {} translation of {}: {:?}", locale.tag(), id, errors);
+    }
+    message.into_owned()
+}
+
+/// Group the digits of a non-negative integer, e.g. `1234567` → `1.234.567`
+fn group_digits(digits: &str, separator: char) -> String {
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// A number with `decimals` digits after the point, e.g. `1,234.5` or
+/// `1.234,5`
+pub fn format_number(locale: Locale, value: f64, decimals: usize) -> String {
+    let (thousands, decimal) = locale.separators();
+    let formatted = format!("{:.*}", decimals, value.abs());
+    let (integer, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+
+    let mut result = String::new();
+    if value < 0.0 && formatted.chars().any(|c| matches!(c, '1'..='9')) {
+        result.push('-');
+    }
+    result.push_str(&group_digits(integer, thousands));
+    if !fraction.is_empty() {
+        result.push(decimal);
+        result.push_str(fraction);
+    }
+    result
+}
+
+/// An amount of Brazilian reais given in centavos, e.g. `R$ 1.234,56`
+/// (with a non-breaking space) or `R$1,234.56`
+pub fn format_brl(locale: Locale, cents: i64) -> String {
+    let (thousands, decimal) = locale.separators();
+    let sign = if cents < 0 { "-" } else { "" };
+    let amount = cents.unsigned_abs();
+    let reais = group_digits(&(amount / 100).to_string(), thousands);
+    let symbol = match locale {
+        Locale::EnUs => "R$",
+        Locale::PtBr => "R$\u{a0}",
+    };
+    format!("{}{}{}{}{:02}", sign, symbol, reais, decimal, amount % 100)
+}
+
+/// A Unix timestamp as a UTC date and time, or `None` if out of range
+fn datetime(timestamp: i64) -> Option<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp(timestamp).ok()
+}
+
+/// The UTC date of a Unix timestamp, e.g. `10/18/2026` or `18/10/2026`
+pub fn format_date(locale: Locale, timestamp: i64) -> String {
+    let Some(date) = datetime(timestamp) else {
+        return timestamp.to_string();
+    };
+    let (year, month, day) = (date.year(), date.month() as u8, date.day());
+    match locale {
+        Locale::EnUs => format!("{:02}/{:02}/{}", month, day, year),
+        Locale::PtBr => format!("{:02}/{:02}/{}", day, month, year),
+    }
+}
+
+/// The UTC date and time of a Unix timestamp, e.g. `10/18/2026, 3:04 PM`
+/// or `18/10/2026 15:04`
+pub fn format_datetime(locale: Locale, timestamp: i64) -> String {
+    let Some(time) = datetime(timestamp) else {
+        return timestamp.to_string();
+    };
+    let date = format_date(locale, timestamp);
+    let (hour, minute) = (time.hour(), time.minute());
+    match locale {
+        Locale::EnUs => {
+            let period = if hour < 12 { "AM" } else { "PM" };
+            let hour = match hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            format!("{}, {}:{:02} {}", date, hour, minute, period)
+        }
+        Locale::PtBr => format!("{} {:02}:{:02}", date, hour, minute),
+    }
+}
+
+/// The locale a template is being rendered in, from its `locale` variable
+fn template_locale(state: &TemplateState) -> Locale {
+    state.lookup("locale").and_then(|tag| tag.as_str().and_then(Locale::parse)).unwrap_or_default()
+}
+
+fn fluent_value(value: Value) -> FluentValue<'static> {
+    match value.kind() {
+        ValueKind::Number => match value.as_i64() {
+            Some(number) => FluentValue::from(number),
+            None => FluentValue::from(f64::try_from(value).unwrap_or(f64::NAN)),
+        },
+        _ => FluentValue::from(value.to_string()),
+    }
+}
+
+/// Add `t` and the formatting filters to a template environment
+pub fn register(environment: &mut Environment<'static>) {
+    environment.add_function("t", |state: &TemplateState, id: &str, kwargs: Kwargs| -> Result<String, minijinja::Error> {
+        let mut args = FluentArgs::new();
+        for name in kwargs.args() {
+            args.set(name.to_string(), fluent_value(kwargs.get::<Value>(name)?));
+        }
+        Ok(translate(template_locale(state), id, Some(&args)))
+    });
+    environment.add_filter("number", |state: &TemplateState, value: f64, decimals: Option<usize>| {
+        format_number(template_locale(state), value, decimals.unwrap_or(0))
+    });
+    environment.add_filter("brl", |state: &TemplateState, cents: i64| format_brl(template_locale(state), cents));
+    environment.add_filter("date", |state: &TemplateState, timestamp: i64| format_date(template_locale(state), timestamp));
+    environment.add_filter("datetime", |state: &TemplateState, timestamp: i64| {
+        format_datetime(template_locale(state), timestamp)
+    });
+}
+
+/// Fairing that checks the catalogs and reads the `i18n` table
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Internationalization", |rocket| async {
+        if let Err(e) = BUNDLES.as_ref() {
+            eprintln!("Invalid translation catalog: {}", e);
+            return Err(rocket);
+        }
+        match config::extract::<I18nConfig>(rocket.figment(), "i18n") {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(e) => {
+                eprintln!("Invalid [i18n] configuration: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[derive(Deserialize)]
+pub struct LocalePreference {
+    /// `null` goes back to following the browser
+    locale: Option<Locale>,
+}
+
+/// Set the signed-in user's locale
+#[put("/user/locale", data = "<preference>")]
+pub async fn set_preference(
+    preference: Json<LocalePreference>,
+    user: AuthenticatedUser,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<Status, ApiError> {
+    set_user_locale(db, user.id, preference.locale.map(Locale::tag)).await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The message ids a catalog defines
+    fn ids(catalog: &str) -> Vec<&str> {
+        let mut ids: Vec<&str> = catalog
+            .lines()
+            .filter(|line| line.starts_with(|c: char| c.is_ascii_lowercase()))
+            .filter_map(|line| line.split_once(" =").map(|(id, _)| id))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_catalogs_parse_and_match() {
+        assert!(BUNDLES.is_ok(), "{:?}", BUNDLES.as_ref().err());
+        let english = ids(Locale::EnUs.catalog());
+        assert!(english.contains(&"error-invalid-credentials"));
+        for locale in Locale::ALL {
+            assert_eq!(ids(locale.catalog()), english, "{} has different messages", locale.tag());
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate(Locale::EnUs, "error-invalid-credentials", None), "Invalid username or password");
+        assert_eq!(translate(Locale::PtBr, "error-invalid-credentials", None), "Usuário ou senha inválidos");
+        assert_eq!(translate(Locale::PtBr, "no-such-message", None), "no-such-message");
+
+        let mut args = FluentArgs::new();
+        args.set("unit", "minute");
+        args.set("count", 5);
+        assert_eq!(translate(Locale::PtBr, "throttle-locked", Some(&args)),
+                   "Muitas tentativas de login sem sucesso. O login está bloqueado por 5 minutos.");
+        let mut args = FluentArgs::new();
+        args.set("unit", "second");
+        args.set("count", 1);
+        assert_eq!(translate(Locale::EnUs, "throttle-delayed", Some(&args)),
+                   "Too many failed login attempts. Please wait a second and try again.");
+
+        let mut args = FluentArgs::new();
+        args.set("name", "ana");
+        args.set("minutes", 30);
+        args.set("link", "https://nexo.example/reset-password/abc");
+        assert!(translate(Locale::PtBr, "reset-email-body", Some(&args)).starts_with(
+            "Olá, ana,\n\nAlguém pediu para redefinir a senha da sua conta do Nexo. Se foi você, abra este link em até 30 minutos:\n\n\
+             https://nexo.example/reset-password/abc\n\n"));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("pt-BR,pt;q=0.9,en-US;q=0.8"), Some(Locale::PtBr));
+        assert_eq!(negotiate("en-GB, pt-BR;q=0.5"), Some(Locale::EnUs));
+        assert_eq!(negotiate("fr-FR, pt;q=0.3, en;q=0.7"), Some(Locale::EnUs));
+        assert_eq!(negotiate("en;q=0, pt-PT"), Some(Locale::PtBr));
+        assert_eq!(negotiate("fr, de;q=0.5, *;q=0.1"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_format_numbers() {
+        assert_eq!(format_number(Locale::EnUs, 1234567.891, 2), "1,234,567.89");
+        assert_eq!(format_number(Locale::PtBr, 1234567.891, 2), "1.234.567,89");
+        assert_eq!(format_number(Locale::PtBr, -999.0, 0), "-999");
+        assert_eq!(format_number(Locale::EnUs, -0.001, 2), "0.00");
+
+        assert_eq!(format_brl(Locale::PtBr, 123456), "R$\u{a0}1.234,56");
+        assert_eq!(format_brl(Locale::EnUs, 123456), "R$1,234.56");
+        assert_eq!(format_brl(Locale::PtBr, -5), "-R$\u{a0}0,05");
+        assert_eq!(format_brl(Locale::EnUs, 100_000_000), "R$1,000,000.00");
+    }
+
+    #[test]
+    fn test_format_dates() {
+        // 2026-10-18 15:04:05 UTC
+        let timestamp = 1792335845;
+        assert_eq!(format_date(Locale::EnUs, timestamp), "10/18/2026");
+        assert_eq!(format_date(Locale::PtBr, timestamp), "18/10/2026");
+        assert_eq!(format_datetime(Locale::EnUs, timestamp), "10/18/2026, 3:04 PM");
+        assert_eq!(format_datetime(Locale::PtBr, timestamp), "18/10/2026 15:04");
+        assert_eq!(format_datetime(Locale::EnUs, 1792281600), "10/18/2026, 12:00 AM");
+    }
+}
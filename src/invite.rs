@@ -0,0 +1,138 @@
+use minijinja::context;
+use rocket::http::{CookieJar, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::cookies::CookieConfig;
+use crate::crypto::{get_current_timestamp, hash_password, PasswordHashConfig};
+use crate::database::{NexoDB, SessionClient};
+use crate::database::invitations::{
+    accept_invitation, create_invitation, find_pending_invitation, list_invitations, revoke_invitation,
+};
+use crate::database::roles::role_exists;
+use crate::auth::{Authorized, ManageInvitations};
+use crate::csrf::{CsrfForm, CsrfVerified};
+use crate::error::ApiError;
+use crate::login::{db_error_fragment, error_fragment, start_session, HxRedirectWithCookie};
+use crate::sessions::SessionConfig;
+use crate::templates::{Page, Template};
+use crate::validation::{validate_password, validate_username, ValidationError};
+
+/// Invitation lifetime when the request doesn't specify one
+const DEFAULT_EXPIRY_HOURS: i64 = 72;
+/// Longest an invitation may stay valid
+const MAX_EXPIRY_HOURS: i64 = 30 * 24;
+
+#[derive(Deserialize)]
+pub struct NewInvitation {
+    expires_in_hours: Option<i64>,
+    role: Option<String>,
+}
+
+/// Create an invitation. The response contains the only copy of the token.
+#[post("/invitations", data = "<request>")]
+pub async fn create(
+    request: Json<NewInvitation>,
+    user: Authorized<ManageInvitations>,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<(Status, Json<serde_json::Value>), ApiError> {
+    let hours = request.expires_in_hours.unwrap_or(DEFAULT_EXPIRY_HOURS);
+    if !(1..=MAX_EXPIRY_HOURS).contains(&hours) {
+        let detail = format!("Invitations expire after 1 to {} hours", MAX_EXPIRY_HOURS);
+        return Err(ApiError::invalid("invalid_expiry", detail));
+    }
+    if let Some(role) = request.role.as_deref()
+        && !role_exists(db, role).await?
+    {
+        return Err(ApiError::invalid("unknown_role", format!("No role is named {}", role)));
+    }
+
+    let (invitation, token) = create_invitation(db, user.id, request.role.as_deref(), hours * 3600).await?;
+    Ok((Status::Created, Json(json!({
+        "invitation": invitation,
+        "token": token,
+        "url": format!("/invite/{}", token)
+    }))))
+}
+
+#[get("/invitations")]
+pub async fn list(_user: Authorized<ManageInvitations>, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let now = get_current_timestamp();
+    let invitations = list_invitations(db).await?;
+    let invitations: Vec<serde_json::Value> = invitations
+        .iter()
+        .map(|invitation| json!({ "status": invitation.status(now), "invitation": invitation }))
+        .collect();
+
+    Ok(Json(json!({ "invitations": invitations })))
+}
+
+#[delete("/invitations/<id>")]
+pub async fn revoke(id: i64, _user: Authorized<ManageInvitations>, _csrf: CsrfVerified, db: &NexoDB) -> Result<Status, ApiError> {
+    match revoke_invitation(db, id).await? {
+        true => Ok(Status::NoContent),
+        false => Err(ApiError::NotFound("invitation")),
+    }
+}
+
+/// The sign-up page behind an invitation link
+#[get("/<token>")]
+pub async fn page(token: &str, page: Page, db: &NexoDB) -> Result<Template, Status> {
+    match find_pending_invitation(db, token).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("Failed to look up invitation: {:?}", e);
+            return Err(if e.is_unavailable() { Status::ServiceUnavailable } else { Status::InternalServerError });
+        }
+    }
+
+    Ok(page.render("invite.html", context! { token }))
+}
+
+#[derive(FromForm)]
+pub struct AcceptForm {
+    username: String,
+    password: String,
+    password_confirm: String,
+}
+
+/// Create the invited user's account and log them in
+#[post("/<token>", data = "<form>")]
+#[allow(clippy::too_many_arguments)] // one per request guard
+pub async fn accept(
+    token: &str,
+    form: CsrfForm<AcceptForm>,
+    db: &NexoDB,
+    cookies: &CookieJar<'_>,
+    client: SessionClient,
+    session_config: &State<SessionConfig>,
+    cookie_config: &State<CookieConfig>,
+    hash_config: &State<PasswordHashConfig>,
+) -> Result<HxRedirectWithCookie, Template> {
+    let username = validate_username(&form.username).map_err(|e| error_fragment(e.message_id()))?;
+    validate_password(&form.password, &username).map_err(|e| error_fragment(e.message_id()))?;
+    if form.password != form.password_confirm {
+        return Err(error_fragment(ValidationError::PasswordMismatch.message_id()));
+    }
+
+    let psw_hash = hash_password(hash_config, &form.password).map_err(|e| {
+        eprintln!("Failed to hash password: {}", e);
+        error_fragment("error-create-account-failed")
+    })?;
+
+    match accept_invitation(db, token, &username, &psw_hash).await {
+        Ok(Some((user_id, _invitation))) => start_session(db, cookies, &client, session_config, cookie_config, user_id).await,
+        Ok(None) => Err(error_fragment("error-invitation-invalid")),
+        Err(e) if e.is_unique_violation() => {
+            Err(error_fragment("error-username-taken"))
+        }
+        Err(e) => {
+            eprintln!("Failed to accept invitation: {:?}", e);
+            Err(db_error_fragment(&e, "error-create-account-failed"))
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Nexo's web server and the `nexo-admin` tool share everything in here.
+
+#[macro_use] extern crate rocket;
+
+pub mod assets;
+pub mod auth;
+pub mod login;
+pub mod crypto;
+pub mod csrf;
+pub mod database;
+pub mod error;
+pub mod config;
+pub mod cookies;
+pub mod totp;
+pub mod register;
+pub mod scheduler;
+pub mod invite;
+pub mod https;
+pub mod i18n;
+pub mod mail;
+pub mod maintenance;
+pub mod password_reset;
+pub mod roles;
+pub mod security_headers;
+pub mod sessions;
+pub mod setup;
+pub mod templates;
+pub mod throttle;
+pub mod validation;
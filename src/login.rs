@@ -1,11 +1,20 @@
-use rocket::response::content::RawHtml;
-use rocket::form::Form;
-use crate::crypto::hash_password;
-use crate::database::{NexoDB, get_password_hash_from_username as get_psw, ensure_db_initialized, 
-                     get_user_id_by_username, create_session, validate_session, get_username_by_id, delete_session};
-use rocket::http::{Status, Cookie, CookieJar};
-use rocket::response::{Responder, Response};
-use rocket::Request;
+use minijinja::context;
+use crate::auth::AuthenticatedUser;
+use crate::cookies::{CookieConfig, SESSION_COOKIE};
+use crate::crypto::{hash_password, verify_password, PasswordHashConfig, PasswordVerification};
+use crate::csrf::{CsrfForm, TokenOnly};
+use crate::database::{NexoDB, DbError, get_password_hash_from_username,
+                     get_user_id_by_username, create_session, delete_session,
+                     update_password_hash, SessionClient};
+use crate::i18n::Locale;
+use crate::database::totp::{create_login_challenge, is_totp_enabled};
+use crate::totp::{challenge_fragment, LOGIN_CHALLENGE_COOKIE, LOGIN_CHALLENGE_PATH, LOGIN_CHALLENGE_SECONDS};
+use crate::sessions::SessionConfig;
+use crate::templates::{Page, Template};
+use crate::throttle::{self, ThrottleConfig};
+use rocket::http::{Header, Status, CookieJar};
+use rocket::response::{Flash, Redirect, Responder, Response};
+use rocket::{Request, State};
 
 #[derive(FromForm)]
 pub struct LoginForm {
@@ -14,36 +23,8 @@ pub struct LoginForm {
 }
 
 #[get("/")]
-pub async fn home(cookies: &CookieJar<'_>, db: &NexoDB) -> Result<rocket::fs::NamedFile, rocket::response::Redirect> {
-    // Check for session token instead of simple logged_in cookie
-    if let Some(session_cookie) = cookies.get("session_token") {
-        let token = session_cookie.value();
-        
-        // Validate the session token
-        if let Some(_user_id) = validate_session(db, token).await {
-            // Session is valid, serve the home page
-            Ok(rocket::fs::NamedFile::open("static/home.html")
-                .await
-                .expect("static/home.html not found"))
-        } else {
-            // Invalid or expired session, redirect to login
-            Err(rocket::response::Redirect::to("/"))
-        }
-    } else {
-        // No session token, redirect to login
-        Err(rocket::response::Redirect::to("/"))
-    }
-}
-
-pub struct HxRedirect(pub String);
-
-impl<'r> Responder<'r, 'static> for HxRedirect {
-    fn respond_to(self, _: &'r Request<'_>) -> rocket::response::Result<'static> {
-        Response::build()
-            .status(Status::Ok)
-            .raw_header("HX-Redirect", self.0)
-            .ok()
-    }
+pub async fn home(_user: AuthenticatedUser, page: Page) -> Template {
+    page.render("home.html", context! {})
 }
 
 pub struct HxRedirectWithCookie {
@@ -59,52 +40,157 @@ impl<'r> Responder<'r, 'static> for HxRedirectWithCookie {
     }
 }
 
+/// Why a login attempt didn't produce a session
+#[derive(Responder)]
+pub enum LoginFailure {
+    /// Wrong credentials, a pending second factor or a server error
+    Rejected(Template),
+    /// Too many recent failures for the account or address
+    #[response(status = 429)]
+    Throttled(Template, Header<'static>),
+    /// The database is busy or unreachable, so the credentials couldn't be
+    /// checked at all
+    #[response(status = 503)]
+    Unavailable(Template),
+}
+
+impl From<Template> for LoginFailure {
+    fn from(fragment: Template) -> Self {
+        LoginFailure::Rejected(fragment)
+    }
+}
+
+impl LoginFailure {
+    /// A failed database call: 503 when the database is busy or unreachable,
+    /// `message` otherwise
+    pub fn database(e: DbError, message: &str) -> Self {
+        eprintln!("Login failed: {}", e);
+        if e.is_unavailable() {
+            LoginFailure::Unavailable(error_fragment("error-database-busy"))
+        } else {
+            LoginFailure::Rejected(error_fragment(message))
+        }
+    }
+}
+
+impl From<DbError> for LoginFailure {
+    fn from(e: DbError) -> Self {
+        LoginFailure::database(e, "error-create-session-failed")
+    }
+}
+
+/// Red error message swapped into the page by HTMX; `message` is an id from
+/// the `locales/` catalogs
+pub fn error_fragment(message: &str) -> Template {
+    Template::render("partials/message.html", context! { kind => "error", message })
+}
+
+/// The error fragment for a failed database call that has no better message:
+/// asks the user to retry when the database is busy, `message` otherwise
+pub fn db_error_fragment(e: &DbError, message: &str) -> Template {
+    error_fragment(if e.is_unavailable() { "error-database-busy" } else { message })
+}
+
+/// Create a session for an authenticated user, set its cookie and send the
+/// browser to the home page
+pub async fn start_session(
+    db: &NexoDB,
+    cookies: &CookieJar<'_>,
+    client: &SessionClient,
+    config: &SessionConfig,
+    cookie_config: &CookieConfig,
+    user_id: i32,
+) -> Result<HxRedirectWithCookie, Template> {
+    match create_session(db, user_id, config.idle_timeout_seconds, config.absolute_lifetime_seconds, client).await {
+        Ok(session_token) => {
+            cookies.add(cookie_config.cookie(SESSION_COOKIE, session_token));
+
+            Ok(HxRedirectWithCookie { location: "/home".to_string() })
+        }
+        Err(e) => {
+            eprintln!("Failed to create session: {}", e);
+            Err(db_error_fragment(&e, "error-create-session-failed"))
+        }
+    }
+}
+
 #[post("/", data = "<form>")]
+#[allow(clippy::too_many_arguments)] // one per request guard
 pub async fn login(
-    form: Form<LoginForm>,
+    form: CsrfForm<LoginForm>,
     db: &NexoDB,
     cookies: &CookieJar<'_>,
-) -> Result<HxRedirectWithCookie, RawHtml<String>> {
-    let stored_hash = get_user_psw_from_db(db, form.username.clone()).await;
-    if validate_user_psw(stored_hash, form.password.clone(), "salt") {
-        // Get user ID for session creation
-        if let Some(user_id) = get_user_id_by_username(db, form.username.as_str()).await {
-            // Create session (24 hours = 86400 seconds)
-            if let Some(session_token) = create_session(db, user_id, 86400).await {
-                let mut cookie = Cookie::new("session_token", session_token);
-                cookie.set_path("/");
-                cookie.set_http_only(true); // Prevent XSS attacks
-                cookie.set_secure(false); // Set to true in production with HTTPS
-                cookies.add(cookie);
-                
-                Ok(HxRedirectWithCookie { location: "/home".to_string() })
-            } else {
-                Err(RawHtml(r#"
-                  <div class="text-red-600 text-center">
-                    Failed to create session. Please try again.
-                  </div>
-                "#.to_string()))
+    client: SessionClient,
+    hash_config: &State<PasswordHashConfig>,
+    throttle_config: &State<ThrottleConfig>,
+    session_config: &State<SessionConfig>,
+    cookie_config: &State<CookieConfig>,
+) -> Result<HxRedirectWithCookie, LoginFailure> {
+    // Counted as a failure before the password is checked, so a blocked
+    // attacker learns nothing, costs us no hashing and can't race guesses
+    // past the count
+    let ip = client.ip.as_deref();
+    if let Some(blocked) = throttle::reserve(db, throttle_config, &form.username, ip).await? {
+        return Err(LoginFailure::Throttled(blocked.fragment(), blocked.retry_after_header()));
+    }
+
+    let stored_hash = get_password_hash_from_username(db, &form.username).await?;
+    let verification = validate_user_psw(hash_config, stored_hash, form.password.as_str());
+    if verification.is_valid() {
+        if verification == PasswordVerification::ValidNeedsRehash {
+            // Failure is only logged: the old hash still verifies, so the
+            // upgrade is retried on the next login.
+            match hash_password(hash_config, &form.password) {
+                Ok(new_hash) => if let Err(e) = update_password_hash(db, &form.username, &new_hash).await {
+                    eprintln!("Failed to upgrade password hash: {}", e);
+                },
+                Err(e) => eprintln!("Failed to upgrade password hash: {}", e),
             }
-        } else {
-            Err(RawHtml(r#"
-              <div class="text-red-600 text-center">
-                User not found. Please try again.
-              </div>
-            "#.to_string()))
+        }
+
+        // Get user ID for session creation
+        match get_user_id_by_username(db, form.username.as_str()).await {
+            Ok(Some(user_id)) => match is_totp_enabled(db, user_id).await {
+                // Password is right but a code is still needed: park the
+                // login in a challenge and ask for the second factor, which
+                // is counted on its own
+                Ok(true) => {
+                    throttle::release(db, throttle_config, &form.username, ip).await;
+                    match create_login_challenge(db, user_id, LOGIN_CHALLENGE_SECONDS).await {
+                        Ok(challenge) => {
+                            let mut cookie = cookie_config.cookie_for_path(LOGIN_CHALLENGE_COOKIE, challenge, LOGIN_CHALLENGE_PATH);
+                            cookie.set_max_age(rocket::time::Duration::seconds(LOGIN_CHALLENGE_SECONDS));
+                            cookies.add(cookie);
+
+                            Err(challenge_fragment().into())
+                        }
+                        Err(e) => Err(e.into()),
+                    }
+                }
+                Ok(false) => {
+                    let session = start_session(db, cookies, &client, session_config, cookie_config, user_id).await?;
+                    throttle::record_success(db, throttle_config, &form.username, ip).await;
+                    Ok(session)
+                }
+                Err(e) => Err(e.into()),
+            },
+            Ok(None) => Err(error_fragment("error-user-not-found").into()),
+            Err(e) => Err(e.into()),
         }
     } else {
-        Err(RawHtml(r#"
-          <div class="text-red-600 text-center">
-            Invalid username or password
-          </div>
-        "#.to_string()))
+        Err(error_fragment("error-invalid-credentials").into())
     }
 }
 
-#[post("/logout")]
-pub async fn logout(cookies: &CookieJar<'_>, db: &NexoDB) -> rocket::response::Redirect {
+#[post("/logout", data = "<_form>")]
+pub async fn logout(
+    _form: CsrfForm<TokenOnly>,
+    cookies: &CookieJar<'_>,
+    cookie_config: &State<CookieConfig>,
+    db: &NexoDB,
+) -> Flash<Redirect> {
     // Get the session token from the cookie
-    if let Some(session_cookie) = cookies.get("session_token") {
+    if let Some(session_cookie) = cookies.get(&cookie_config.name(SESSION_COOKIE)) {
         let token = session_cookie.value();
         
         // Delete the session from the database
@@ -114,105 +200,97 @@ pub async fn logout(cookies: &CookieJar<'_>, db: &NexoDB) -> rocket::response::R
     }
     
     // Remove session cookie
-    cookies.remove(Cookie::from("session_token"));
+    cookies.remove(cookie_config.removal(SESSION_COOKIE));
     
-    rocket::response::Redirect::to("/")
+    Flash::success(Redirect::to("/"), "logout-success")
 }
 
 #[get("/user")]
-pub async fn get_current_user(cookies: &CookieJar<'_>, db: &NexoDB) -> Result<rocket::serde::json::Json<serde_json::Value>, Status> {
-    if let Some(session_cookie) = cookies.get("session_token") {
-        let token = session_cookie.value();
-        
-        if let Some(user_id) = validate_session(db, token).await {
-            if let Some(username) = get_username_by_id(db, user_id).await {
-                let user_data = serde_json::json!({
-                    "username": username,
-                    "user_id": user_id
-                });
-                Ok(rocket::serde::json::Json(user_data))
-            } else {
-                Err(Status::InternalServerError)
-            }
-        } else {
-            Err(Status::Unauthorized)
-        }
-    } else {
-        Err(Status::Unauthorized)
-    }
+pub async fn get_current_user(user: AuthenticatedUser, locale: Locale) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(serde_json::json!({
+        "username": user.name,
+        "user_id": user.id,
+        "roles": user.roles,
+        "permissions": user.permissions,
+        "locale": locale.tag()
+    }))
 }
 
-async fn get_user_psw_from_db(db: &NexoDB, username: String) -> Option<String>{
-    // Ensure database is initialized before querying
-    if let Err(e) = ensure_db_initialized(db).await {
-        eprintln!("Failed to initialize database: {:?}", e);
-        return None;
-    }
-    
-    let result = get_psw(db, username.as_str()).await;
-    result
-}
-fn validate_user_psw(stored_password_hash: Option<String>, login_password: String, salt:&str) -> bool {
+fn validate_user_psw(config: &PasswordHashConfig, stored_password_hash: Option<String>, login_password: &str) -> PasswordVerification {
     match stored_password_hash {
         Some(from_db) => {
-            from_db == hash_password(salt, login_password.as_str())
+            verify_password(config, &from_db, login_password).unwrap_or_else(|e| {
+                eprintln!("Failed to verify password: {}", e);
+                PasswordVerification::Invalid
+            })
         }
-        None => false
+        None => PasswordVerification::Invalid
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::validate_user_psw;
-    use crate::crypto::hash_password;
+    use crate::crypto::{hash_password, PasswordHashConfig, PasswordVerification};
 
-    const SALT: &str = "test_salt";
+    const CONFIG: PasswordHashConfig = PasswordHashConfig {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    };
 
     #[test]
     fn test_valid_password() {
         let password = "secure_password";
-        let hashed = hash_password(SALT, password);
-        let result = validate_user_psw(Some(hashed), password.to_string(), SALT);
-        assert!(result, "Password should be valid");
+        let hashed = hash_password(&CONFIG, password).unwrap();
+        let result = validate_user_psw(&CONFIG, Some(hashed), password);
+        assert_eq!(result, PasswordVerification::Valid, "Password should be valid");
     }
 
     #[test]
     fn test_invalid_password() {
         let correct_password = "secure_password";
         let wrong_password = "wrong_password";
-        let hashed = hash_password(SALT, correct_password);
-        let result = validate_user_psw(Some(hashed), wrong_password.to_string(), SALT);
-        assert!(!result, "Password should be invalid");
+        let hashed = hash_password(&CONFIG, correct_password).unwrap();
+        let result = validate_user_psw(&CONFIG, Some(hashed), wrong_password);
+        assert_eq!(result, PasswordVerification::Invalid, "Password should be invalid");
     }
 
     #[test]
     fn test_none_stored_hash() {
-        let result = validate_user_psw(None, "any_password".to_string(), SALT);
-        assert!(!result, "Validation should fail if no stored hash is found");
+        let result = validate_user_psw(&CONFIG, None, "any_password");
+        assert!(!result.is_valid(), "Validation should fail if no stored hash is found");
     }
 
     #[test]
     fn test_empty_password() {
-        let hashed = hash_password(SALT, "");
-        let result = validate_user_psw(Some(hashed), "".to_string(), SALT);
-        assert!(result, "Empty password should match its correct hash");
+        let hashed = hash_password(&CONFIG, "").unwrap();
+        let result = validate_user_psw(&CONFIG, Some(hashed), "");
+        assert!(result.is_valid(), "Empty password should match its correct hash");
     }
 
     #[test]
     fn test_mismatched_hash() {
         let password = "password";
-        let hashed = hash_password(SALT, password);
+        let hashed = hash_password(&CONFIG, password).unwrap();
         let tampered_hash = format!("{}_modified", hashed);
-        let result = validate_user_psw(Some(tampered_hash), password.to_string(), SALT);
-        assert!(!result, "Hash mismatch should result in invalidation");
+        let result = validate_user_psw(&CONFIG, Some(tampered_hash), password);
+        assert!(!result.is_valid(), "Hash mismatch should result in invalidation");
     }
 
     #[test]
     fn test_password_with_special_chars() {
         let password = "!@#$%^&*()_+-=[]{}|;':,.<>?";
-        let hashed = hash_password(SALT, password);
-        let result = validate_user_psw(Some(hashed), password.to_string(), SALT);
-        assert!(result, "Password with special characters should be valid");
+        let hashed = hash_password(&CONFIG, password).unwrap();
+        let result = validate_user_psw(&CONFIG, Some(hashed), password);
+        assert!(result.is_valid(), "Password with special characters should be valid");
     }
-}
 
+    #[test]
+    fn test_outdated_parameters_still_valid() {
+        let hashed = hash_password(&CONFIG, "password").unwrap();
+        let stronger = PasswordHashConfig { memory_kib: 2048, ..CONFIG };
+        let result = validate_user_psw(&stronger, Some(hashed), "password");
+        assert_eq!(result, PasswordVerification::ValidNeedsRehash);
+    }
+}
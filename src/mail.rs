@@ -0,0 +1,362 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use crate::config;
+
+/// How outgoing mail leaves the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Print messages to stdout. For development.
+    Stdout,
+    /// Write each message as an `.eml` file into `file_dir`. For development.
+    File,
+    /// Deliver through an SMTP relay.
+    Smtp,
+}
+
+/// Transport security for the SMTP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection. Only for a relay on localhost.
+    None,
+    /// Upgrade with STARTTLS (usually port 587)
+    Starttls,
+    /// TLS from the start (usually port 465)
+    Tls,
+}
+
+/// Settings from the `mail` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    pub transport: TransportKind,
+    /// Sender address, e.g. `Nexo <nexo@example.com>`
+    pub from: String,
+    /// Base URL used to build links in emails, without a trailing slash
+    pub public_url: String,
+    pub file_dir: PathBuf,
+    pub smtp_host: String,
+    pub smtp_port: Option<u16>,
+    pub smtp_security: SmtpSecurity,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: TransportKind::Stdout,
+            from: "Nexo <nexo@localhost>".to_string(),
+            public_url: "http://localhost:8000".to_string(),
+            file_dir: PathBuf::from("mail"),
+            smtp_host: "localhost".to_string(),
+            smtp_port: None,
+            smtp_security: SmtpSecurity::Starttls,
+            smtp_username: None,
+            smtp_password: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    /// Bad settings, such as an unparseable sender address
+    Config(String),
+    /// The message couldn't be built, e.g. an invalid recipient
+    Message(String),
+    /// The transport failed to deliver the message
+    Transport(String),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Config(e) => write!(f, "invalid mail configuration: {}", e),
+            MailError::Message(e) => write!(f, "invalid message: {}", e),
+            MailError::Transport(e) => write!(f, "failed to send mail: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+/// A plain-text email with validated addresses
+pub struct Email {
+    pub from: Mailbox,
+    pub to: Mailbox,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// The MIME message as it goes over the wire
+    pub fn to_message(&self) -> Result<Message, MailError> {
+        Message::builder()
+            .from(self.from.clone())
+            .to(self.to.clone())
+            .subject(&self.subject)
+            .body(self.body.clone())
+            .map_err(|e| MailError::Message(e.to_string()))
+    }
+}
+
+/// Something that can deliver an email
+#[rocket::async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+/// Prints emails to stdout, undecoded so links can be copied straight out
+pub struct StdoutTransport;
+
+#[rocket::async_trait]
+impl MailTransport for StdoutTransport {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        println!(
+            "----- outgoing mail -----\nFrom: {}\nTo: {}\nSubject: {}\n\n{}\n-------------------------",
+            email.from, email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+pub struct FileTransport(AsyncFileTransport<Tokio1Executor>);
+
+#[rocket::async_trait]
+impl MailTransport for FileTransport {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.0.send(email.to_message()?).await.map(|_| ()).map_err(|e| MailError::Transport(e.to_string()))
+    }
+}
+
+pub struct SmtpTransport(AsyncSmtpTransport<Tokio1Executor>);
+
+impl SmtpTransport {
+    pub fn from_config(config: &MailConfig) -> Result<Self, MailError> {
+        let builder = match config.smtp_security {
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host),
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+                .map_err(|e| MailError::Config(e.to_string()))?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)
+                .map_err(|e| MailError::Config(e.to_string()))?,
+        };
+
+        let builder = match config.smtp_port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+        let builder = match (&config.smtp_username, &config.smtp_password) {
+            (Some(username), Some(password)) => builder.credentials(Credentials::new(username.clone(), password.clone())),
+            _ => builder,
+        };
+
+        Ok(SmtpTransport(builder.build()))
+    }
+}
+
+#[rocket::async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.0.send(email.to_message()?).await.map(|_| ()).map_err(|e| MailError::Transport(e.to_string()))
+    }
+}
+
+/// Managed state for sending mail. Cheap to clone, so it can be moved into a
+/// background task when the response shouldn't wait for delivery.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Arc<dyn MailTransport>,
+    from: Mailbox,
+    public_url: String,
+}
+
+impl Mailer {
+    pub fn new(transport: Arc<dyn MailTransport>, from: &str, public_url: &str) -> Result<Self, MailError> {
+        let from = from.parse().map_err(|e: lettre::address::AddressError| MailError::Config(e.to_string()))?;
+        Ok(Mailer {
+            transport,
+            from,
+            public_url: public_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn from_config(config: &MailConfig) -> Result<Self, MailError> {
+        let transport: Arc<dyn MailTransport> = match config.transport {
+            TransportKind::Stdout => Arc::new(StdoutTransport),
+            TransportKind::File => {
+                std::fs::create_dir_all(&config.file_dir).map_err(|e| MailError::Config(e.to_string()))?;
+                Arc::new(FileTransport(AsyncFileTransport::new(&config.file_dir)))
+            }
+            TransportKind::Smtp => Arc::new(SmtpTransport::from_config(config)?),
+        };
+        Mailer::new(transport, &config.from, &config.public_url)
+    }
+
+    /// Absolute URL for a path on this deployment, for links in emails
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.public_url, path)
+    }
+
+    /// Send a plain-text email
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), MailError> {
+        let email = Email {
+            from: self.from.clone(),
+            to: to.parse().map_err(|e: lettre::address::AddressError| MailError::Message(e.to_string()))?,
+            subject: subject.to_string(),
+            body,
+        };
+
+        self.transport.send(&email).await
+    }
+}
+
+/// Fairing that reads the `mail` table and manages a [`Mailer`]
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Mail", |rocket| async {
+        let config = match config::extract::<MailConfig>(rocket.figment(), "mail") {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid [mail] configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match Mailer::from_config(&config) {
+            Ok(mailer) => Ok(rocket.manage(mailer)),
+            Err(e) => {
+                eprintln!("Failed to set up mail: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use rocket::tokio::net::TcpListener;
+
+    /// A minimal SMTP server standing in for a real relay. Accepts a single
+    /// message and returns the raw DATA section.
+    async fn fake_smtp_server() -> (u16, rocket::tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = rocket::tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut data = String::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 localhost ESMTP test\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 OK queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+
+                let reply: &[u8] = match line.split_whitespace().next().unwrap_or("").to_uppercase().as_str() {
+                    "EHLO" | "HELO" => b"250 localhost\r\n",
+                    "MAIL" | "RCPT" | "RSET" | "NOOP" => b"250 OK\r\n",
+                    "DATA" => {
+                        in_data = true;
+                        b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 Bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"502 Command not implemented\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn test_smtp_transport_delivers() {
+        rocket::async_test(async {
+            let (port, server) = fake_smtp_server().await;
+            let config = MailConfig {
+                transport: TransportKind::Smtp,
+                smtp_host: "127.0.0.1".to_string(),
+                smtp_port: Some(port),
+                smtp_security: SmtpSecurity::None,
+                ..MailConfig::default()
+            };
+
+            let mailer = Mailer::from_config(&config).unwrap();
+            mailer
+                .send("maria@example.com", "Hello", "Body text".to_string())
+                .await
+                .expect("Failed to send through fake SMTP server");
+            drop(mailer);
+
+            let data = server.await.unwrap();
+            assert!(data.contains("To: maria@example.com"), "{}", data);
+            assert!(data.contains("Subject: Hello"), "{}", data);
+            assert!(data.contains("Body text"), "{}", data);
+        });
+    }
+
+    #[test]
+    fn test_file_transport_writes_eml() {
+        rocket::async_test(async {
+            let dir = std::env::temp_dir().join(format!("nexo-mail-test-{}", std::process::id()));
+            let config = MailConfig {
+                transport: TransportKind::File,
+                file_dir: dir.clone(),
+                ..MailConfig::default()
+            };
+
+            let mailer = Mailer::from_config(&config).unwrap();
+            mailer.send("maria@example.com", "Hello", "Body text".to_string()).await.unwrap();
+
+            let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+            assert_eq!(files.len(), 1);
+            let contents = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+            assert!(contents.contains("Body text"));
+
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_invalid_addresses() {
+        let config = MailConfig { from: "not an address".to_string(), ..MailConfig::default() };
+        assert!(matches!(Mailer::from_config(&config), Err(MailError::Config(_))));
+
+        rocket::async_test(async {
+            let mailer = Mailer::from_config(&MailConfig::default()).unwrap();
+            let result = mailer.send("nobody", "Hello", String::new()).await;
+            assert!(matches!(result, Err(MailError::Message(_))));
+        });
+    }
+
+    #[test]
+    fn test_url_joins_public_url() {
+        let config = MailConfig { public_url: "https://nexo.example.com/".to_string(), ..MailConfig::default() };
+        let mailer = Mailer::from_config(&config).unwrap();
+        assert_eq!(mailer.url("/reset-password/abc"), "https://nexo.example.com/reset-password/abc");
+    }
+}
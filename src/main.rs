@@ -1,9 +1,8 @@
 #[macro_use] extern crate rocket;
 use rocket_db_pools::Database;
-mod login;
-mod crypto;
-mod database;
-mod api_utils;
+use minijinja::context;
+use nexo::{assets, auth, config, cookies, crypto, database, error, https, i18n, invite, login, mail, maintenance, password_reset, register, roles,
+           scheduler, security_headers, sessions, setup, templates, throttle, totp};
 
 #[get("/health")]
 async fn health() -> rocket::serde::json::Json<serde_json::Value> {
@@ -13,33 +12,85 @@ async fn health() -> rocket::serde::json::Json<serde_json::Value> {
 }
 
 #[get("/")]
-async fn index(cookies: &rocket::http::CookieJar<'_>, db: &database::NexoDB) -> rocket::fs::NamedFile {
-    // If authenticated, redirect to /home (handled by /home route)
-    if let Some(session_cookie) = cookies.get("session_token") {
-        let token = session_cookie.value();
-        if let Some(_user_id) = database::validate_session(db, token).await {
-            // Serve home page directly if authenticated
-            return rocket::fs::NamedFile::open("static/home.html").await.expect("static/home.html not found");
-        }
+async fn index(
+    user: Option<auth::AuthenticatedUser>,
+    page: templates::Page,
+    db: &database::NexoDB,
+) -> templates::Template {
+    // Serve home page directly if authenticated
+    if user.is_some() {
+        return page.render("home.html", context! {});
+    }
+    // A fresh instance asks for its first admin instead
+    match database::setup::setup_required(db).await {
+        Ok(true) => return page.render("setup.html", context! {}),
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to check setup status: {:?}", e),
     }
     // Always serve login page for unauthenticated users
-    rocket::fs::NamedFile::open("static/index.html").await.expect("static/index.html not found")
+    page.render("index.html", context! {})
 }
 
-#[launch]
-fn rocket() -> _ {
+fn rocket() -> rocket::Rocket<rocket::Build> {
     rocket::build()
-        .mount("/", routes![index, health])
+        .mount("/", routes![index, health, assets::asset, security_headers::csp_report])
         .mount("/home", routes![login::home])
-        .mount("/login", routes![login::login])
+        .mount("/login", routes![login::login, totp::login_second_step])
         .mount("/", routes![login::logout])
-        .mount("/api", routes![login::get_current_user, api_utils::init_db_endpoint])
-        .register("/", catchers![not_found])
+        .mount("/setup", routes![setup::setup])
+        .mount("/register", routes![register::page, register::register])
+        .mount("/invite", routes![invite::page, invite::accept])
+        .mount("/", routes![password_reset::forgot_password_page, password_reset::forgot_password,
+                            password_reset::reset_password_page, password_reset::reset_password])
+        .mount("/api", routes![login::get_current_user, i18n::set_preference,
+                               totp::status, totp::enroll, totp::confirm, totp::disable,
+                               invite::create, invite::list, invite::revoke,
+                               throttle::list, throttle::clear,
+                               sessions::list, sessions::rename, sessions::revoke, sessions::revoke_others,
+                               scheduler::status,
+                               roles::list, roles::users, roles::assign,
+                               maintenance::status, maintenance::migrate, maintenance::integrity_check])
+        .register("/", vec![auth::unauthorized_catcher()])
+        .register("/", error::catchers())
         .attach(database::NexoDB::init())
+        .attach(database::migrations::stage())
+        .attach(setup::stage())
+        .attach(config::section::<crypto::PasswordHashConfig>("Password Hashing", "password_hashing"))
+        .attach(config::section::<register::RegistrationConfig>("Registration", "registration"))
+        .attach(config::section::<throttle::ThrottleConfig>("Login Throttling", "login_throttle"))
+        .attach(config::section::<sessions::SessionConfig>("Sessions", "sessions"))
+        .attach(config::section::<assets::AssetConfig>("Assets", "assets"))
+        .attach(i18n::stage())
+        .attach(templates::stage())
+        .attach(cookies::stage())
+        .attach(https::stage())
+        .attach(security_headers::stage())
+        .attach(sessions::renewal())
+        .attach(mail::stage())
+        .attach(scheduler::stage())
 }
 
-#[catch(404)]
-fn not_found() -> &'static str {
-    "File not found"
+#[rocket::main]
+async fn main() -> std::process::ExitCode {
+    // Keeps running across certificate reloads. Invalid [https] settings
+    // are reported when the main server fails to ignite.
+    if let Ok(Some(redirect)) = https::redirect_server(&rocket::Config::figment()) {
+        rocket::tokio::spawn(async move {
+            if let Err(e) = redirect.launch().await {
+                eprintln!("HTTPS redirect server failed: {}", e.pretty_print());
+            }
+        });
+    }
+
+    loop {
+        match rocket().launch().await {
+            Ok(rocket) if https::reload_requested(&rocket) => continue,
+            Ok(_) => return std::process::ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{}", e.pretty_print());
+                return std::process::ExitCode::FAILURE;
+            }
+        }
+    }
 }
 
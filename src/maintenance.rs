@@ -0,0 +1,35 @@
+use rocket::serde::json::Json;
+use serde_json::json;
+
+use crate::auth::{Authorized, ManageDatabase};
+use crate::csrf::CsrfVerified;
+use crate::database::NexoDB;
+use crate::database::maintenance::{check_integrity, database_status};
+use crate::database::migrations::run_migrations;
+use crate::error::ApiError;
+
+type JsonResult = Result<Json<serde_json::Value>, ApiError>;
+
+/// Schema version, pending migrations and table row counts
+#[get("/database")]
+pub async fn status(_user: Authorized<ManageDatabase>, db: &NexoDB) -> JsonResult {
+    let status = database_status(db).await?;
+    Ok(Json(json!({ "status": "ok", "database": status })))
+}
+
+/// Apply pending migrations. Migrations also run at startup, so this only
+/// does something after a database was swapped under a running server.
+#[post("/database/migrations")]
+pub async fn migrate(_user: Authorized<ManageDatabase>, _csrf: CsrfVerified, db: &NexoDB) -> JsonResult {
+    let applied = run_migrations(db).await?;
+    let status = database_status(db).await?;
+
+    Ok(Json(json!({ "status": "ok", "applied": applied, "database": status })))
+}
+
+/// Run SQLite's integrity and foreign key checks
+#[post("/database/integrity-check")]
+pub async fn integrity_check(_user: Authorized<ManageDatabase>, _csrf: CsrfVerified, db: &NexoDB) -> JsonResult {
+    let report = check_integrity(db).await?;
+    Ok(Json(json!({ "status": if report.ok { "ok" } else { "failed" }, "report": report })))
+}
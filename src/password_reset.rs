@@ -0,0 +1,164 @@
+use fluent_bundle::FluentArgs;
+use minijinja::context;
+use rocket::http::{CookieJar, Status};
+use rocket::State;
+
+use crate::cookies::{CookieConfig, SESSION_COOKIE};
+use crate::crypto::{hash_password, PasswordHashConfig};
+use crate::csrf::CsrfForm;
+use crate::database::{NexoDB, get_user_by_email, get_user_locale, get_username_by_id};
+use crate::database::password_resets::{complete_password_reset, create_password_reset, find_password_reset_user};
+use crate::i18n::{translate, I18nConfig, Locale};
+use crate::login::{db_error_fragment, error_fragment, HxRedirectWithCookie};
+use crate::mail::Mailer;
+use crate::templates::{Page, Template};
+use crate::validation::{validate_email, validate_password, ValidationError};
+
+/// How long a reset link stays valid
+const RESET_TOKEN_SECONDS: i64 = 30 * 60;
+
+#[derive(FromForm)]
+pub struct ForgotPasswordForm {
+    email: String,
+}
+
+#[get("/forgot-password")]
+pub async fn forgot_password_page(page: Page) -> Template {
+    page.render("forgot-password.html", context! {})
+}
+
+/// The locale a user's emails are written in: the one they picked, else
+/// the instance default, since there is no browser to negotiate with
+async fn email_locale(db: &NexoDB, user_id: i32, config: &I18nConfig) -> Locale {
+    match get_user_locale(db, user_id).await {
+        Ok(Some(tag)) => match Locale::parse(&tag) {
+            Some(locale) => return locale,
+            None => eprintln!("User {} has unknown locale {}", user_id, tag),
+        },
+        Ok(None) => {}
+        Err(e) => eprintln!("Failed to load user locale: {:?}", e),
+    }
+    config.default_locale
+}
+
+/// Email a reset link if the address belongs to a user
+///
+/// The response is the same whether or not an account exists, and the email
+/// is sent in the background so response times don't give that away either.
+#[post("/forgot-password", data = "<form>")]
+pub async fn forgot_password(
+    form: CsrfForm<ForgotPasswordForm>,
+    db: &NexoDB,
+    mailer: &State<Mailer>,
+    i18n_config: &State<I18nConfig>,
+) -> Template {
+    let sent = || Template::render("partials/message.html", context! { kind => "success", message => "forgot-password-sent" });
+
+    let Ok(email) = validate_email(&form.email) else {
+        return sent();
+    };
+
+    let user = match get_user_by_email(db, &email).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return sent(),
+        Err(e) => {
+            eprintln!("Failed to look up user by email: {:?}", e);
+            return sent();
+        }
+    };
+    let (user_id, username) = user;
+
+    let token = match create_password_reset(db, user_id, RESET_TOKEN_SECONDS).await {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Failed to create password reset: {:?}", e);
+            return sent();
+        }
+    };
+
+    let locale = email_locale(db, user_id, i18n_config).await;
+    let mailer = mailer.inner().clone();
+    let mut args = FluentArgs::new();
+    args.set("name", username);
+    args.set("minutes", RESET_TOKEN_SECONDS / 60);
+    args.set("link", mailer.url(&format!("/reset-password/{}", token)));
+    let subject = translate(locale, "reset-email-subject", None);
+    let body = translate(locale, "reset-email-body", Some(&args));
+    rocket::tokio::spawn(async move {
+        if let Err(e) = mailer.send(&email, &subject, body).await {
+            eprintln!("Failed to send password reset email: {}", e);
+        }
+    });
+
+    sent()
+}
+
+#[get("/reset-password/<token>")]
+pub async fn reset_password_page(token: &str, page: Page, db: &NexoDB) -> Result<Template, Status> {
+    match find_password_reset_user(db, token).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("Failed to look up password reset: {:?}", e);
+            return Err(if e.is_unavailable() { Status::ServiceUnavailable } else { Status::InternalServerError });
+        }
+    }
+
+    Ok(page.render("reset-password.html", context! { token }))
+}
+
+#[derive(FromForm)]
+pub struct ResetPasswordForm {
+    password: String,
+    password_confirm: String,
+}
+
+/// Set the new password, log the user out everywhere and send them to login
+#[post("/reset-password/<token>", data = "<form>")]
+pub async fn reset_password(
+    token: &str,
+    form: CsrfForm<ResetPasswordForm>,
+    db: &NexoDB,
+    cookies: &CookieJar<'_>,
+    cookie_config: &State<CookieConfig>,
+    hash_config: &State<PasswordHashConfig>,
+) -> Result<HxRedirectWithCookie, Template> {
+    let user_id = match find_password_reset_user(db, token).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return Err(error_fragment("error-reset-link-invalid")),
+        Err(e) => {
+            eprintln!("Failed to look up password reset: {:?}", e);
+            return Err(db_error_fragment(&e, "error-reset-password-failed"));
+        }
+    };
+    let username = match get_username_by_id(db, user_id).await {
+        Ok(Some(username)) => username,
+        Ok(None) => return Err(error_fragment("error-reset-link-invalid")),
+        Err(e) => {
+            eprintln!("Failed to look up user: {:?}", e);
+            return Err(db_error_fragment(&e, "error-reset-password-failed"));
+        }
+    };
+
+    validate_password(&form.password, &username).map_err(|e| error_fragment(e.message_id()))?;
+    if form.password != form.password_confirm {
+        return Err(error_fragment(ValidationError::PasswordMismatch.message_id()));
+    }
+
+    let psw_hash = hash_password(hash_config, &form.password).map_err(|e| {
+        eprintln!("Failed to hash password: {}", e);
+        error_fragment("error-reset-password-failed")
+    })?;
+
+    match complete_password_reset(db, token, &psw_hash).await {
+        Ok(Some(_)) => {
+            cookies.remove(cookie_config.removal(SESSION_COOKIE));
+            Ok(HxRedirectWithCookie { location: "/".to_string() })
+        }
+        Ok(None) => Err(error_fragment("error-reset-link-invalid")),
+        Err(e) => {
+            eprintln!("Failed to reset password: {:?}", e);
+            Err(db_error_fragment(&e, "error-reset-password-failed"))
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use minijinja::context;
+use rocket::http::{CookieJar, Status};
+use rocket::State;
+use serde::Deserialize;
+
+use crate::cookies::CookieConfig;
+use crate::crypto::{hash_password, PasswordHashConfig};
+use crate::csrf::CsrfForm;
+use crate::database::{NexoDB, SessionClient, UserConflict, create_user, find_user_conflict};
+use crate::database::setup::setup_required;
+use crate::login::{db_error_fragment, error_fragment, start_session, HxRedirectWithCookie};
+use crate::sessions::SessionConfig;
+use crate::templates::{Page, Template};
+use crate::validation::{validate_cpf, validate_email, validate_password, validate_username, ValidationError};
+
+/// Settings from the `registration` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RegistrationConfig {
+    /// Whether anyone may create an account at `/register`. Turn this off
+    /// for invite-only deployments.
+    pub enabled: bool,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        RegistrationConfig { enabled: true }
+    }
+}
+
+#[derive(FromForm)]
+pub struct RegisterForm {
+    username: String,
+    password: String,
+    password_confirm: String,
+    email: String,
+    cpf: String,
+}
+
+/// A registration that passed validation, with normalized fields
+#[derive(Debug, PartialEq, Eq)]
+pub struct NewUser {
+    pub username: String,
+    pub email: String,
+    pub cpf: Option<String>,
+}
+
+/// Validate and normalize the submitted form. The CPF is optional.
+fn validate_registration(form: &RegisterForm) -> Result<NewUser, ValidationError> {
+    let username = validate_username(&form.username)?;
+    let email = validate_email(&form.email)?;
+    let cpf = match form.cpf.trim() {
+        "" => None,
+        cpf => Some(validate_cpf(cpf)?),
+    };
+    validate_password(&form.password, &username)?;
+    if form.password != form.password_confirm {
+        return Err(ValidationError::PasswordMismatch);
+    }
+
+    Ok(NewUser { username, email, cpf })
+}
+
+#[get("/")]
+pub async fn page(config: &State<RegistrationConfig>, page: Page) -> Result<Template, Status> {
+    if !config.enabled {
+        return Err(Status::NotFound);
+    }
+
+    Ok(page.render("register.html", context! {}))
+}
+
+#[post("/", data = "<form>")]
+#[allow(clippy::too_many_arguments)] // one per request guard
+pub async fn register(
+    form: CsrfForm<RegisterForm>,
+    db: &NexoDB,
+    cookies: &CookieJar<'_>,
+    client: SessionClient,
+    session_config: &State<SessionConfig>,
+    cookie_config: &State<CookieConfig>,
+    config: &State<RegistrationConfig>,
+    hash_config: &State<PasswordHashConfig>,
+) -> Result<HxRedirectWithCookie, Template> {
+    if !config.enabled {
+        return Err(error_fragment("error-registration-disabled"));
+    }
+
+    // The first account must be the admin made on the setup page
+    match setup_required(db).await {
+        Ok(false) => {}
+        Ok(true) => return Err(error_fragment("error-setup-required")),
+        Err(e) => {
+            eprintln!("Failed to check setup status: {:?}", e);
+            return Err(db_error_fragment(&e, "error-create-account-failed"));
+        }
+    }
+
+    let new_user = validate_registration(&form).map_err(|e| error_fragment(e.message_id()))?;
+
+    match find_user_conflict(db, &new_user.username, Some(&new_user.email), new_user.cpf.as_deref()).await {
+        Ok(None) => {}
+        Ok(Some(UserConflict::Name)) => return Err(error_fragment("error-username-taken")),
+        Ok(Some(UserConflict::Email)) => return Err(error_fragment("error-email-taken")),
+        Ok(Some(UserConflict::Cpf)) => return Err(error_fragment("error-cpf-taken")),
+        Err(e) => {
+            eprintln!("Failed to check for existing users: {:?}", e);
+            return Err(db_error_fragment(&e, "error-create-account-failed"));
+        }
+    }
+
+    let psw_hash = hash_password(hash_config, &form.password).map_err(|e| {
+        eprintln!("Failed to hash password: {}", e);
+        error_fragment("error-create-account-failed")
+    })?;
+
+    match create_user(db, &new_user.username, &psw_hash, Some(&new_user.email), new_user.cpf.as_deref()).await {
+        Ok(user_id) => start_session(db, cookies, &client, session_config, cookie_config, user_id).await,
+        // Someone took the name, email or CPF between the check and the insert
+        Err(e) if e.is_unique_violation() => {
+            Err(error_fragment("error-details-taken"))
+        }
+        Err(e) => {
+            eprintln!("Failed to create user: {:?}", e);
+            Err(db_error_fragment(&e, "error-create-account-failed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(username: &str, password: &str, confirm: &str, email: &str, cpf: &str) -> RegisterForm {
+        RegisterForm {
+            username: username.to_string(),
+            password: password.to_string(),
+            password_confirm: confirm.to_string(),
+            email: email.to_string(),
+            cpf: cpf.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_valid_registration_is_normalized() {
+        let form = form(" maria ", "correct horse", "correct horse", "Maria@Example.com", "529.982.247-25");
+        assert_eq!(validate_registration(&form), Ok(NewUser {
+            username: "maria".to_string(),
+            email: "maria@example.com".to_string(),
+            cpf: Some("52998224725".to_string()),
+        }));
+    }
+
+    #[test]
+    fn test_cpf_is_optional() {
+        let form = form("maria", "correct horse", "correct horse", "maria@example.com", "  ");
+        assert_eq!(validate_registration(&form).unwrap().cpf, None);
+    }
+
+    #[test]
+    fn test_invalid_registrations() {
+        let cases = [
+            (form("m", "correct horse", "correct horse", "maria@example.com", ""), ValidationError::UsernameLength),
+            (form("maria", "correct horse", "correct horse", "maria", ""), ValidationError::InvalidEmail),
+            (form("maria", "correct horse", "correct horse", "maria@example.com", "123.456.789-00"), ValidationError::InvalidCpf),
+            (form("maria", "short", "short", "maria@example.com", ""), ValidationError::PasswordTooShort),
+            (form("maria", "correct horse", "correct horsr", "maria@example.com", ""), ValidationError::PasswordMismatch),
+        ];
+
+        for (form, expected) in cases {
+            assert_eq!(validate_registration(&form), Err(expected));
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use rocket::serde::json::Json;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::auth::{Authorized, ManageUsers};
+use crate::csrf::CsrfVerified;
+use crate::database::NexoDB;
+use crate::database::roles::{SetRolesError, list_roles, list_users_with_roles, set_user_roles};
+use crate::error::ApiError;
+
+/// Every role and the permissions it grants
+#[get("/roles")]
+pub async fn list(_user: Authorized<ManageUsers>, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let roles = list_roles(db).await?;
+    Ok(Json(json!({ "roles": roles })))
+}
+
+/// Every user and their roles
+#[get("/users")]
+pub async fn users(_user: Authorized<ManageUsers>, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let users = list_users_with_roles(db).await?;
+    Ok(Json(json!({ "users": users })))
+}
+
+#[derive(Deserialize)]
+pub struct UserRolesRequest {
+    roles: Vec<String>,
+}
+
+/// Replace a user's roles
+///
+/// Refused with 409 when it would leave nobody able to manage the instance.
+#[put("/users/<id>/roles", data = "<request>")]
+pub async fn assign(
+    id: i32,
+    request: Json<UserRolesRequest>,
+    _user: Authorized<ManageUsers>,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    match set_user_roles(db, id, &request.roles).await {
+        Ok(()) => Ok(Json(json!({ "user_id": id, "roles": request.roles }))),
+        Err(SetRolesError::UnknownUser) => Err(ApiError::NotFound("user")),
+        Err(SetRolesError::UnknownRole(role)) => Err(ApiError::invalid("unknown_role", format!("No role is named {}", role))),
+        Err(SetRolesError::LastAdmin) => Err(ApiError::conflict("last_admin", "At least one user must keep the admin role")),
+        Err(SetRolesError::Database(e)) => Err(e.into()),
+    }
+}
@@ -0,0 +1,558 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rocket::fairing::AdHoc;
+use rocket::futures::future::BoxFuture;
+use rocket::serde::json::Json;
+use rocket::time::OffsetDateTime;
+use rocket::State;
+use rocket_db_pools::Database;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+use crate::config;
+use crate::crypto::get_current_timestamp;
+use crate::database::{DbError, NexoDB, cleanup_expired_sessions};
+use crate::database::jobs::{get_job_record, record_job_run};
+use crate::database::login_throttle::prune_login_throttle;
+use crate::database::totp::cleanup_expired_login_challenges;
+use crate::throttle::ThrottleConfig;
+use crate::auth::{Authorized, ViewJobs};
+use crate::error::ApiError;
+
+/// Longest the scheduler sleeps before checking for due jobs again
+const MAX_SLEEP_SECONDS: i64 = 60;
+
+/// Source of the current time, so tests can move it by hand
+pub trait Clock: Send + Sync {
+    /// Seconds since the Unix epoch
+    fn now(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        get_current_timestamp()
+    }
+}
+
+/// When a job runs: at a fixed interval, or on a cron expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every this many seconds, and straight away if the job never ran
+    Every(i64),
+    /// On every match; a job that never ran waits for the first match after
+    /// the scheduler started
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// Parse `every <n><s|m|h|d>` or a five-field cron expression
+    pub fn parse(spec: &str) -> Result<Schedule, String> {
+        let spec = spec.trim();
+        match spec.strip_prefix("every ") {
+            Some(interval) => parse_interval(interval.trim()).map(Schedule::Every),
+            None => Cron::parse(spec).map(Schedule::Cron),
+        }
+    }
+
+    /// When a job that last started at `last_run` is due next, for a
+    /// scheduler that started at `since`
+    pub fn next_run(&self, last_run: Option<i64>, since: i64) -> i64 {
+        match self {
+            Schedule::Every(seconds) => last_run.map_or(since, |last| last + seconds),
+            Schedule::Cron(cron) => cron.next_after(last_run.unwrap_or(since)),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Every(seconds) => write!(f, "every {}s", seconds),
+            Schedule::Cron(cron) => write!(f, "{}", cron.source),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let spec = String::deserialize(deserializer)?;
+        Schedule::parse(&spec).map_err(serde::de::Error::custom)
+    }
+}
+
+fn parse_interval(interval: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid interval '{}', expected e.g. 30s, 15m, 1h or 1d", interval);
+    let split = interval.len().checked_sub(1).ok_or_else(invalid)?;
+    let (count, unit) = interval.split_at(split);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    match count.checked_mul(unit) {
+        Some(seconds) if seconds > 0 => Ok(seconds),
+        _ => Err(invalid()),
+    }
+}
+
+/// A cron expression: minute, hour, day of month, month and day of week,
+/// evaluated in UTC
+///
+/// Fields accept `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`) and
+/// comma separated lists of those. As in classic cron, when both day fields
+/// are restricted a day matching either one is enough. Day of week runs from
+/// 0 (Sunday) to 6, with 7 also meaning Sunday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("'{}' is neither 'every <interval>' nor a five-field cron expression", spec));
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+
+        Ok(Cron {
+            source: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn day_matches(&self, time: &OffsetDateTime) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().number_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`
+    pub fn next_after(&self, after: i64) -> i64 {
+        const MINUTE: i64 = 60;
+        const HOUR: i64 = 60 * MINUTE;
+        const DAY: i64 = 24 * HOUR;
+        // Every valid expression matches within a few years (Feb 29 on a
+        // given weekday being the slowest), so this only stops runaways.
+        let limit = after + 30 * 366 * DAY;
+
+        let mut t = (after.div_euclid(MINUTE) + 1) * MINUTE;
+        while t < limit {
+            let Ok(time) = OffsetDateTime::from_unix_timestamp(t) else {
+                break;
+            };
+            if self.months & (1 << time.month() as u8) == 0 || !self.day_matches(&time) {
+                t = (t.div_euclid(DAY) + 1) * DAY;
+            } else if self.hours & (1 << time.hour()) == 0 {
+                t = (t.div_euclid(HOUR) + 1) * HOUR;
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                t += MINUTE;
+            } else {
+                return t;
+            }
+        }
+
+        i64::MAX
+    }
+}
+
+/// Parse one cron field into a bitmask of the values it allows
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field '{}' (values {}-{})", field, min, max);
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    (value, value)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+/// The work a job does. Returns a short report, or why it failed.
+///
+/// Each job is built by a function that takes the settings it needs and
+/// returns one of these.
+pub type JobFn = Box<dyn for<'a> Fn(&'a NexoDB) -> BoxFuture<'a, Result<String, String>> + Send + Sync>;
+
+pub struct Job {
+    pub name: &'static str,
+    pub schedule: Schedule,
+    pub run: JobFn,
+}
+
+/// Settings from the `scheduler` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// Whether background jobs run in this process at all
+    pub enabled: bool,
+    pub session_cleanup: Schedule,
+    pub login_throttle_cleanup: Schedule,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            enabled: true,
+            session_cleanup: Schedule::Every(60 * 60),
+            login_throttle_cleanup: Schedule::Every(60 * 60),
+        }
+    }
+}
+
+/// Deletes expired sessions and the login challenges abandoned at the
+/// second factor
+fn session_cleanup() -> JobFn {
+    Box::new(|db| Box::pin(async move {
+        let sessions = cleanup_expired_sessions(db).await.map_err(|e| e.to_string())?;
+        let challenges = cleanup_expired_login_challenges(db).await.map_err(|e| e.to_string())?;
+        Ok(format!("deleted {} expired sessions and {} expired login challenges", sessions, challenges))
+    }))
+}
+
+/// Deletes throttle entries whose failures are older than
+/// `forget_after_seconds` and no longer block anything
+fn login_throttle_cleanup(forget_after_seconds: i64) -> JobFn {
+    Box::new(move |db| Box::pin(async move {
+        let now = get_current_timestamp();
+        let deleted = prune_login_throttle(db, now, now - forget_after_seconds).await.map_err(|e| e.to_string())?;
+        Ok(format!("deleted {} stale login throttle entries", deleted))
+    }))
+}
+
+/// Every job nexo runs, with its configured schedule
+pub fn jobs(config: &SchedulerConfig, throttle_config: &ThrottleConfig) -> Vec<Job> {
+    vec![
+        Job { name: "session_cleanup", schedule: config.session_cleanup.clone(), run: session_cleanup() },
+        Job {
+            name: "login_throttle_cleanup",
+            schedule: config.login_throttle_cleanup.clone(),
+            run: login_throttle_cleanup(throttle_config.forget_after_seconds),
+        },
+    ]
+}
+
+/// Runs registered jobs when they are due and records how each run went
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    clock: Arc<dyn Clock>,
+    started_at: i64,
+}
+
+impl Scheduler {
+    pub fn new(jobs: Vec<Job>, clock: Arc<dyn Clock>) -> Self {
+        let started_at = clock.now();
+        Scheduler { jobs, clock, started_at }
+    }
+
+    /// Run every job that is due, one after another, and return the names
+    /// of those that ran
+    ///
+    /// A job's failure is recorded and doesn't stop the others; only errors
+    /// reading or writing the job records are returned.
+    pub async fn run_due(&self, db: &NexoDB) -> Result<Vec<&'static str>, DbError> {
+        let mut ran = Vec::new();
+
+        for job in &self.jobs {
+            let record = get_job_record(db, job.name).await?;
+            let started_at = self.clock.now();
+            if job.schedule.next_run(record.last_started_at, self.started_at) > started_at {
+                continue;
+            }
+
+            let result = (job.run)(db).await;
+            if let Err(e) = &result {
+                eprintln!("Job {} failed: {}", job.name, e);
+            }
+            record_job_run(db, job.name, started_at, self.clock.now(), result.as_deref().map_err(String::as_str)).await?;
+            ran.push(job.name);
+        }
+
+        Ok(ran)
+    }
+
+    /// When the next job is due
+    pub async fn next_due(&self, db: &NexoDB) -> Result<Option<i64>, DbError> {
+        let mut next: Option<i64> = None;
+
+        for job in &self.jobs {
+            let record = get_job_record(db, job.name).await?;
+            let due = job.schedule.next_run(record.last_started_at, self.started_at);
+            next = Some(next.map_or(due, |next| next.min(due)));
+        }
+
+        Ok(next)
+    }
+
+    /// Run jobs as they come due until `shutdown` fires
+    async fn run(self: Arc<Self>, db: NexoDB, mut shutdown: rocket::Shutdown) {
+        loop {
+            if let Err(e) = self.run_due(&db).await {
+                eprintln!("Failed to run scheduled jobs: {:?}", e);
+            }
+
+            let now = self.clock.now();
+            let wait = match self.next_due(&db).await {
+                Ok(Some(due)) => (due - now).clamp(1, MAX_SLEEP_SECONDS),
+                Ok(None) | Err(_) => MAX_SLEEP_SECONDS,
+            };
+
+            rocket::tokio::select! {
+                _ = rocket::tokio::time::sleep(std::time::Duration::from_secs(wait as u64)) => {}
+                _ = &mut shutdown => break,
+            }
+        }
+    }
+}
+
+/// Fairing that sets up the scheduler and starts it once the server is up.
+/// Must be attached after `NexoDB::init()`.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Scheduler", |rocket| async {
+        let config = match config::extract::<SchedulerConfig>(rocket.figment(), "scheduler") {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid [scheduler] configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        // Attached before this fairing; the defaults keep tests without it working
+        let throttle_config = rocket.state::<ThrottleConfig>().cloned().unwrap_or_default();
+        let scheduler = Arc::new(Scheduler::new(jobs(&config, &throttle_config), Arc::new(SystemClock)));
+        let rocket = rocket.manage(scheduler.clone());
+        if !config.enabled {
+            return Ok(rocket);
+        }
+
+        Ok(rocket.attach(AdHoc::on_liftoff("Scheduler Start", move |rocket| Box::pin(async move {
+            let Some(db) = NexoDB::fetch(rocket) else {
+                return;
+            };
+            rocket::tokio::spawn(scheduler.run(db.clone(), rocket.shutdown()));
+        }))))
+    })
+}
+
+/// Every job with its schedule, when it runs next and how its last run went
+#[get("/jobs")]
+pub async fn status(
+    _user: Authorized<ViewJobs>,
+    db: &NexoDB,
+    scheduler: &State<Arc<Scheduler>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut jobs = Vec::new();
+    for job in &scheduler.jobs {
+        let record = get_job_record(db, job.name).await?;
+        jobs.push(json!({
+            "name": job.name,
+            "schedule": job.schedule.to_string(),
+            "next_run_at": job.schedule.next_run(record.last_started_at, scheduler.started_at),
+            "last_run": record,
+        }));
+    }
+
+    Ok(Json(json!({ "jobs": jobs })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use crate::database::{open_test_db, close_test_db, create_session, validate_session, create_test_user,
+                          SessionClient};
+    use crate::database::jobs::get_job_record;
+    use crate::database::login_throttle::{get_throttle_entry, reserve_login_attempt};
+    use crate::database::totp::{create_login_challenge, get_login_challenge_user};
+
+    /// A clock that only moves when told to
+    struct ManualClock(AtomicI64);
+
+    impl ManualClock {
+        fn advance(&self, seconds: i64) {
+            self.0.fetch_add(seconds, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> i64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn always_fails() -> JobFn {
+        Box::new(|_db| Box::pin(async { Err("boom".to_string()) }))
+    }
+
+    /// 2024-01-01 00:00:00 UTC, a Monday
+    const MONDAY: i64 = 1_704_067_200;
+
+    #[test]
+    fn test_parse_schedules() {
+        assert_eq!(Schedule::parse("every 30s"), Ok(Schedule::Every(30)));
+        assert_eq!(Schedule::parse("every 15m"), Ok(Schedule::Every(900)));
+        assert_eq!(Schedule::parse(" every 2d "), Ok(Schedule::Every(172_800)));
+        assert!(Schedule::parse("every 0s").is_err());
+        assert!(Schedule::parse("every 5w").is_err());
+        assert!(Schedule::parse("every h").is_err());
+
+        assert!(Schedule::parse("0 3 * * *").is_ok());
+        assert!(Schedule::parse("*/15 9-17 * * 1-5").is_ok());
+        assert!(Schedule::parse("0 0 1,15 * 7").is_ok());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("* * 0 * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("5-1 * * * *").is_err());
+        assert!(Schedule::parse("* * * *").is_err());
+    }
+
+    #[test]
+    fn test_interval_next_run() {
+        let schedule = Schedule::Every(3600);
+        assert_eq!(schedule.next_run(None, 500), 500, "never run means due from the start");
+        assert_eq!(schedule.next_run(Some(1000), 1500), 4600);
+    }
+
+    #[test]
+    fn test_cron_next_run() {
+        let cron = |spec| match Schedule::parse(spec).unwrap() {
+            Schedule::Cron(cron) => cron,
+            Schedule::Every(_) => unreachable!(),
+        };
+
+        // Strictly after, even when exactly on a match
+        assert_eq!(cron("* * * * *").next_after(MONDAY), MONDAY + 60);
+        assert_eq!(cron("30 3 * * *").next_after(MONDAY), MONDAY + 3 * 3600 + 30 * 60);
+        assert_eq!(cron("0 0 * * *").next_after(MONDAY), MONDAY + 86_400);
+        assert_eq!(cron("*/15 * * * *").next_after(MONDAY + 61), MONDAY + 15 * 60);
+        // Next Saturday, then Sunday written as 7
+        assert_eq!(cron("0 12 * * 6").next_after(MONDAY), MONDAY + 5 * 86_400 + 12 * 3600);
+        assert_eq!(cron("0 0 * * 7").next_after(MONDAY), MONDAY + 6 * 86_400);
+        // Both day fields restricted: either one matches
+        assert_eq!(cron("0 0 15 * 3").next_after(MONDAY), MONDAY + 2 * 86_400);
+        // February 29th, 2024
+        assert_eq!(cron("0 0 29 2 *").next_after(MONDAY), MONDAY + 59 * 86_400);
+        // Never matches
+        assert_eq!(cron("0 0 31 2 *").next_after(MONDAY), i64::MAX);
+    }
+
+    #[test]
+    fn test_jobs_run_when_due() {
+        rocket::async_test(async {
+            let db_path = "test_scheduler_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let clock = Arc::new(ManualClock(AtomicI64::new(MONDAY)));
+            let scheduler = Scheduler::new(vec![
+                Job { name: "hourly", schedule: Schedule::Every(3600), run: session_cleanup() },
+                Job { name: "nightly", schedule: Schedule::parse("0 2 * * *").unwrap(), run: always_fails() },
+            ], clock.clone());
+
+            // The interval job runs at once, the cron job waits for 02:00
+            assert_eq!(scheduler.run_due(&db).await.unwrap(), vec!["hourly"]);
+            assert!(scheduler.run_due(&db).await.unwrap().is_empty());
+            assert_eq!(scheduler.next_due(&db).await.unwrap(), Some(MONDAY + 3600));
+
+            clock.advance(3600);
+            assert_eq!(scheduler.run_due(&db).await.unwrap(), vec!["hourly"]);
+
+            clock.advance(3600);
+            assert_eq!(scheduler.run_due(&db).await.unwrap(), vec!["hourly", "nightly"]);
+
+            let hourly = get_job_record(&db, "hourly").await.unwrap();
+            assert_eq!((hourly.run_count, hourly.failure_count), (3, 0));
+            assert_eq!(hourly.last_started_at, Some(MONDAY + 7200));
+            assert_eq!(hourly.last_message.as_deref(), Some("deleted 0 expired sessions and 0 expired login challenges"));
+
+            let nightly = get_job_record(&db, "nightly").await.unwrap();
+            assert_eq!((nightly.run_count, nightly.failure_count), (1, 1));
+            assert_eq!(nightly.last_outcome.as_deref(), Some("failure"));
+            assert_eq!(nightly.last_message.as_deref(), Some("boom"));
+
+            close_test_db(db, db_path).await;
+        });
+    }
+
+    #[test]
+    fn test_cleanup_jobs() {
+        rocket::async_test(async {
+            let db_path = "test_scheduler_cleanup_db.sqlite";
+            let db = open_test_db(db_path).await;
+            let user_id = create_test_user(&db, "thiago").await;
+            let live = create_session(&db, user_id, 3600, 3600, &SessionClient::default()).await.unwrap();
+            create_session(&db, user_id, -1, 3600, &SessionClient::default()).await.unwrap();
+            create_session(&db, user_id, -1, 3600, &SessionClient::default()).await.unwrap();
+            let pending = create_login_challenge(&db, user_id, 300).await.unwrap();
+            create_login_challenge(&db, user_id, -1).await.unwrap();
+
+            let throttle_config = ThrottleConfig::default();
+            let now = get_current_timestamp();
+            let stale = now - throttle_config.forget_after_seconds - 1;
+            reserve_login_attempt(&db, "ip", "127.0.0.1", stale, 0, |_| None).await.unwrap();
+            reserve_login_attempt(&db, "account", "thiago", now, 0, |_| None).await.unwrap();
+
+            let clock = Arc::new(ManualClock(AtomicI64::new(MONDAY)));
+            let scheduler = Scheduler::new(jobs(&SchedulerConfig::default(), &throttle_config), clock);
+            assert_eq!(scheduler.run_due(&db).await.unwrap(), vec!["session_cleanup", "login_throttle_cleanup"]);
+
+            let record = get_job_record(&db, "session_cleanup").await.unwrap();
+            assert_eq!(record.last_message.as_deref(), Some("deleted 2 expired sessions and 1 expired login challenges"));
+            assert_eq!(validate_session(&db, &live).await.unwrap(), Some(user_id));
+            assert_eq!(get_login_challenge_user(&db, &pending).await.unwrap(), Some(user_id));
+            let record = get_job_record(&db, "login_throttle_cleanup").await.unwrap();
+            assert_eq!(record.last_message.as_deref(), Some("deleted 1 stale login throttle entries"));
+            assert!(get_throttle_entry(&db, "account", "thiago").await.unwrap().is_some());
+
+            close_test_db(db, db_path).await;
+        });
+    }
+}
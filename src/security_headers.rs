@@ -0,0 +1,303 @@
+//! Content-Security-Policy and the other security headers on every response
+//!
+//! Each response gets its own CSP nonce; pages with inline scripts take it
+//! from the [`CspNonce`] guard. Violations are reported to `/csp-report`,
+//! which logs them, so a policy can be tried with `csp_report_only` before
+//! it blocks anything.
+
+use std::convert::Infallible;
+
+use rocket::data::{Data, ToByteUnit};
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config;
+use crate::crypto::generate_session_token;
+
+/// Where browsers send violation reports
+pub const CSP_REPORT_PATH: &str = "/csp-report";
+/// Name of the reporting endpoint in `Reporting-Endpoints`
+const REPORT_GROUP: &str = "csp";
+/// Reports bigger than this are dropped unread
+const MAX_REPORT_BYTES: u64 = 16 * 1024;
+
+/// Settings from the `security_headers` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    /// Send `Content-Security-Policy-Report-Only`: violations are reported
+    /// but nothing is blocked
+    pub csp_report_only: bool,
+    /// Script sources allowed besides nexo itself and the response's nonce
+    pub script_src: Vec<String>,
+    /// Style sources allowed besides nexo itself
+    pub style_src: Vec<String>,
+    /// `max-age` of Strict-Transport-Security; 0 leaves the header out
+    pub hsts_max_age_seconds: u64,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    /// "DENY" or "SAMEORIGIN"; also sets the CSP `frame-ancestors`
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            csp_report_only: false,
+            // The pages use Alpine's CSP build and HTMX with eval and its
+            // indicator <style> turned off, so nothing else is needed
+            script_src: Vec::new(),
+            style_src: Vec::new(),
+            hsts_max_age_seconds: 0,
+            hsts_include_subdomains: false,
+            hsts_preload: false,
+            frame_options: "DENY".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            permissions_policy: "camera=(), microphone=(), geolocation=(), payment=(), interest-cohort=()".to_string(),
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    fn validate(&self) -> Result<(), String> {
+        if !matches!(self.frame_options.as_str(), "DENY" | "SAMEORIGIN") {
+            return Err(format!("frame_options must be \"DENY\" or \"SAMEORIGIN\", not {:?}", self.frame_options));
+        }
+        if self.hsts_preload && (!self.hsts_include_subdomains || self.hsts_max_age_seconds < 31536000) {
+            return Err("hsts_preload requires hsts_include_subdomains and a max age of at least a year".to_string());
+        }
+        Ok(())
+    }
+
+    /// The policy for a response with the given nonce
+    fn content_security_policy(&self, nonce: &str) -> String {
+        let frame_ancestors = if self.frame_options == "SAMEORIGIN" { "'self'" } else { "'none'" };
+        let script_src = std::iter::once(format!("'self' 'nonce-{}'", nonce))
+            .chain(self.script_src.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let style_src = std::iter::once("'self'".to_string())
+            .chain(self.style_src.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!(
+            "default-src 'self'; script-src {}; style-src {}; img-src 'self' data:; object-src 'none'; \
+             base-uri 'self'; form-action 'self'; frame-ancestors {}; report-uri {}; report-to {}",
+            script_src, style_src, frame_ancestors, CSP_REPORT_PATH, REPORT_GROUP,
+        )
+    }
+
+    fn strict_transport_security(&self) -> Option<String> {
+        if self.hsts_max_age_seconds == 0 {
+            return None;
+        }
+        let mut value = format!("max-age={}", self.hsts_max_age_seconds);
+        if self.hsts_include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.hsts_preload {
+            value.push_str("; preload");
+        }
+        Some(value)
+    }
+}
+
+/// The CSP nonce of the response to a request, for `<script nonce="...">`
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r CspNonce {
+        request.local_cache(|| CspNonce(generate_session_token()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r CspNonce {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(CspNonce::of(request))
+    }
+}
+
+/// Fairing that reads the `security_headers` table and adds the headers to
+/// every response
+///
+/// The headers replace those of Rocket's default Shield, which runs first.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Security Headers", |rocket| async {
+        let config = match config::extract::<SecurityHeadersConfig>(rocket.figment(), "security_headers") {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid [security_headers] configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+        if let Err(e) = config.validate() {
+            eprintln!("Invalid [security_headers] configuration: {}", e);
+            return Err(rocket);
+        }
+
+        Ok(rocket.manage(config).attach(AdHoc::on_response("Security Headers", |request, response| Box::pin(async move {
+            let Some(config) = request.rocket().state::<SecurityHeadersConfig>() else {
+                return;
+            };
+
+            let csp_header = if config.csp_report_only {
+                "Content-Security-Policy-Report-Only"
+            } else {
+                "Content-Security-Policy"
+            };
+            response.set_raw_header(csp_header, config.content_security_policy(CspNonce::of(request).as_str()));
+            response.set_raw_header("Reporting-Endpoints", format!("{}=\"{}\"", REPORT_GROUP, CSP_REPORT_PATH));
+            if let Some(hsts) = config.strict_transport_security() {
+                response.set_raw_header("Strict-Transport-Security", hsts);
+            }
+            response.set_header(Header::new("X-Frame-Options", config.frame_options.clone()));
+            response.set_raw_header("X-Content-Type-Options", "nosniff");
+            response.set_header(Header::new("Referrer-Policy", config.referrer_policy.clone()));
+            response.set_header(Header::new("Permissions-Policy", config.permissions_policy.clone()));
+        }))))
+    })
+}
+
+/// A one-line summary of each violation in a report, in either the
+/// `report-uri` format or the Reporting API's
+fn describe_violations(report: &Value) -> Vec<String> {
+    let field = |body: &Value, keys: &[&str]| {
+        keys.iter().find_map(|key| body.get(*key).and_then(Value::as_str)).unwrap_or("?").to_string()
+    };
+    let describe = |body: &Value| {
+        format!(
+            "{} blocked {} on {}",
+            field(body, &["effective-directive", "violated-directive", "effectiveDirective"]),
+            field(body, &["blocked-uri", "blockedURL"]),
+            field(body, &["document-uri", "documentURL"]),
+        )
+    };
+
+    match report {
+        Value::Object(object) => object.get("csp-report").map(describe).into_iter().collect(),
+        Value::Array(reports) => reports
+            .iter()
+            .filter(|report| report.get("type").and_then(Value::as_str) == Some("csp-violation"))
+            .filter_map(|report| report.get("body"))
+            .map(describe)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Collect CSP violation reports from browsers and log them
+///
+/// Browsers send these without cookies or a CSRF token, so anyone can post
+/// here; reports are only logged, never stored.
+#[post("/csp-report", data = "<report>")]
+pub async fn csp_report(report: Data<'_>) -> Status {
+    let body = match report.open(MAX_REPORT_BYTES.bytes()).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        _ => return Status::PayloadTooLarge,
+    };
+    match serde_json::from_str::<Value>(&body) {
+        Ok(report) => {
+            for violation in describe_violations(&report) {
+                eprintln!("CSP violation: {:?}", violation);
+            }
+            Status::NoContent
+        }
+        Err(_) => Status::BadRequest,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+    use serde_json::json;
+
+    #[get("/nonce")]
+    fn nonce(nonce: &CspNonce) -> String {
+        nonce.as_str().to_string()
+    }
+
+    fn client(figment: rocket::figment::Figment) -> Client {
+        let rocket = rocket::custom(figment).mount("/", routes![nonce, csp_report]).attach(stage());
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn test_headers_carry_the_page_nonce() {
+        let client = client(rocket::Config::figment());
+        let response = client.get("/nonce").dispatch();
+        let csp = response.headers().get_one("Content-Security-Policy").unwrap().to_string();
+        assert_eq!(response.headers().get_one("X-Frame-Options"), Some("DENY"));
+        assert_eq!(response.headers().get_one("Strict-Transport-Security"), None);
+        assert_eq!(response.headers().get("Permissions-Policy").count(), 1);
+
+        let nonce = response.into_string().unwrap();
+        assert!(csp.contains(&format!("script-src 'self' 'nonce-{}';", nonce)));
+        assert!(csp.contains("style-src 'self';"));
+        assert!(csp.contains("frame-ancestors 'none'"));
+        assert!(csp.contains("report-uri /csp-report"));
+
+        let other = client.get("/nonce").dispatch().into_string().unwrap();
+        assert_ne!(nonce, other);
+    }
+
+    #[test]
+    fn test_report_only_and_hsts() {
+        let figment = rocket::Config::figment()
+            .merge(("security_headers.csp_report_only", true))
+            .merge(("security_headers.hsts_max_age_seconds", 31536000))
+            .merge(("security_headers.hsts_include_subdomains", true))
+            .merge(("security_headers.frame_options", "SAMEORIGIN"));
+        let client = client(figment);
+        let response = client.get("/nonce").dispatch();
+        assert!(response.headers().get_one("Content-Security-Policy").is_none());
+        let csp = response.headers().get_one("Content-Security-Policy-Report-Only").unwrap();
+        assert!(csp.contains("frame-ancestors 'self'"));
+        assert_eq!(response.headers().get_one("Strict-Transport-Security"), Some("max-age=31536000; includeSubDomains"));
+
+        let invalid = SecurityHeadersConfig { frame_options: "ALLOW".to_string(), ..SecurityHeadersConfig::default() };
+        assert!(invalid.validate().is_err());
+        let invalid = SecurityHeadersConfig { hsts_preload: true, ..SecurityHeadersConfig::default() };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_violation_reports() {
+        let report = json!({ "csp-report": {
+            "document-uri": "https://nexo.example.com/home",
+            "violated-directive": "script-src-elem",
+            "blocked-uri": "https://evil.example.com/x.js",
+        }});
+        assert_eq!(describe_violations(&report), vec![
+            "script-src-elem blocked https://evil.example.com/x.js on https://nexo.example.com/home".to_string(),
+        ]);
+        let reports = json!([
+            { "type": "csp-violation", "body": { "documentURL": "https://nexo.example.com/", "effectiveDirective": "style-src", "blockedURL": "inline" } },
+            { "type": "deprecation", "body": {} },
+        ]);
+        assert_eq!(describe_violations(&reports), vec!["style-src blocked inline on https://nexo.example.com/".to_string()]);
+
+        let client = client(rocket::Config::figment());
+        let response = client.post(CSP_REPORT_PATH)
+            .header(ContentType::new("application", "csp-report"))
+            .body(report.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(client.post(CSP_REPORT_PATH).body("not json").dispatch().status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,160 @@
+use rocket::fairing::AdHoc;
+use rocket::http::{CookieJar, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::{Request, State};
+use rocket_db_pools::Database;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::database::{
+    NexoDB, SessionClient, delete_other_sessions, delete_user_session, list_user_sessions, rename_user_session,
+    renew_session,
+};
+use crate::auth::AuthenticatedUser;
+use crate::cookies::{CookieConfig, SESSION_COOKIE};
+use crate::csrf::CsrfVerified;
+use crate::error::ApiError;
+
+/// Longest user agent kept with a session
+const MAX_USER_AGENT_LENGTH: usize = 256;
+/// Longest name a user can give a session
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Settings from the `sessions` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// A session without requests for this long expires
+    pub idle_timeout_seconds: i64,
+    /// A session expires this long after login, however active it is
+    pub absolute_lifetime_seconds: i64,
+    /// How old a session token gets before it is replaced
+    pub rotate_after_seconds: i64,
+    /// How long a replaced token keeps working, for requests that were
+    /// already on their way
+    pub rotation_grace_seconds: i64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_timeout_seconds: 2 * 60 * 60,
+            absolute_lifetime_seconds: 7 * 24 * 60 * 60,
+            rotate_after_seconds: 15 * 60,
+            rotation_grace_seconds: 60,
+        }
+    }
+}
+
+/// Renew the session of every request that carries one
+///
+/// This runs on the response rather than in a guard so it also covers error
+/// responses, whose cookie changes Rocket would otherwise drop, and so a
+/// rotated cookie reaches the browser whatever route was hit. Responses that
+/// already set the session cookie (login, logout) are left alone.
+pub fn renewal() -> AdHoc {
+    AdHoc::on_response("Session Renewal", |request, response| Box::pin(async move {
+        let cookie_config = CookieConfig::of(request);
+        let name = cookie_config.name(SESSION_COOKIE);
+        let Some(token) = request.cookies().get(&name).map(|c| c.value().to_string()) else {
+            return;
+        };
+        if response.headers().get("Set-Cookie").any(|c| c.starts_with(&format!("{}=", name))) {
+            return;
+        }
+        let (Some(db), Some(config)) = (NexoDB::fetch(request.rocket()), request.rocket().state::<SessionConfig>()) else {
+            return;
+        };
+
+        let renewed = renew_session(
+            db,
+            &token,
+            config.idle_timeout_seconds,
+            config.rotate_after_seconds,
+            config.rotation_grace_seconds,
+        ).await;
+        match renewed {
+            Ok(Some(new_token)) => response.adjoin_header(cookie_config.cookie(SESSION_COOKIE, new_token)),
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to renew session: {:?}", e),
+        }
+    }))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionClient {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_agent = request
+            .headers()
+            .get_one("User-Agent")
+            .map(|ua| ua.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        let ip = request.client_ip().map(|ip| ip.to_string());
+
+        Outcome::Success(SessionClient { user_agent, ip })
+    }
+}
+
+/// The signed-in user's active sessions
+#[get("/sessions")]
+pub async fn list(user: AuthenticatedUser, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let sessions = list_user_sessions(db, user.id, &user.session_token).await?;
+    Ok(Json(json!({ "sessions": sessions })))
+}
+
+#[derive(Deserialize)]
+pub struct SessionName {
+    name: Option<String>,
+}
+
+/// Name one of the user's sessions. An empty or missing name clears it.
+#[patch("/sessions/<id>", data = "<request>")]
+pub async fn rename(
+    id: i64,
+    request: Json<SessionName>,
+    user: AuthenticatedUser,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<Status, ApiError> {
+    let name = request.name.as_deref().map(str::trim).filter(|name| !name.is_empty());
+    if name.is_some_and(|name| name.chars().count() > MAX_NAME_LENGTH) {
+        return Err(ApiError::invalid("name_too_long", format!("Session names are at most {} characters", MAX_NAME_LENGTH)));
+    }
+
+    match rename_user_session(db, user.id, id, name).await? {
+        true => Ok(Status::NoContent),
+        false => Err(ApiError::NotFound("session")),
+    }
+}
+
+/// Revoke one of the user's sessions, which may be the current one
+#[delete("/sessions/<id>")]
+pub async fn revoke(
+    id: i64,
+    user: AuthenticatedUser,
+    _csrf: CsrfVerified,
+    cookies: &CookieJar<'_>,
+    cookie_config: &State<CookieConfig>,
+    db: &NexoDB,
+) -> Result<Status, ApiError> {
+    let is_current = list_user_sessions(db, user.id, &user.session_token)
+        .await
+        .is_ok_and(|sessions| sessions.iter().any(|s| s.id == id && s.current));
+
+    if !delete_user_session(db, user.id, id).await? {
+        return Err(ApiError::NotFound("session"));
+    }
+    if is_current {
+        cookies.remove(cookie_config.removal(SESSION_COOKIE));
+    }
+    Ok(Status::NoContent)
+}
+
+/// Log out everywhere except the session making the request
+#[post("/sessions/revoke-others")]
+pub async fn revoke_others(user: AuthenticatedUser, _csrf: CsrfVerified, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let revoked = delete_other_sessions(db, user.id, &user.session_token).await?;
+    Ok(Json(json!({ "revoked": revoked })))
+}
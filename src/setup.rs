@@ -0,0 +1,150 @@
+use rocket::fairing::AdHoc;
+use rocket::http::CookieJar;
+use rocket::State;
+use rocket_db_pools::Database;
+
+use crate::cookies::CookieConfig;
+use crate::crypto::{hash_password, PasswordHashConfig};
+use crate::csrf::CsrfForm;
+use crate::database::{NexoDB, SessionClient};
+use crate::database::roles::has_active_admin;
+use crate::database::setup::{create_first_admin, setup_required};
+use crate::login::{db_error_fragment, error_fragment, start_session, HxRedirectWithCookie};
+use crate::sessions::SessionConfig;
+use crate::templates::Template;
+use crate::validation::{validate_admin_password, validate_email, validate_username, ValidationError};
+
+#[derive(FromForm)]
+pub struct SetupForm {
+    username: String,
+    email: String,
+    password: String,
+    password_confirm: String,
+}
+
+/// An admin account that passed validation, with normalized fields
+#[derive(Debug, PartialEq, Eq)]
+pub struct NewAdmin {
+    pub username: String,
+    pub email: Option<String>,
+}
+
+/// Validate and normalize the submitted form. The email is optional.
+fn validate_setup(form: &SetupForm) -> Result<NewAdmin, ValidationError> {
+    let username = validate_username(&form.username)?;
+    let email = match form.email.trim() {
+        "" => None,
+        email => Some(validate_email(email)?),
+    };
+    validate_admin_password(&form.password, &username)?;
+    if form.password != form.password_confirm {
+        return Err(ValidationError::PasswordMismatch);
+    }
+
+    Ok(NewAdmin { username, email })
+}
+
+/// Why the setup form didn't create the admin
+#[derive(Responder)]
+pub enum SetupFailure {
+    Rejected(Template),
+    /// Setup already happened, so the route no longer exists
+    #[response(status = 404)]
+    Closed(()),
+}
+
+impl From<Template> for SetupFailure {
+    fn from(fragment: Template) -> Self {
+        SetupFailure::Rejected(fragment)
+    }
+}
+
+/// Create the first admin from the setup page served at `/` on a fresh
+/// instance, and log them in
+#[post("/", data = "<form>")]
+pub async fn setup(
+    form: CsrfForm<SetupForm>,
+    db: &NexoDB,
+    cookies: &CookieJar<'_>,
+    client: SessionClient,
+    session_config: &State<SessionConfig>,
+    cookie_config: &State<CookieConfig>,
+    hash_config: &State<PasswordHashConfig>,
+) -> Result<HxRedirectWithCookie, SetupFailure> {
+    match setup_required(db).await {
+        Ok(true) => {}
+        Ok(false) => return Err(SetupFailure::Closed(())),
+        Err(e) => {
+            eprintln!("Failed to check setup status: {:?}", e);
+            return Err(db_error_fragment(&e, "error-create-account-failed").into());
+        }
+    }
+
+    let admin = validate_setup(&form).map_err(|e| error_fragment(e.message_id()))?;
+    let psw_hash = hash_password(hash_config, &form.password).map_err(|e| {
+        eprintln!("Failed to hash password: {}", e);
+        error_fragment("error-create-account-failed")
+    })?;
+
+    match create_first_admin(db, &admin.username, &psw_hash, admin.email.as_deref()).await {
+        Ok(Some(user_id)) => Ok(start_session(db, cookies, &client, session_config, cookie_config, user_id).await?),
+        // Someone else finished setup while this password was hashing
+        Ok(None) => Err(SetupFailure::Closed(())),
+        Err(e) => {
+            eprintln!("Failed to create first admin: {:?}", e);
+            Err(db_error_fragment(&e, "error-create-account-failed").into())
+        }
+    }
+}
+
+/// Fairing that warns at startup when accounts exist but none is an enabled
+/// admin. Setup only runs on an empty instance and nobody is promoted
+/// automatically, so only the operator can fix this.
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("Admin Check", |rocket| Box::pin(async move {
+        let Some(db) = NexoDB::fetch(rocket) else {
+            return;
+        };
+        match (setup_required(db).await, has_active_admin(db).await) {
+            (Ok(false), Ok(false)) => eprintln!(
+                "No enabled user has the admin role; grant it with `nexo-admin user roles <name> admin`"
+            ),
+            (Err(e), _) | (_, Err(e)) => eprintln!("Failed to check for an admin: {:?}", e),
+            _ => {}
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(username: &str, email: &str, password: &str, confirm: &str) -> SetupForm {
+        SetupForm {
+            username: username.to_string(),
+            email: email.to_string(),
+            password: password.to_string(),
+            password_confirm: confirm.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_valid_setup_is_normalized() {
+        assert_eq!(validate_setup(&form(" maria ", "", "Correct-horse-9", "Correct-horse-9")), Ok(NewAdmin {
+            username: "maria".to_string(),
+            email: None,
+        }));
+        assert_eq!(
+            validate_setup(&form("maria", "Maria@Example.com", "Correct-horse-9", "Correct-horse-9")).unwrap().email,
+            Some("maria@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_setup_requires_a_strong_password() {
+        assert_eq!(validate_setup(&form("maria", "", "correct horse", "correct horse")),
+                   Err(ValidationError::PasswordTooWeak));
+        assert_eq!(validate_setup(&form("maria", "", "Correct-horse-9", "Correct-horse-8")),
+                   Err(ValidationError::PasswordMismatch));
+    }
+}
@@ -0,0 +1,336 @@
+//! Server-side HTML templates
+//!
+//! Templates under `templates/` are compiled into the binary and rendered
+//! with minijinja. Pages extend `layout.html` and fill its `content` block;
+//! routes render them through the [`Page`] guard, which adds the signed-in
+//! user, the CSRF token, the CSP nonce and any flash message. When HTMX asks
+//! for a page, only the layout's `body` block is sent back. Fragments that
+//! HTMX swaps into a page live in `partials/` and are rendered with
+//! [`Template::render`]. Every template also gets the request's `locale`
+//! and the translation helpers from [`crate::i18n`].
+
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use include_dir::{include_dir, Dir};
+use minijinja::value::Value;
+use minijinja::{context, Environment, ErrorKind};
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::request::{FlashMessage, FromRequest, Outcome};
+use rocket::response::{Responder, Response};
+use rocket::Request;
+use serde::Deserialize;
+
+use crate::assets::AssetConfig;
+use crate::auth::AuthenticatedUser;
+use crate::config;
+use crate::csrf::CsrfToken;
+use crate::i18n::{self, Locale};
+use crate::security_headers::CspNonce;
+
+static TEMPLATES: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/templates");
+
+/// The layout block HTMX requests for a page get
+const PARTIAL_BLOCK: &str = "body";
+
+/// Settings from the `templates` table in Rocket.toml
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TemplateConfig {
+    /// Directory laid out like `templates/` whose files are used instead of
+    /// the embedded ones and re-read on every render, so they can be edited
+    /// without rebuilding
+    pub override_dir: Option<PathBuf>,
+}
+
+/// The template environment, in managed state
+pub struct Templates {
+    config: TemplateConfig,
+    assets: AssetConfig,
+    environment: Environment<'static>,
+}
+
+fn load(override_dir: Option<&Path>, name: &str) -> Result<Option<String>, minijinja::Error> {
+    if let Some(source) = override_dir.and_then(|dir| std::fs::read_to_string(dir.join(name)).ok()) {
+        return Ok(Some(source));
+    }
+    Ok(TEMPLATES.get_file(name).and_then(|file| file.contents_utf8()).map(str::to_string))
+}
+
+impl Templates {
+    pub fn new(config: TemplateConfig, assets: AssetConfig) -> Self {
+        let environment = Self::environment(&config, &assets);
+        Templates { config, assets, environment }
+    }
+
+    fn environment(config: &TemplateConfig, assets: &AssetConfig) -> Environment<'static> {
+        let mut environment = Environment::new();
+        let override_dir = config.override_dir.clone();
+        environment.set_loader(move |name| load(override_dir.as_deref(), name));
+
+        let assets = assets.clone();
+        environment.add_function("asset", move |path: &str| {
+            assets.url(path).ok_or_else(|| minijinja::Error::new(ErrorKind::InvalidOperation, format!("no asset {}", path)))
+        });
+        i18n::register(&mut environment);
+        environment
+    }
+
+    /// Compile every embedded template and look up every asset they link,
+    /// so mistakes stop the server at startup instead of failing the first
+    /// request that renders them
+    fn check(&self) -> Result<(), minijinja::Error> {
+        fn check_dir(templates: &Templates, dir: &'static Dir<'static>) -> Result<(), minijinja::Error> {
+            for file in dir.files() {
+                let name = file.path().to_string_lossy();
+                templates.environment.get_template(&name)?;
+                for path in referenced_assets(file.contents_utf8().unwrap_or_default()) {
+                    if templates.assets.url(path).is_none() {
+                        let message = format!("{} links {}, which isn't in static/assets; run vendor-assets.sh", name, path);
+                        return Err(minijinja::Error::new(ErrorKind::InvalidOperation, message));
+                    }
+                }
+            }
+            dir.dirs().try_for_each(|dir| check_dir(templates, dir))
+        }
+        check_dir(self, &TEMPLATES)
+    }
+
+    /// Render a template, or just one of its blocks
+    pub fn render(&self, name: &str, context: Value, block: Option<&str>) -> Result<String, minijinja::Error> {
+        // Overridden templates are loaded afresh so edits show up right away
+        let fresh;
+        let environment = if self.config.override_dir.is_some() {
+            fresh = Self::environment(&self.config, &self.assets);
+            &fresh
+        } else {
+            &self.environment
+        };
+
+        let template = environment.get_template(name)?;
+        match block {
+            Some(block) => template.render_captured_to(context, std::io::sink())?.with_state_mut(|state| state.render_block(block)),
+            None => template.render(context),
+        }
+    }
+}
+
+/// The paths a template passes to `asset(...)` as string literals
+fn referenced_assets(source: &str) -> impl Iterator<Item = &str> {
+    source.split("asset(").skip(1).filter_map(|call| {
+        let quote = call.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        call[1..].split(quote).next()
+    })
+}
+
+/// Fairing that reads the `templates` table, checks the templates and places
+/// them in managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Templates", |rocket| async {
+        let configs = config::extract::<TemplateConfig>(rocket.figment(), "templates")
+            .and_then(|templates| Ok((templates, config::extract::<AssetConfig>(rocket.figment(), "assets")?)));
+        let templates = match configs {
+            Ok((config, assets)) => Templates::new(config, assets),
+            Err(e) => {
+                eprintln!("Invalid [templates] configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match templates.check() {
+            Ok(()) => Ok(rocket.manage(templates)),
+            Err(e) => {
+                eprintln!("Invalid template: {:#}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// A template and what to render it with
+pub struct Template {
+    name: &'static str,
+    context: Value,
+    block: Option<&'static str>,
+}
+
+impl Template {
+    /// A fragment rendered with only the given context, e.g.
+    /// `Template::render("partials/message.html", context! { kind => "error", message })`
+    pub fn render(name: &'static str, context: Value) -> Template {
+        Template { name, context, block: None }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Template {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        static DEFAULT: LazyLock<Templates> = LazyLock::new(|| Templates::new(TemplateConfig::default(), AssetConfig::default()));
+        let templates = request.rocket().state::<Templates>().unwrap_or(&DEFAULT);
+
+        let context = context! { locale => Locale::of(request).tag(), ..self.context };
+        match templates.render(self.name, context, self.block) {
+            Ok(html) => Response::build_from(html.respond_to(request)?)
+                .header(ContentType::HTML)
+                .raw_header("Vary", "Accept-Language")
+                .ok(),
+            Err(e) => {
+                eprintln!("Failed to render {}: {:#}", self.name, e);
+                Err(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+/// What every page is rendered with
+///
+/// Available in templates as `user` (`name`, `roles` and `permissions`, or
+/// none when logged out), `csrf_token`, `csp_nonce` and `flash` (`kind` and
+/// `message`). The page is rendered in the user's chosen locale.
+pub struct Page {
+    context: Value,
+    /// Only the page's `body` block is wanted: an HTMX request that swaps it
+    /// into the current page, rather than a boosted link or a history restore
+    /// that replaces the whole document
+    partial: bool,
+}
+
+impl Page {
+    /// A page template with this request's context added to `context`
+    pub fn render(&self, name: &'static str, context: Value) -> Template {
+        Template {
+            name,
+            context: context! { ..context, ..self.context.clone() },
+            block: self.partial.then_some(PARTIAL_BLOCK),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Page {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Resolved now so the responder's `Locale::of` sees the user's choice
+        let _ = request.guard::<Locale>().await;
+        let user = request.guard::<AuthenticatedUser>().await.succeeded().map(|user| context! {
+            name => user.name,
+            roles => user.roles,
+            permissions => user.permissions,
+        });
+        let csrf_token = request.guard::<CsrfToken>().await.succeeded().map(|csrf| csrf.as_str().to_string());
+        let csp_nonce = CspNonce::of(request).as_str();
+        let flash = request.guard::<FlashMessage<'_>>().await.succeeded().map(|flash| context! {
+            kind => flash.kind(),
+            message => flash.message(),
+        });
+
+        let headers = request.headers();
+        let partial = headers.contains("HX-Request")
+            && !headers.contains("HX-Boosted")
+            && !headers.contains("HX-History-Restore-Request");
+
+        Outcome::Success(Page { context: context! { user, csrf_token, csp_nonce, flash }, partial })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+    use rocket::response::{Flash, Redirect};
+
+    #[get("/")]
+    fn page(page: Page) -> Template {
+        page.render("forgot-password.html", context! {})
+    }
+
+    #[get("/message?<name>")]
+    fn message(name: &str) -> Template {
+        Template::render("partials/message.html", context! { kind => "error", message => "home-welcome", args => context! { name } })
+    }
+
+    #[get("/flash")]
+    fn flash() -> Flash<Redirect> {
+        Flash::success(Redirect::to("/"), "logout-success")
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::build().mount("/", routes![page, message, flash]).attach(stage());
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn test_embedded_templates_compile() {
+        Templates::new(TemplateConfig::default(), AssetConfig::default()).check().unwrap();
+    }
+
+    #[test]
+    fn test_referenced_assets() {
+        let source = r#"<script src="{{ asset("vendor/htmx.min.js") }}"></script>{{ asset('app.css') }}{{ asset(name) }}"#;
+        assert_eq!(referenced_assets(source).collect::<Vec<_>>(), ["vendor/htmx.min.js", "app.css"]);
+    }
+
+    #[test]
+    fn test_home_page_renders() {
+        let templates = Templates::new(TemplateConfig::default(), AssetConfig::default());
+        let context = context! {
+            locale => "pt-BR",
+            user => context! { name => "ana", roles => vec!["admin"], permissions => vec!["finance.view"] },
+        };
+        let html = templates.render("home.html", context, None).unwrap();
+        assert!(html.contains("Olá, ana!"));
+        assert!(html.contains(r#""unknown_device":"Dispositivo desconhecido""#));
+    }
+
+    #[test]
+    fn test_htmx_requests_get_the_body_only() {
+        let client = client();
+        let full = client.get("/").dispatch().into_string().unwrap();
+        assert!(full.starts_with("<!DOCTYPE html>"));
+        let token = client.cookies().get(crate::csrf::CSRF_COOKIE).unwrap().value().to_string();
+        assert!(full.contains(&format!(r#""X-CSRF-Token": "{}""#, token)));
+
+        let partial = client.get("/").header(Header::new("HX-Request", "true")).dispatch().into_string().unwrap();
+        assert!(!partial.contains("<html"));
+        assert!(partial.contains("Send reset link"));
+
+        let boosted = client.get("/")
+            .header(Header::new("HX-Request", "true"))
+            .header(Header::new("HX-Boosted", "true"))
+            .dispatch();
+        assert!(boosted.into_string().unwrap().starts_with("<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn test_flash_message_is_shown_once() {
+        let client = client();
+        client.get("/flash").dispatch();
+        assert!(client.get("/").dispatch().into_string().unwrap().contains("You have been logged out."));
+        assert!(!client.get("/").dispatch().into_string().unwrap().contains("You have been logged out."));
+    }
+
+    #[test]
+    fn test_fragments_are_escaped() {
+        let client = client();
+        let response = client.get("/message?name=%3Cscript%3E").dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let html = response.into_string().unwrap();
+        assert!(html.contains("text-red-600"));
+        assert!(html.contains("Welcome, &lt;script&gt;!"));
+    }
+
+    #[test]
+    fn test_pages_follow_accept_language() {
+        let client = client();
+        let html = client.get("/").header(Header::new("Accept-Language", "pt-BR,pt;q=0.9,en;q=0.8")).dispatch().into_string().unwrap();
+        assert!(html.contains(r#"<html lang="pt-BR">"#));
+        assert!(html.contains("Enviar link de redefinição"));
+
+        let html = client.get("/message?name=Ana").header(Header::new("Accept-Language", "pt")).dispatch().into_string().unwrap();
+        assert!(html.contains("Olá, Ana!"));
+        let html = client.get("/message?name=Ana").header(Header::new("Accept-Language", "fr")).dispatch().into_string().unwrap();
+        assert!(html.contains("Welcome, Ana!"));
+    }
+}
@@ -0,0 +1,291 @@
+use minijinja::context;
+use rocket::http::{Header, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::crypto::get_current_timestamp;
+use crate::database::{DbError, NexoDB};
+use crate::database::login_throttle::{
+    clear_throttle_entry, list_throttle_entries, refund_login_attempt, reserve_login_attempt, Attempt,
+};
+use crate::auth::{Authorized, ManageLockouts};
+use crate::csrf::CsrfVerified;
+use crate::error::ApiError;
+use crate::templates::Template;
+
+/// What failed logins are counted against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The username that was submitted, whether or not it exists
+    Account,
+    /// The client's IP address
+    Ip,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Account => "account",
+            Scope::Ip => "ip",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "account" => Some(Scope::Account),
+            "ip" => Some(Scope::Ip),
+            _ => None,
+        }
+    }
+}
+
+/// Failure counts at which a scope starts backing off and gets locked out
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ScopeLimits {
+    /// Failures allowed before any delay applies
+    pub free_attempts: i64,
+    /// Failures after which the key is locked out for `lockout_seconds`
+    pub lockout_after: i64,
+}
+
+/// Settings from the `login_throttle` table in Rocket.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    pub account: ScopeLimits,
+    /// Kept looser than `account` since many users can share one address
+    pub ip: ScopeLimits,
+    /// Delay after the first failure past `free_attempts`; it doubles with
+    /// every failure after that
+    pub base_delay_seconds: i64,
+    pub max_delay_seconds: i64,
+    pub lockout_seconds: i64,
+    /// Failures older than this no longer count
+    pub forget_after_seconds: i64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            account: ScopeLimits { free_attempts: 3, lockout_after: 10 },
+            ip: ScopeLimits { free_attempts: 20, lockout_after: 100 },
+            base_delay_seconds: 1,
+            max_delay_seconds: 300,
+            lockout_seconds: 15 * 60,
+            forget_after_seconds: 60 * 60,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    fn limits(&self, scope: Scope) -> ScopeLimits {
+        match scope {
+            Scope::Account => self.account,
+            Scope::Ip => self.ip,
+        }
+    }
+
+    /// How long a key stays blocked after its `failures`-th failure, and
+    /// whether that is a lockout
+    pub fn block_seconds(&self, scope: Scope, failures: i64) -> Option<(i64, bool)> {
+        let limits = self.limits(scope);
+        if failures >= limits.lockout_after {
+            return Some((self.lockout_seconds, true));
+        }
+        if failures <= limits.free_attempts {
+            return None;
+        }
+
+        let doublings = (failures - limits.free_attempts - 1).min(32) as u32;
+        let delay = self.base_delay_seconds.saturating_mul(1 << doublings);
+        Some((delay.min(self.max_delay_seconds), false))
+    }
+}
+
+/// A login that was refused before the password was even checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Blocked {
+    pub retry_after: i64,
+    pub locked: bool,
+}
+
+impl Blocked {
+    /// The error fragment for the login form
+    pub fn fragment(&self) -> Template {
+        let (unit, count) = match self.retry_after {
+            ..=90 => ("second", self.retry_after.max(1)),
+            _ => ("minute", (self.retry_after + 59) / 60),
+        };
+        let message = if self.locked { "throttle-locked" } else { "throttle-delayed" };
+
+        Template::render("partials/message.html", context! { kind => "warning", message, args => context! { unit, count } })
+    }
+
+    pub fn retry_after_header(&self) -> Header<'static> {
+        Header::new("Retry-After", self.retry_after.max(1).to_string())
+    }
+}
+
+fn keys(username: &str, client_ip: Option<&str>) -> Vec<(Scope, String)> {
+    let mut keys = vec![(Scope::Account, username.to_string())];
+    if let Some(ip) = client_ip {
+        keys.push((Scope::Ip, ip.to_string()));
+    }
+    keys
+}
+
+/// Count a login for `username` from `client_ip` as a failure against both
+/// before its password or code is checked, or refuse it while either is
+/// blocked
+///
+/// Counting first means concurrent guesses can't all get through before the
+/// first failure is recorded. An attempt that turns out right is handed back
+/// with [`release`] or [`record_success`]. Database errors are logged and let
+/// the attempt through, so a broken throttle table can't lock everyone out.
+/// Only a busy or unreachable database is returned, since the login couldn't
+/// succeed anyway.
+pub async fn reserve(
+    db: &NexoDB,
+    config: &ThrottleConfig,
+    username: &str,
+    client_ip: Option<&str>,
+) -> Result<Option<Blocked>, DbError> {
+    let now = get_current_timestamp();
+    let forget_before = now - config.forget_after_seconds;
+    let mut counted = Vec::new();
+    let mut blocked: Option<Blocked> = None;
+
+    for (scope, key) in keys(username, client_ip) {
+        let block_for = |failures| config.block_seconds(scope, failures).map(|(secs, locked)| (now + secs, locked));
+        match reserve_login_attempt(db, scope.as_str(), &key, now, forget_before, block_for).await {
+            Ok(Attempt::Counted(_)) => counted.push((scope, key)),
+            Ok(Attempt::Blocked(entry)) => {
+                let this = Blocked { retry_after: entry.blocked_until - now, locked: entry.locked };
+                if blocked.is_none_or(|b| this.retry_after > b.retry_after) {
+                    blocked = Some(this);
+                }
+            }
+            Err(e) if e.is_unavailable() => {
+                refund(db, config, &counted).await;
+                return Err(e);
+            }
+            Err(e) => eprintln!("Failed to count login attempt: {}", e),
+        }
+    }
+
+    // A refused attempt doesn't count against the other key either
+    if blocked.is_some() {
+        refund(db, config, &counted).await;
+    }
+    Ok(blocked)
+}
+
+async fn refund(db: &NexoDB, config: &ThrottleConfig, keys: &[(Scope, String)]) {
+    let now = get_current_timestamp();
+
+    for (scope, key) in keys {
+        let block_for = |failures| config.block_seconds(*scope, failures).map(|(secs, locked)| (now + secs, locked));
+        if let Err(e) = refund_login_attempt(db, scope.as_str(), key, block_for).await {
+            eprintln!("Failed to refund login attempt: {:?}", e);
+        }
+    }
+}
+
+/// Hand back an attempt [`reserve`] counted that wasn't a failure after all,
+/// i.e. a right password that still needs a second factor
+pub async fn release(db: &NexoDB, config: &ThrottleConfig, username: &str, client_ip: Option<&str>) {
+    refund(db, config, &keys(username, client_ip)).await;
+}
+
+/// Forget an account's failures after it logged in, and hand back the
+/// attempt [`reserve`] counted against the client's address
+///
+/// The address keeps its earlier failures, otherwise anyone holding one
+/// valid account could reset it between guesses against others.
+pub async fn record_success(db: &NexoDB, config: &ThrottleConfig, username: &str, client_ip: Option<&str>) {
+    if let Err(e) = clear_throttle_entry(db, Scope::Account.as_str(), username).await {
+        eprintln!("Failed to clear login throttle: {:?}", e);
+    }
+    if let Some(ip) = client_ip {
+        refund(db, config, &[(Scope::Ip, ip.to_string())]).await;
+    }
+}
+
+/// Accounts and addresses with recent failures or an active block
+#[get("/lockouts")]
+pub async fn list(
+    _user: Authorized<ManageLockouts>,
+    db: &NexoDB,
+    config: &State<ThrottleConfig>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let now = get_current_timestamp();
+    let entries = list_throttle_entries(db, now, now - config.forget_after_seconds).await?;
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| json!({ "blocked": entry.blocked_until > now, "entry": entry }))
+        .collect();
+
+    Ok(Json(json!({ "lockouts": entries })))
+}
+
+/// Lift the block on an account or address and reset its failures
+#[delete("/lockouts/<scope>/<key>")]
+pub async fn clear(
+    scope: &str,
+    key: &str,
+    _user: Authorized<ManageLockouts>,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<Status, ApiError> {
+    let Some(scope) = Scope::parse(scope) else {
+        return Err(ApiError::NotFound("lockout"));
+    };
+
+    match clear_throttle_entry(db, scope.as_str(), key).await? {
+        true => Ok(Status::NoContent),
+        false => Err(ApiError::NotFound("lockout")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_attempts_are_not_delayed() {
+        let config = ThrottleConfig::default();
+        for failures in 1..=config.account.free_attempts {
+            assert_eq!(config.block_seconds(Scope::Account, failures), None);
+        }
+        assert_eq!(config.block_seconds(Scope::Ip, config.account.free_attempts + 1), None);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let config = ThrottleConfig { max_delay_seconds: 6, ..ThrottleConfig::default() };
+        let free = config.account.free_attempts;
+        assert_eq!(config.block_seconds(Scope::Account, free + 1), Some((1, false)));
+        assert_eq!(config.block_seconds(Scope::Account, free + 2), Some((2, false)));
+        assert_eq!(config.block_seconds(Scope::Account, free + 3), Some((4, false)));
+        assert_eq!(config.block_seconds(Scope::Account, free + 4), Some((6, false)));
+    }
+
+    #[test]
+    fn test_lockout_after_threshold() {
+        let config = ThrottleConfig::default();
+        let lockout = Some((config.lockout_seconds, true));
+        assert_eq!(config.block_seconds(Scope::Account, config.account.lockout_after), lockout);
+        assert_eq!(config.block_seconds(Scope::Ip, config.ip.lockout_after + 50), lockout);
+        assert_ne!(config.block_seconds(Scope::Ip, config.account.lockout_after), lockout);
+    }
+
+    #[test]
+    fn test_scope_round_trip() {
+        for scope in [Scope::Account, Scope::Ip] {
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(Scope::parse("user"), None);
+    }
+}
@@ -0,0 +1,400 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use minijinja::context;
+use rand::Rng;
+use rocket::http::{CookieJar, RawStr};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::Sha1;
+
+use crate::cookies::CookieConfig;
+use crate::crypto::{get_current_timestamp, hash_token};
+use crate::database::{DbError, NexoDB, SessionClient, get_username_by_id};
+use crate::database::totp::{
+    consume_recovery_code, confirm_totp_enrollment, count_unused_recovery_codes, delete_login_challenge,
+    disable_totp, get_login_challenge_user, get_totp, record_login_challenge_failure, record_totp_step,
+    start_totp_enrollment,
+};
+use crate::auth::AuthenticatedUser;
+use crate::csrf::{CsrfForm, CsrfVerified};
+use crate::error::ApiError;
+use crate::login::{error_fragment, start_session, HxRedirectWithCookie, LoginFailure};
+use crate::sessions::SessionConfig;
+use crate::templates::Template;
+use crate::throttle::{self, ThrottleConfig};
+
+/// Length of a TOTP time step in seconds (RFC 6238 default)
+pub const PERIOD: i64 = 30;
+/// Number of digits in a code
+pub const DIGITS: u32 = 6;
+/// How many steps either side of the current one are accepted, to allow for
+/// clock drift between the server and the authenticator app
+const SKEW_STEPS: i64 = 1;
+/// Name shown next to the account in authenticator apps
+const ISSUER: &str = "Nexo";
+/// Number of recovery codes issued on enrollment
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Recovery code alphabet, without look-alike characters (0/o, 1/l/i)
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Cookie that carries a pending login between the password and code steps
+pub const LOGIN_CHALLENGE_COOKIE: &str = "login_challenge";
+/// Path the challenge cookie is limited to, as only the login routes read it
+pub const LOGIN_CHALLENGE_PATH: &str = "/login";
+/// How long the code step may take before the password must be re-entered
+pub const LOGIN_CHALLENGE_SECONDS: i64 = 300;
+/// Wrong codes allowed per login challenge
+const LOGIN_CHALLENGE_MAX_ATTEMPTS: i64 = 5;
+
+/// Generate a new 160-bit shared secret, base32 encoded for authenticator apps
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// HOTP (RFC 4226) code for a counter value
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]])
+        & 0x7fff_ffff;
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The time step a Unix timestamp falls in
+pub fn step_at(timestamp: i64) -> i64 {
+    timestamp.div_euclid(PERIOD)
+}
+
+/// The code an authenticator app shows for `step`
+pub fn code_at_step(secret: &[u8], step: i64) -> String {
+    format!("{:0width$}", hotp(secret, step as u64), width = DIGITS as usize)
+}
+
+/// Check a code against a base32 secret at time `now`
+///
+/// Codes from the current step and [`SKEW_STEPS`] either side are accepted,
+/// except for steps at or before `last_used_step`. Returns the matching step
+/// so the caller can record it and reject replays.
+pub fn verify_code(secret: &str, code: &str, now: i64, last_used_step: Option<i64>) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = step_at(now);
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| {
+            let expected = code_at_step(&secret, *step);
+            expected.bytes().zip(code.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+        })
+}
+
+/// `otpauth://` URI that authenticator apps import, usually via a QR code
+pub fn provisioning_uri(account: &str, secret: &str) -> String {
+    let issuer = RawStr::new(ISSUER).percent_encode();
+    let account = RawStr::new(account).percent_encode();
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={PERIOD}"
+    )
+}
+
+/// Generate a fresh set of single-use recovery codes, formatted `xxxxx-xxxxx`
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// Canonical form of a recovery code for hashing, so that case, spaces and
+/// the dash don't matter when the user types it back in
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Check a TOTP code or, failing that, a recovery code for an enabled user,
+/// consuming whichever one matched.
+async fn check_second_factor(db: &NexoDB, user_id: i32, code: &str) -> Result<bool, DbError> {
+    let Some(totp) = get_totp(db, user_id).await? else {
+        return Ok(false);
+    };
+    if !totp.enabled {
+        return Ok(false);
+    }
+
+    if let Some(step) = verify_code(&totp.secret, code, get_current_timestamp(), totp.last_used_step) {
+        return record_totp_step(db, user_id, step).await;
+    }
+
+    let recovery_hash = hash_token(&normalize_recovery_code(code));
+    consume_recovery_code(db, user_id, &recovery_hash).await
+}
+
+/// The form shown in place of the login form when a second factor is needed
+pub fn challenge_fragment() -> Template {
+    Template::render("partials/totp-challenge.html", context! {})
+}
+
+/// Remove the challenge cookie
+fn clear_challenge_cookie(cookies: &CookieJar<'_>, cookie_config: &CookieConfig) {
+    cookies.remove(cookie_config.removal_for_path(LOGIN_CHALLENGE_COOKIE, LOGIN_CHALLENGE_PATH));
+}
+
+#[derive(FromForm)]
+pub struct TotpLoginForm {
+    code: String,
+}
+
+/// Second login step: exchange a login challenge and a valid code for a session
+///
+/// Wrong codes count as failed logins for the account and the address, so
+/// starting a new challenge after running out of attempts doesn't allow
+/// unlimited guesses.
+#[post("/totp", data = "<form>")]
+#[allow(clippy::too_many_arguments)] // one per request guard
+pub async fn login_second_step(
+    form: CsrfForm<TotpLoginForm>,
+    db: &NexoDB,
+    cookies: &CookieJar<'_>,
+    client: SessionClient,
+    throttle_config: &State<ThrottleConfig>,
+    session_config: &State<SessionConfig>,
+    cookie_config: &State<CookieConfig>,
+) -> Result<HxRedirectWithCookie, LoginFailure> {
+    let Some(challenge) = cookies.get(&cookie_config.name(LOGIN_CHALLENGE_COOKIE)).map(|c| c.value().to_string()) else {
+        return Err(error_fragment("error-login-expired").into());
+    };
+
+    let failed = |e| LoginFailure::database(e, "error-verify-code-failed");
+    let user_id = match get_login_challenge_user(db, &challenge).await.map_err(failed)? {
+        Some(user_id) => user_id,
+        None => {
+            clear_challenge_cookie(cookies, cookie_config);
+            return Err(error_fragment("error-login-expired").into());
+        }
+    };
+    let Some(username) = get_username_by_id(db, user_id).await.map_err(failed)? else {
+        clear_challenge_cookie(cookies, cookie_config);
+        return Err(error_fragment("error-login-expired").into());
+    };
+    // Counted as a failure before the code is checked, as for passwords
+    let ip = client.ip.as_deref();
+    if let Some(blocked) = throttle::reserve(db, throttle_config, &username, ip).await.map_err(failed)? {
+        return Err(LoginFailure::Throttled(blocked.fragment(), blocked.retry_after_header()));
+    }
+
+    if check_second_factor(db, user_id, &form.code).await.map_err(failed)? {
+        if let Err(e) = delete_login_challenge(db, &challenge).await {
+            eprintln!("Failed to delete login challenge: {:?}", e);
+        }
+        clear_challenge_cookie(cookies, cookie_config);
+        let session = start_session(db, cookies, &client, session_config, cookie_config, user_id).await?;
+        throttle::record_success(db, throttle_config, &username, ip).await;
+        return Ok(session);
+    }
+
+    match record_login_challenge_failure(db, &challenge, LOGIN_CHALLENGE_MAX_ATTEMPTS).await {
+        Ok(true) => Err(error_fragment("error-invalid-code").into()),
+        Ok(false) => {
+            clear_challenge_cookie(cookies, cookie_config);
+            Err(error_fragment("error-too-many-codes").into())
+        }
+        Err(e) => Err(LoginFailure::database(e, "error-invalid-code")),
+    }
+}
+
+fn already_enabled() -> ApiError {
+    ApiError::conflict("totp_already_enabled", "Two-factor authentication is already on")
+}
+
+fn invalid_code() -> ApiError {
+    ApiError::invalid("invalid_code", "The code is wrong or has expired")
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+/// Whether the current user has 2FA enabled, and how many recovery codes remain
+#[get("/totp")]
+pub async fn status(user: AuthenticatedUser, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let user_id = user.id;
+
+    let totp = get_totp(db, user_id).await?;
+    let remaining = count_unused_recovery_codes(db, user_id).await?;
+
+    Ok(Json(json!({
+        "enabled": totp.is_some_and(|t| t.enabled),
+        "recovery_codes_remaining": remaining
+    })))
+}
+
+/// Start enrollment: generate a secret and return it with its provisioning URI.
+/// Nothing changes for login until the enrollment is confirmed.
+#[post("/totp/enroll")]
+pub async fn enroll(user: AuthenticatedUser, _csrf: CsrfVerified, db: &NexoDB) -> Result<Json<serde_json::Value>, ApiError> {
+    let secret = generate_secret();
+    if !start_totp_enrollment(db, user.id, &secret).await? {
+        return Err(already_enabled());
+    }
+
+    Ok(Json(json!({
+        "secret": secret,
+        "otpauth_uri": provisioning_uri(&user.name, &secret)
+    })))
+}
+
+/// Finish enrollment with a code from the authenticator app. Returns the
+/// recovery codes; this is the only time they are shown.
+#[post("/totp/confirm", data = "<request>")]
+pub async fn confirm(
+    request: Json<CodeRequest>,
+    user: AuthenticatedUser,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let user_id = user.id;
+
+    let totp = match get_totp(db, user_id).await? {
+        Some(totp) if !totp.enabled => totp,
+        Some(_) => return Err(already_enabled()),
+        None => return Err(ApiError::conflict("totp_not_enrolling", "Start enrollment before confirming it")),
+    };
+
+    let step = verify_code(&totp.secret, &request.code, get_current_timestamp(), None).ok_or_else(invalid_code)?;
+
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| hash_token(&normalize_recovery_code(c))).collect();
+    confirm_totp_enrollment(db, user_id, step, &hashes).await?;
+
+    Ok(Json(json!({ "recovery_codes": codes })))
+}
+
+/// Turn 2FA off. Requires a current code so a hijacked session can't do it.
+#[post("/totp/disable", data = "<request>")]
+pub async fn disable(
+    request: Json<CodeRequest>,
+    user: AuthenticatedUser,
+    _csrf: CsrfVerified,
+    db: &NexoDB,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let user_id = user.id;
+
+    if !check_second_factor(db, user_id, &request.code).await? {
+        return Err(invalid_code());
+    }
+
+    disable_totp(db, user_id).await?;
+    Ok(Json(json!({ "enabled": false })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1 secret "12345678901234567890"
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (time, expected) in vectors {
+            assert_eq!(code_at_step(RFC_SECRET, step_at(time)), expected, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn test_verify_code_window() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let now = 1111111111;
+        let step = step_at(now);
+
+        assert_eq!(verify_code(&secret, "050471", now, None), Some(step));
+        // Codes with spaces, as some apps display them
+        assert_eq!(verify_code(&secret, "050 471", now, None), Some(step));
+
+        // One step of drift either way is fine, two is not
+        let previous = code_at_step(RFC_SECRET, step - 1);
+        let too_old = code_at_step(RFC_SECRET, step - 2);
+        assert_eq!(verify_code(&secret, &previous, now, None), Some(step - 1));
+        assert_eq!(verify_code(&secret, &too_old, now, None), None);
+
+        assert_eq!(verify_code(&secret, "000000", now, None), None);
+        assert_eq!(verify_code(&secret, "05047", now, None), None);
+        assert_eq!(verify_code(&secret, "abcdef", now, None), None);
+    }
+
+    #[test]
+    fn test_verify_code_rejects_replay() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let now = 1111111111;
+        let step = step_at(now);
+
+        assert_eq!(verify_code(&secret, "050471", now, Some(step)), None);
+        assert_eq!(verify_code(&secret, "050471", now, Some(step - 1)), Some(step));
+    }
+
+    #[test]
+    fn test_generated_secret_round_trips() {
+        let secret = generate_secret();
+        assert_eq!(BASE32_NOPAD.decode(secret.as_bytes()).unwrap().len(), 20);
+        assert_ne!(secret, generate_secret());
+
+        let now = get_current_timestamp();
+        let code = code_at_step(&BASE32_NOPAD.decode(secret.as_bytes()).unwrap(), step_at(now));
+        assert_eq!(verify_code(&secret, &code, now, None), Some(step_at(now)));
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let uri = provisioning_uri("maria silva", "JBSWY3DPEHPK3PXP");
+        assert_eq!(
+            uri,
+            "otpauth://totp/Nexo:maria%20silva?secret=JBSWY3DPEHPK3PXP&issuer=Nexo&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(&code[5..6], "-");
+        }
+
+        let unique: std::collections::HashSet<_> = codes.iter().collect();
+        assert_eq!(unique.len(), codes.len());
+
+        assert_eq!(normalize_recovery_code(" ABCDE-fghjk "), "abcdefghjk");
+        assert_eq!(normalize_recovery_code(&codes[0]).len(), 10);
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+
+/// Why a user-supplied value was rejected. The `Display` text is the English
+/// message for the command line; pages translate [`ValidationError::message_id`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    UsernameLength,
+    UsernameCharacters,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordMatchesUsername,
+    PasswordTooWeak,
+    PasswordMismatch,
+    InvalidEmail,
+    InvalidCpf,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ValidationError::UsernameLength => "Username must be between 3 and 32 characters",
+            ValidationError::UsernameCharacters => "Username may only contain letters, numbers, '.', '-' and '_'",
+            ValidationError::PasswordTooShort => "Password must be at least 8 characters",
+            ValidationError::PasswordTooLong => "Password must be at most 128 characters",
+            ValidationError::PasswordMatchesUsername => "Password must not be the same as the username",
+            ValidationError::PasswordTooWeak => {
+                "Password must be at least 12 characters and mix at least three of lowercase letters, \
+                 uppercase letters, digits and symbols"
+            }
+            ValidationError::PasswordMismatch => "Passwords do not match",
+            ValidationError::InvalidEmail => "Please enter a valid email address",
+            ValidationError::InvalidCpf => "Please enter a valid CPF",
+        };
+        f.write_str(message)
+    }
+}
+
+impl ValidationError {
+    /// The message in the `locales/` catalogs
+    pub fn message_id(&self) -> &'static str {
+        match self {
+            ValidationError::UsernameLength => "validation-username-length",
+            ValidationError::UsernameCharacters => "validation-username-characters",
+            ValidationError::PasswordTooShort => "validation-password-too-short",
+            ValidationError::PasswordTooLong => "validation-password-too-long",
+            ValidationError::PasswordMatchesUsername => "validation-password-matches-username",
+            ValidationError::PasswordTooWeak => "validation-password-too-weak",
+            ValidationError::PasswordMismatch => "validation-password-mismatch",
+            ValidationError::InvalidEmail => "validation-invalid-email",
+            ValidationError::InvalidCpf => "validation-invalid-cpf",
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check a username and return it trimmed
+pub fn validate_username(username: &str) -> Result<String, ValidationError> {
+    let username = username.trim();
+    if !(3..=32).contains(&username.chars().count()) {
+        return Err(ValidationError::UsernameLength);
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) {
+        return Err(ValidationError::UsernameCharacters);
+    }
+    Ok(username.to_string())
+}
+
+/// Check a new password against the basic policy
+pub fn validate_password(password: &str, username: &str) -> Result<(), ValidationError> {
+    let length = password.chars().count();
+    if length < 8 {
+        return Err(ValidationError::PasswordTooShort);
+    }
+    // Argon2 copes with anything, but there's no reason to accept megabytes
+    if length > 128 {
+        return Err(ValidationError::PasswordTooLong);
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Err(ValidationError::PasswordMatchesUsername);
+    }
+    Ok(())
+}
+
+/// Check the password of an admin account, which gets a stricter policy
+/// than [`validate_password`]: 12 characters or more, from at least three of
+/// lowercase, uppercase, digits and symbols, and not containing the username
+pub fn validate_admin_password(password: &str, username: &str) -> Result<(), ValidationError> {
+    validate_password(password, username)?;
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    let contains_username = password.to_lowercase().contains(&username.to_lowercase());
+    if password.chars().count() < 12 || classes.iter().filter(|c| **c).count() < 3 || contains_username {
+        return Err(ValidationError::PasswordTooWeak);
+    }
+    Ok(())
+}
+
+/// Check an email address and return it trimmed and lowercased
+///
+/// This is a syntax check, not full RFC 5322: one `@`, a non-empty local
+/// part, and a dotted domain made of letters, digits and hyphens.
+pub fn validate_email(email: &str) -> Result<String, ValidationError> {
+    let email = email.trim().to_lowercase();
+    if email.len() > 254 {
+        return Err(ValidationError::InvalidEmail);
+    }
+
+    let (local, domain) = email.split_once('@').ok_or(ValidationError::InvalidEmail)?;
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~.-".contains(c));
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if local_ok && domain_ok {
+        Ok(email)
+    } else {
+        Err(ValidationError::InvalidEmail)
+    }
+}
+
+/// Check a Brazilian CPF and return its 11 digits without punctuation
+///
+/// Accepts both `123.456.789-09` and `12345678909`. The two trailing check
+/// digits must match the first nine, and sequences like `111.111.111-11`,
+/// which pass the check-digit test, are rejected.
+pub fn validate_cpf(cpf: &str) -> Result<String, ValidationError> {
+    let cpf = cpf.trim();
+    if !cpf.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | ' ')) {
+        return Err(ValidationError::InvalidCpf);
+    }
+
+    let digits: Vec<u32> = cpf.chars().filter_map(|c| c.to_digit(10)).collect();
+    if digits.len() != 11 || digits.iter().all(|d| *d == digits[0]) {
+        return Err(ValidationError::InvalidCpf);
+    }
+
+    let check_digit = |len: usize| {
+        let sum: u32 = digits[..len]
+            .iter()
+            .zip((2..=len as u32 + 1).rev())
+            .map(|(digit, weight)| digit * weight)
+            .sum();
+        match sum % 11 {
+            0 | 1 => 0,
+            remainder => 11 - remainder,
+        }
+    };
+
+    if check_digit(9) != digits[9] || check_digit(10) != digits[10] {
+        return Err(ValidationError::InvalidCpf);
+    }
+
+    Ok(digits.iter().map(|d| char::from_digit(*d, 10).unwrap()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_usernames() {
+        assert_eq!(validate_username("  thiago "), Ok("thiago".to_string()));
+        assert_eq!(validate_username("maria.silva-2_"), Ok("maria.silva-2_".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_usernames() {
+        assert_eq!(validate_username("ab"), Err(ValidationError::UsernameLength));
+        assert_eq!(validate_username(&"a".repeat(33)), Err(ValidationError::UsernameLength));
+        assert_eq!(validate_username("maria silva"), Err(ValidationError::UsernameCharacters));
+        assert_eq!(validate_username("joão"), Err(ValidationError::UsernameCharacters));
+    }
+
+    #[test]
+    fn test_password_policy() {
+        assert_eq!(validate_password("correct horse", "thiago"), Ok(()));
+        assert_eq!(validate_password("short", "thiago"), Err(ValidationError::PasswordTooShort));
+        assert_eq!(validate_password(&"x".repeat(129), "thiago"), Err(ValidationError::PasswordTooLong));
+        assert_eq!(validate_password("MariaSilva", "mariasilva"), Err(ValidationError::PasswordMatchesUsername));
+    }
+
+    #[test]
+    fn test_admin_password_policy() {
+        assert_eq!(validate_admin_password("Correct-horse-9", "maria"), Ok(()));
+        assert_eq!(validate_admin_password("correct horse 9", "maria"), Ok(()));
+        assert_eq!(validate_admin_password("Short-1", "maria"), Err(ValidationError::PasswordTooShort));
+        assert_eq!(validate_admin_password("correct horse", "maria"), Err(ValidationError::PasswordTooWeak));
+        assert_eq!(validate_admin_password("Horse-9-", "maria"), Err(ValidationError::PasswordTooWeak));
+        assert_eq!(validate_admin_password("Maria-2024-pass", "maria"), Err(ValidationError::PasswordTooWeak));
+    }
+
+    #[test]
+    fn test_valid_emails() {
+        assert_eq!(validate_email(" Thiago@Example.com "), Ok("thiago@example.com".to_string()));
+        assert!(validate_email("first.last+tag@mail.example.com.br").is_ok());
+        assert!(validate_email("a@b.co").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_emails() {
+        for email in [
+            "",
+            "thiago",
+            "thiago@",
+            "@example.com",
+            "thiago@localhost",
+            "thiago@@example.com",
+            "thi ago@example.com",
+            ".thiago@example.com",
+            "thi..ago@example.com",
+            "thiago@-example.com",
+            "thiago@example..com",
+        ] {
+            assert_eq!(validate_email(email), Err(ValidationError::InvalidEmail), "{:?}", email);
+        }
+    }
+
+    #[test]
+    fn test_valid_cpfs() {
+        assert_eq!(validate_cpf("123.456.789-09"), Ok("12345678909".to_string()));
+        assert_eq!(validate_cpf("12345678909"), Ok("12345678909".to_string()));
+        // Check digits that come out as 10 or 11 become 0
+        assert_eq!(validate_cpf("529.982.247-25"), Ok("52998224725".to_string()));
+        assert_eq!(validate_cpf("000.000.001-91"), Ok("00000000191".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_cpfs() {
+        for cpf in [
+            "",
+            "123.456.789-00",
+            "123.456.789-19",
+            "1234567890",
+            "123456789091",
+            "111.111.111-11",
+            "000.000.000-00",
+            "123.456.78a-09",
+        ] {
+            assert_eq!(validate_cpf(cpf), Err(ValidationError::InvalidCpf), "{:?}", cpf);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Alpine components for the home page
+//
+// Alpine's CSP build only evaluates property and method names in x-
+// attributes, so all logic lives here. Methods used inside x-for read the
+// current item as `this.s` or `this.c`. Texts come from the JSON block with
+// id "messages" that the page renders in the user's language.
+
+function messages() {
+    return JSON.parse(document.getElementById('messages').textContent);
+}
+
+function csrfToken() {
+    return document.querySelector('meta[name=csrf-token]').content;
+}
+
+// fetch() with the CSRF header and a JSON body; rejects on an error status
+function send(method, url, body) {
+    return fetch(url, {
+        method,
+        headers: { 'Content-Type': 'application/json', 'X-CSRF-Token': csrfToken() },
+        body: body ? JSON.stringify(body) : null,
+    }).then(r => r.ok ? r : Promise.reject(r.status));
+}
+
+document.addEventListener('alpine:init', () => {
+    Alpine.data('localeSelect', () => ({
+        change(event) {
+            send('PUT', '/api/user/locale', { locale: event.target.value }).then(() => window.location.reload());
+        },
+    }));
+
+    Alpine.data('totp', () => ({
+        enabled: false,
+        remaining: 0,
+        secret: '',
+        uri: '',
+        code: '',
+        codes: [],
+        error: '',
+        messages: messages(),
+
+        init() {
+            this.load();
+        },
+        load() {
+            fetch('/api/totp').then(r => r.json()).then(d => {
+                this.enabled = d.enabled;
+                this.remaining = d.recovery_codes_remaining;
+            });
+        },
+        get showIntro() {
+            return !this.enabled && !this.secret;
+        },
+        get showEnrollment() {
+            return !this.enabled && !!this.secret;
+        },
+        get hasCodes() {
+            return this.codes.length > 0;
+        },
+        get remainingText() {
+            return this.messages.codes_left + ' ' + this.remaining;
+        },
+        setCode(event) {
+            this.code = event.target.value;
+        },
+        enroll() {
+            send('POST', '/api/totp/enroll').then(r => r.json())
+                .then(d => { this.secret = d.secret; this.uri = d.otpauth_uri; this.error = ''; })
+                .catch(() => this.error = this.messages.enroll_failed);
+        },
+        confirm() {
+            send('POST', '/api/totp/confirm', { code: this.code }).then(r => r.json())
+                .then(d => { this.codes = d.recovery_codes; this.secret = ''; this.code = ''; this.error = ''; this.load(); })
+                .catch(() => this.error = this.messages.invalid_code);
+        },
+        disable() {
+            send('POST', '/api/totp/disable', { code: this.code })
+                .then(() => { this.codes = []; this.code = ''; this.error = ''; this.load(); })
+                .catch(() => this.error = this.messages.invalid_code);
+        },
+    }));
+
+    Alpine.data('sessions', () => ({
+        sessions: [],
+        editing: null,
+        name: '',
+        error: '',
+        messages: messages(),
+
+        init() {
+            this.load();
+        },
+        load() {
+            fetch('/api/sessions').then(r => r.json()).then(d => this.sessions = d.sessions);
+        },
+        when(ts) {
+            return new Date(ts * 1000).toLocaleString(document.documentElement.lang);
+        },
+        get hasOthers() {
+            return this.sessions.length > 1;
+        },
+
+        // For the session `s` of the x-for loop
+        title() {
+            return this.s.name || this.s.user_agent || this.messages.unknown_device;
+        },
+        showUserAgent() {
+            return !!(this.s.name && this.s.user_agent);
+        },
+        whereAndLastActive() {
+            return (this.s.ip || this.messages.unknown_address) + ' · ' + this.messages.last_active + ' ' + this.when(this.s.last_seen_at);
+        },
+        signedIn() {
+            return this.messages.signed_in + ' ' + this.when(this.s.created_at);
+        },
+        isEditing() {
+            return this.editing === this.s.id;
+        },
+        isNotEditing() {
+            return this.editing !== this.s.id;
+        },
+        revokeLabel() {
+            return this.s.current ? this.messages.log_out : this.messages.revoke;
+        },
+        startEditing() {
+            this.editing = this.s.id;
+            this.name = this.s.name || '';
+        },
+        setName(event) {
+            this.name = event.target.value;
+        },
+        rename() {
+            send('PATCH', '/api/sessions/' + this.s.id, { name: this.name })
+                .then(() => { this.editing = null; this.error = ''; this.load(); })
+                .catch(() => this.error = this.messages.rename_failed);
+        },
+        revoke() {
+            const current = this.s.current;
+            send('DELETE', '/api/sessions/' + this.s.id)
+                .then(() => current ? window.location.href = '/' : this.load())
+                .catch(() => this.error = this.messages.revoke_failed);
+        },
+        revokeOthers() {
+            send('POST', '/api/sessions/revoke-others')
+                .then(() => { this.error = ''; this.load(); })
+                .catch(() => this.error = this.messages.revoke_others_failed);
+        },
+    }));
+});
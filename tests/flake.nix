@@ -95,6 +95,11 @@
             export PATH="${rustToolchain}/bin:$PATH"
             export RUST_SRC_PATH="${rustToolchain}/lib/rustlib/src/rust/library"
 
+            # Frontend libraries are embedded at build time
+            if [ ! -f static/assets/vendor/htmx.min.js ]; then
+              ./vendor-assets.sh
+            fi
+
             # Build the application
             echo "📦 Building application..."
             ${rustToolchain}/bin/cargo build --release
@@ -103,6 +108,13 @@
             echo "🔧 Running Rust tests..."
             ${rustToolchain}/bin/cargo test
 
+            # Fresh test databases have no users, so create the admin the
+            # robot tests log in as instead of going through the setup page
+            echo "👤 Creating test admin..."
+            ROCKET_PROFILE=test ROCKET_CONFIG=./Rocket.toml ./target/release/nexo-admin migrate
+            printf 'Robot-test-pass-1\n' | ROCKET_PROFILE=test ROCKET_CONFIG=./Rocket.toml \
+              ./target/release/nexo-admin user create robot --role admin --password-stdin || true
+
             # Start server in background using test environment
             echo "🚀 Starting server using test environment..."
             ROCKET_PROFILE=test ROCKET_CONFIG=./Rocket.toml ROCKET_PORT=8001 ROCKET_ADDRESS=127.0.0.1 ${rustToolchain}/bin/cargo run --release &
@@ -122,10 +134,6 @@
               exit 1
             fi
 
-            # Trigger DB initialization by hitting the init-db endpoint
-            echo "Triggering DB initialization..."
-            curl -s -X POST http://localhost:$PORT/api/init-db > /dev/null
-
             # Set up Python environment for Robot Framework
             export PYTHONPATH="${pythonEnv}/${pythonEnv.sitePackages}"
             export PATH="${pythonEnv}/bin:$PATH"
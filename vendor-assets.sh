@@ -0,0 +1,59 @@
+#!/usr/bin/env bash
+
+# Download the frontend libraries into static/assets/vendor, where the build
+# embeds them into the binary, and commit the result. Run whenever a version
+# below changes; the server itself never fetches anything.
+#
+# Every download must match the SHA-256 pinned next to it, so a compromised
+# CDN can't slip anything into the build. After changing a version, run
+# `./vendor-assets.sh --print-checksums`, check the printed hashes against
+# the upstream release and pin them below.
+
+set -euo pipefail
+
+HTMX_VERSION="2.0.6"
+ALPINE_VERSION="3.14.9"
+TAILWIND_VERSION="2.2.19"
+
+# Filled in by whoever last bumped the versions; see above
+HTMX_SHA256=""
+ALPINE_SHA256=""
+TAILWIND_SHA256=""
+
+print_checksums=false
+if [[ "${1:-}" == "--print-checksums" ]]; then
+    print_checksums=true
+fi
+
+cd "$(dirname "$0")/static/assets/vendor"
+
+fetch() {
+    local file="$1" url="$2" sha256="$3"
+    echo "Fetching $url"
+    curl --fail --silent --show-error --location --output "$file.tmp" "$url"
+
+    if $print_checksums; then
+        sha256sum "$file.tmp" | sed "s/\.tmp\$//"
+        rm "$file.tmp"
+        return
+    fi
+    if [[ -z "$sha256" ]]; then
+        rm "$file.tmp"
+        echo "No SHA-256 pinned for $file; run with --print-checksums and pin it" >&2
+        exit 1
+    fi
+    if ! echo "$sha256  $file.tmp" | sha256sum --check --quiet; then
+        rm "$file.tmp"
+        echo "$file doesn't match its pinned SHA-256; refusing to use it" >&2
+        exit 1
+    fi
+    mv "$file.tmp" "$file"
+}
+
+fetch htmx.min.js "https://unpkg.com/htmx.org@${HTMX_VERSION}/dist/htmx.min.js" "$HTMX_SHA256"
+fetch alpine-csp.min.js "https://unpkg.com/@alpinejs/csp@${ALPINE_VERSION}/dist/cdn.min.js" "$ALPINE_SHA256"
+fetch tailwind.min.css "https://cdn.jsdelivr.net/npm/tailwindcss@${TAILWIND_VERSION}/dist/tailwind.min.css" "$TAILWIND_SHA256"
+
+if ! $print_checksums; then
+    echo "Commit static/assets/vendor and rebuild nexo to embed the new files."
+fi